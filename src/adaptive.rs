@@ -0,0 +1,165 @@
+use crate::material::Color;
+use crate::photon_map::{read_floats, write_floats};
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+
+// Running mean and variance of the samples of a pixel (Welford's algorithm)
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PixelStatistics {
+    count: u32,
+    mean: Color,
+    m2: Color,
+}
+
+impl PixelStatistics {
+    pub fn add(&mut self, sample: Color) {
+        self.count += 1;
+        let delta = sample - self.mean;
+        self.mean += delta / (self.count as f32);
+        self.m2 += delta * (sample - self.mean);
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Color {
+        self.mean
+    }
+
+    pub fn variance(&self) -> Color {
+        if self.count < 2 {
+            Color::black()
+        } else {
+            self.m2 / ((self.count - 1) as f32)
+        }
+    }
+
+    // standard error of the mean relative to the brightness of the pixel, dark pixels are
+    // compared against a minimum brightness so noise in the shadows is not over-sampled
+    pub fn relative_error(&self) -> f32 {
+        if self.count < 2 {
+            return f32::INFINITY;
+        }
+        let standard_error = (self.variance().sum() / (3.0 * self.count as f32)).sqrt();
+        standard_error / (self.mean.sum() / 3.0).max(0.05)
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.count.to_le_bytes())?;
+        write_floats(writer, &self.mean.to_array())?;
+        write_floats(writer, &self.m2.to_array())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut count = [0; 4];
+        reader.read_exact(&mut count)?;
+        let [r, g, b] = read_floats(reader)?;
+        let mean = Color::new(r, g, b);
+        let [r, g, b] = read_floats(reader)?;
+        Ok(Self {
+            count: u32::from_le_bytes(count),
+            mean,
+            m2: Color::new(r, g, b),
+        })
+    }
+}
+
+// Pixels start with min_samples and receive batches of batch_size samples until their
+// relative error drops below noise_threshold or they reach max_samples. Setting
+// min_samples equal to max_samples gives a fixed number of samples per pixel. The first
+// rounds bring every pixel up to the sample counts in progressive_passes, so a complete
+// low quality image is available early on.
+#[derive(Debug, Copy, Clone)]
+pub struct AdaptiveSampling {
+    pub min_samples: u32,
+    pub max_samples: u32,
+    pub batch_size: u32,
+    pub noise_threshold: f32,
+    pub progressive_passes: &'static [u32],
+}
+
+impl AdaptiveSampling {
+    pub fn converged(&self, statistics: &PixelStatistics) -> bool {
+        statistics.count() >= self.max_samples
+            || (statistics.count() >= self.min_samples
+                && statistics.relative_error() < self.noise_threshold)
+    }
+
+    // number of samples every pixel receives in the next round. Pixels always get their
+    // min_samples, when the remaining budget does not cover the batches of all unconverged
+    // pixels the noisiest pixels go first.
+    pub fn allocate(
+        &self,
+        statistics: &[PixelStatistics],
+        budget: usize,
+        round: usize,
+    ) -> Vec<u32> {
+        if let Some(target) = self.progressive_passes.get(round) {
+            let target = (*target).min(self.max_samples);
+            return statistics
+                .iter()
+                .map(|s| target.saturating_sub(s.count()))
+                .collect();
+        }
+
+        let mut allocation = statistics
+            .iter()
+            .map(|s| {
+                if s.count() < self.min_samples {
+                    self.min_samples - s.count()
+                } else if self.converged(s) {
+                    0
+                } else {
+                    self.batch_size.min(self.max_samples - s.count())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let requested: usize = allocation.iter().map(|a| *a as usize).sum();
+        if requested > budget {
+            let mut order = (0..statistics.len())
+                .filter(|i| allocation[*i] > 0 && statistics[*i].count() >= self.min_samples)
+                .collect::<Vec<_>>();
+            order.sort_by(|a, b| {
+                statistics[*b]
+                    .relative_error()
+                    .partial_cmp(&statistics[*a].relative_error())
+                    .unwrap_or(Ordering::Equal)
+            });
+            let mut remaining = budget.saturating_sub(
+                statistics
+                    .iter()
+                    .map(|s| self.min_samples.saturating_sub(s.count()) as usize)
+                    .sum(),
+            );
+            for i in order {
+                let granted = allocation[i].min(remaining as u32);
+                allocation[i] = granted;
+                remaining -= granted as usize;
+            }
+        }
+        allocation
+    }
+}
+
+impl Hash for AdaptiveSampling {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.min_samples.hash(state);
+        self.max_samples.hash(state);
+        self.batch_size.hash(state);
+        self.noise_threshold.to_bits().hash(state);
+        self.progressive_passes.hash(state);
+    }
+}
+
+// maps a sample count to a color from blue (few samples) over green to red (max_samples)
+pub fn heat_map_color(count: u32, max_samples: u32) -> Color {
+    let t = ((count as f32) / (max_samples.max(1) as f32)).min(1.0);
+    if t < 0.5 {
+        Color::new(0.0, 2.0 * t, 1.0 - 2.0 * t)
+    } else {
+        Color::new(2.0 * t - 1.0, 2.0 - 2.0 * t, 0.0)
+    }
+}
@@ -0,0 +1,131 @@
+use crate::material::Color;
+use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
+
+// Values that can be animated. Interpolation parameters outside of [0, 1] extrapolate, the
+// Catmull-Rom curve relies on that.
+pub trait Interpolate: Copy {
+    fn lerp(&self, other: &Self, t: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Vector3 {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+impl Interpolate for Color {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        self + (other - self) * t
+    }
+}
+
+// How a value moves from a key to the next one
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum Curve {
+    // holds the value of the key until the next one
+    Step,
+    Linear,
+    // eases in and out of both keys
+    Smooth,
+    // spline through the keys around it, moving through them without stopping
+    CatmullRom,
+}
+
+// time in frames, the curve applies between this key and the next
+#[derive(Debug, Copy, Clone)]
+pub struct Key<T> {
+    pub time: f32,
+    pub value: T,
+    pub curve: Curve,
+}
+
+impl<T: Hash> Hash for Key<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.time.to_bits().hash(state);
+        self.value.hash(state);
+        self.curve.hash(state);
+    }
+}
+
+// Keyframed value, holding its first and last value before and after its keys
+#[derive(Debug, Clone)]
+pub struct Track<T> {
+    keys: Vec<Key<T>>,
+}
+
+impl<T: Interpolate> Track<T> {
+    // keys in any order, at least one and no two at the same time
+    pub fn new(mut keys: Vec<Key<T>>) -> Self {
+        assert!(!keys.is_empty(), "a track needs at least one key");
+        keys.sort_by(|a, b| a.time.total_cmp(&b.time));
+        assert!(
+            keys.windows(2).all(|pair| pair[0].time < pair[1].time),
+            "the keys of a track need distinct times"
+        );
+        Self { keys }
+    }
+
+    pub fn constant(value: T) -> Self {
+        Self::new(vec![Key {
+            time: 0.0,
+            value,
+            curve: Curve::Step,
+        }])
+    }
+
+    pub fn times(&self) -> impl Iterator<Item = f32> + '_ {
+        self.keys.iter().map(|k| k.time)
+    }
+
+    pub fn at(&self, time: f32) -> T {
+        let next = self.keys.partition_point(|k| k.time <= time);
+        if next == 0 {
+            return self.keys[0].value;
+        }
+        if next == self.keys.len() {
+            return self.keys[next - 1].value;
+        }
+        let (a, b) = (&self.keys[next - 1], &self.keys[next]);
+        let t = (time - a.time) / (b.time - a.time);
+        match a.curve {
+            Curve::Step => a.value,
+            Curve::Linear => a.value.lerp(&b.value, t),
+            Curve::Smooth => a.value.lerp(&b.value, t * t * (3.0 - 2.0 * t)),
+            Curve::CatmullRom => self.catmull_rom(next - 1, time),
+        }
+    }
+
+    // Spline with the times of the keys as its knots, evaluated with the pyramid of Barry and
+    // Goldman, which only needs lerps. Missing keys at the ends are mirrored.
+    fn catmull_rom(&self, i: usize, time: f32) -> T {
+        let (k1, k2) = (&self.keys[i], &self.keys[i + 1]);
+        let (t1, p1, t2, p2) = (k1.time, k1.value, k2.time, k2.value);
+        let (t0, p0) = match i.checked_sub(1).map(|j| &self.keys[j]) {
+            Some(k) => (k.time, k.value),
+            None => (2.0 * t1 - t2, p2.lerp(&p1, 2.0)),
+        };
+        let (t3, p3) = match self.keys.get(i + 2) {
+            Some(k) => (k.time, k.value),
+            None => (2.0 * t2 - t1, p1.lerp(&p2, 2.0)),
+        };
+        let a1 = p0.lerp(&p1, (time - t0) / (t1 - t0));
+        let a2 = p1.lerp(&p2, (time - t1) / (t2 - t1));
+        let a3 = p2.lerp(&p3, (time - t2) / (t3 - t2));
+        let b1 = a1.lerp(&a2, (time - t0) / (t2 - t0));
+        let b2 = a2.lerp(&a3, (time - t1) / (t3 - t1));
+        b1.lerp(&b2, (time - t1) / (t2 - t1))
+    }
+}
+
+impl<T: Hash> Hash for Track<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.keys.hash(state);
+    }
+}
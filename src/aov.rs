@@ -0,0 +1,378 @@
+use crate::exr::{ChannelData, ExrImage};
+use crate::film::{Film, FilmTile, PixelFilter};
+use crate::material::Color;
+use crate::photon_map::{read_floats, write_floats};
+use crate::vector3::Vector3;
+use overload::overload;
+use std::io::{Read, Write};
+use std::ops;
+
+pub const COMPONENT_NAMES: [&str; 5] = ["direct", "specular", "reflected", "global", "caustic"];
+
+// The terms of the radiance along a camera path, their sum is the beauty image. Light seen
+// through glass keeps its component, reflected only holds light from mirror reflections of
+// non-refractive surfaces.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct Radiance {
+    pub direct: Color,
+    pub specular: Color,
+    pub reflected: Color,
+    pub global: Color,
+    pub caustic: Color,
+}
+
+impl Radiance {
+    pub fn total(&self) -> Color {
+        self.direct + self.specular + self.reflected + self.global + self.caustic
+    }
+
+    // in the order of COMPONENT_NAMES
+    pub fn components(&self) -> [Color; 5] {
+        [
+            self.direct,
+            self.specular,
+            self.reflected,
+            self.global,
+            self.caustic,
+        ]
+    }
+}
+
+overload!((a: ?Radiance) + (b: ?Radiance) -> Radiance {
+    Radiance {
+        direct: a.direct + b.direct,
+        specular: a.specular + b.specular,
+        reflected: a.reflected + b.reflected,
+        global: a.global + b.global,
+        caustic: a.caustic + b.caustic,
+    }
+});
+overload!((a: ?Radiance) * (b: f32) -> Radiance {
+    Radiance {
+        direct: a.direct * b,
+        specular: a.specular * b,
+        reflected: a.reflected * b,
+        global: a.global * b,
+        caustic: a.caustic * b,
+    }
+});
+overload!((a: ?Radiance) * (b: Color) -> Radiance {
+    Radiance {
+        direct: a.direct * b,
+        specular: a.specular * b,
+        reflected: a.reflected * b,
+        global: a.global * b,
+        caustic: a.caustic * b,
+    }
+});
+
+// What a camera ray sees at its first hit. Ids start at 1, 0 is left for the background.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SurfaceSample {
+    pub depth: f32,
+    pub normal: Vector3,
+    pub albedo: Color,
+    pub object_id: u32,
+    pub material_id: u32,
+}
+
+// Sums of the surface samples of a pixel. Depth and normal are averaged over the samples that
+// hit something, albedo over all samples so it covers the pixel like the beauty image does.
+// Ids can not be averaged, a pixel takes the ids of its first sample that hits something.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct SurfacePixel {
+    samples: u32,
+    hits: u32,
+    depth: f32,
+    normal: Vector3,
+    albedo: Color,
+    object_id: u32,
+    material_id: u32,
+}
+
+impl SurfacePixel {
+    pub fn add(&mut self, surface: Option<SurfaceSample>) {
+        if let Some(surface) = surface {
+            if self.hits == 0 {
+                self.object_id = surface.object_id;
+                self.material_id = surface.material_id;
+            }
+            self.hits += 1;
+            self.depth += surface.depth;
+            self.normal += surface.normal;
+            self.albedo += surface.albedo;
+        }
+        self.samples += 1;
+    }
+
+    pub fn is_hit(&self) -> bool {
+        self.hits > 0
+    }
+
+    // 0 where no sample hit anything
+    pub fn depth(&self) -> f32 {
+        if self.hits == 0 {
+            0.0
+        } else {
+            self.depth / (self.hits as f32)
+        }
+    }
+
+    // 0 where no sample hit anything, or where the normals of the hits cancel out
+    pub fn normal(&self) -> Vector3 {
+        if self.normal.length_squared() == 0.0 {
+            self.normal
+        } else {
+            self.normal.normalized()
+        }
+    }
+
+    pub fn albedo(&self) -> Color {
+        if self.samples == 0 {
+            self.albedo
+        } else {
+            self.albedo / (self.samples as f32)
+        }
+    }
+
+    pub fn object_id(&self) -> u32 {
+        self.object_id
+    }
+
+    pub fn material_id(&self) -> u32 {
+        self.material_id
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for value in [self.samples, self.hits, self.object_id, self.material_id] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        writer.write_all(&self.depth.to_le_bytes())?;
+        write_floats(writer, &self.normal.to_array())?;
+        write_floats(writer, &self.albedo.to_array())
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut words = [0; 20];
+        reader.read_exact(&mut words)?;
+        let word = |i: usize| {
+            [
+                words[4 * i],
+                words[4 * i + 1],
+                words[4 * i + 2],
+                words[4 * i + 3],
+            ]
+        };
+        let [x, y, z] = read_floats(reader)?;
+        let [r, g, b] = read_floats(reader)?;
+        Ok(Self {
+            samples: u32::from_le_bytes(word(0)),
+            hits: u32::from_le_bytes(word(1)),
+            object_id: u32::from_le_bytes(word(2)),
+            material_id: u32::from_le_bytes(word(3)),
+            depth: f32::from_le_bytes(word(4)),
+            normal: Vector3::new(x, y, z),
+            albedo: Color::new(r, g, b),
+        })
+    }
+}
+
+// Render passes next to the beauty film. The radiance components are reconstructed with the
+// same filter as the beauty image, so they add up to it.
+#[derive(Clone)]
+pub struct AovBuffers {
+    pub components: Vec<Film>,
+    pub surfaces: Vec<SurfacePixel>,
+}
+
+impl AovBuffers {
+    pub fn new(width: usize, height: usize, filter: PixelFilter, radius: f32) -> Self {
+        Self {
+            components: COMPONENT_NAMES
+                .iter()
+                .map(|_| Film::new(width, height, filter, radius))
+                .collect(),
+            surfaces: vec![SurfacePixel::default(); width * height],
+        }
+    }
+
+    // tile of the passes of pixels [x0, x1) x [y0, y1), see Film::tile
+    pub fn tile(&self, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> AovTile {
+        let width = self.components[0].width();
+        AovTile {
+            x0,
+            y0,
+            x1,
+            components: self
+                .components
+                .iter()
+                .map(|film| film.tile((x0, y0), (x1, y1)))
+                .collect(),
+            surfaces: (y0..y1)
+                .flat_map(|y| self.surfaces[y * width + x0..y * width + x1].to_vec())
+                .collect(),
+        }
+    }
+
+    pub fn merge_tile(&mut self, tile: &AovTile) {
+        for (film, film_tile) in self.components.iter_mut().zip(&tile.components) {
+            film.merge_tile(film_tile);
+        }
+        let width = self.components[0].width();
+        let tile_width = tile.x1 - tile.x0;
+        for (i, pixel) in tile.surfaces.iter().enumerate() {
+            let y = tile.y0 + i / tile_width;
+            self.surfaces[y * width + tile.x0 + i % tile_width] = *pixel;
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for film in &self.components {
+            film.write_to(writer)?;
+        }
+        for pixel in &self.surfaces {
+            pixel.write_to(writer)?;
+        }
+        Ok(())
+    }
+
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        (width, height): (usize, usize),
+        filter: PixelFilter,
+        radius: f32,
+    ) -> std::io::Result<Self> {
+        let components = COMPONENT_NAMES
+            .iter()
+            .map(|_| Film::read_from(reader, (width, height), filter, radius))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let surfaces = (0..width * height)
+            .map(|_| SurfacePixel::read_from(reader))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self {
+            components,
+            surfaces,
+        })
+    }
+
+    // Writes the beauty image and all passes either as layers of file_name.exr or as separate
+    // images file_name_<pass>.exr, next to file_name.exr holding the beauty image.
+    pub fn write_exr(
+        &self,
+        beauty: &Film,
+        file_name: &str,
+        layout: AovLayout,
+    ) -> std::io::Result<()> {
+        let width = beauty.width();
+        let height = beauty.height();
+        let mut passes = vec![("", color_channels(beauty))];
+        for (name, film) in COMPONENT_NAMES.iter().zip(&self.components) {
+            passes.push((name, color_channels(film)));
+        }
+        let surface_channel = |f: &dyn Fn(&SurfacePixel) -> f32| {
+            ChannelData::Float(self.surfaces.iter().map(f).collect())
+        };
+        let id_channel = |f: &dyn Fn(&SurfacePixel) -> u32| {
+            ChannelData::Uint(self.surfaces.iter().map(f).collect())
+        };
+        passes.push(("depth", vec![("Z", surface_channel(&|p| p.depth()))]));
+        passes.push((
+            "normal",
+            vec![
+                ("X", surface_channel(&|p| p.normal().x)),
+                ("Y", surface_channel(&|p| p.normal().y)),
+                ("Z", surface_channel(&|p| p.normal().z)),
+            ],
+        ));
+        passes.push((
+            "albedo",
+            vec![
+                ("R", surface_channel(&|p| p.albedo().to_array()[0])),
+                ("G", surface_channel(&|p| p.albedo().to_array()[1])),
+                ("B", surface_channel(&|p| p.albedo().to_array()[2])),
+            ],
+        ));
+        passes.push(("object_id", vec![("id", id_channel(&|p| p.object_id()))]));
+        passes.push((
+            "material_id",
+            vec![("id", id_channel(&|p| p.material_id()))],
+        ));
+
+        match layout {
+            AovLayout::Layers => {
+                let mut image = ExrImage::new(width, height);
+                for (pass, channels) in passes {
+                    for (channel, data) in channels {
+                        if pass.is_empty() {
+                            image.add_channel(channel, data);
+                        } else {
+                            image.add_channel(&format!("{}.{}", pass, channel), data);
+                        }
+                    }
+                }
+                image.write_file(&format!("{}.exr", file_name))
+            }
+            AovLayout::SeparateImages => {
+                for (pass, channels) in passes {
+                    let mut image = ExrImage::new(width, height);
+                    for (channel, data) in channels {
+                        image.add_channel(channel, data);
+                    }
+                    if pass.is_empty() {
+                        image.write_file(&format!("{}.exr", file_name))?;
+                    } else {
+                        image.write_file(&format!("{}_{}.exr", file_name, pass))?;
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum AovLayout {
+    SeparateImages,
+    Layers,
+}
+
+fn color_channels(film: &Film) -> Vec<(&'static str, ChannelData)> {
+    let colors = (0..film.height())
+        .flat_map(|y| (0..film.width()).map(move |x| film.color(x, y, 0.0).to_array()))
+        .collect::<Vec<_>>();
+    ["R", "G", "B"]
+        .iter()
+        .enumerate()
+        .map(|(i, name)| {
+            (
+                *name,
+                ChannelData::Float(colors.iter().map(|c| c[i]).collect()),
+            )
+        })
+        .collect()
+}
+
+// the passes of the pixels of a tile, in rows from x0 to x1 starting at y0
+pub struct AovTile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    components: Vec<FilmTile>,
+    surfaces: Vec<SurfacePixel>,
+}
+
+impl AovTile {
+    // pixel is the index of the pixel within the tile, (x, y) the raster position of the sample
+    pub fn add_sample(
+        &mut self,
+        pixel: usize,
+        (x, y): (f32, f32),
+        radiance: &Radiance,
+        surface: Option<SurfaceSample>,
+    ) {
+        for (tile, color) in self.components.iter_mut().zip(radiance.components()) {
+            tile.add_sample((x, y), color);
+        }
+        self.surfaces[pixel].add(surface);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::mesh::Mesh;
+use crate::vector3::Vector3;
+use std::io::{Error, ErrorKind};
+
+// parameter step towards the middle of a patch where its normal is degenerate
+const DEGENERATE_STEP: f32 = 0.001;
+
+// Bicubic Bézier patch with its control points in rows of four. u runs along the rows and v
+// across them, the normal is the cross product of the derivatives along u and v.
+#[derive(Debug, Copy, Clone)]
+pub struct BezierPatch {
+    pub control_points: [Vector3; 16],
+}
+
+impl BezierPatch {
+    pub fn point(&self, u: f32, v: f32) -> Vector3 {
+        let (bu, bv) = (bernstein(u), bernstein(v));
+        self.sum(|row, column| bv[row] * bu[column])
+    }
+
+    // Where a row of control points collapses into one point, like at the top of the lid of
+    // the teapot, the derivatives are parallel and the normal is taken a little further in.
+    pub fn normal(&self, u: f32, v: f32) -> Vector3 {
+        let normal = self
+            .derivative_u(u, v)
+            .outer_product(self.derivative_v(u, v));
+        if normal.length_squared() > 1e-12 {
+            return normal.normalized();
+        }
+        let inward = |t: f32| t + (0.5 - t).signum() * DEGENERATE_STEP;
+        let (u, v) = (inward(u), inward(v));
+        self.derivative_u(u, v)
+            .outer_product(self.derivative_v(u, v))
+            .normalized()
+    }
+
+    fn derivative_u(&self, u: f32, v: f32) -> Vector3 {
+        let (du, bv) = (bernstein_derivative(u), bernstein(v));
+        self.sum(|row, column| bv[row] * du[column])
+    }
+
+    fn derivative_v(&self, u: f32, v: f32) -> Vector3 {
+        let (bu, dv) = (bernstein(u), bernstein_derivative(v));
+        self.sum(|row, column| dv[row] * bu[column])
+    }
+
+    fn sum(&self, weight: impl Fn(usize, usize) -> f32) -> Vector3 {
+        let mut sum = Vector3::default();
+        for row in 0..4 {
+            for column in 0..4 {
+                sum += self.control_points[4 * row + column] * weight(row, column);
+            }
+        }
+        sum
+    }
+
+    // Reads patches in the text format the Utah teapot is distributed in: the number of patches,
+    // then for each patch its degrees, which must be 3 3, and its 16 control points.
+    pub fn read_file(file_name: &str) -> std::io::Result<Vec<Self>> {
+        let text = std::fs::read_to_string(file_name)?;
+        let invalid = |message: &str| Error::new(ErrorKind::InvalidData, message.to_string());
+        let mut tokens = text.split_whitespace();
+        let mut number = || -> std::io::Result<f32> {
+            tokens
+                .next()
+                .ok_or_else(|| invalid("unexpected end of patch file"))?
+                .parse::<f32>()
+                .map_err(|_| invalid("not a number in patch file"))
+        };
+
+        // the count is not trusted with an allocation, a broken file runs out of data instead
+        let count = number()? as usize;
+        let mut patches = vec![];
+        for _ in 0..count {
+            if number()? != 3.0 || number()? != 3.0 {
+                return Err(invalid("only bicubic patches are supported"));
+            }
+            let mut control_points = [Vector3::default(); 16];
+            for point in control_points.iter_mut() {
+                *point = Vector3::new(number()?, number()?, number()?);
+            }
+            patches.push(Self { control_points });
+        }
+        Ok(patches)
+    }
+}
+
+// A resolution by resolution grid of quads on each patch, split into triangles. Vertices on
+// the edges of patches are not shared, their normals are evaluated on the patches themselves.
+pub fn tessellate(patches: &[BezierPatch], resolution: usize) -> Mesh {
+    let resolution = resolution.max(1);
+    let side = resolution + 1;
+    let mut positions = Vec::with_capacity(patches.len() * side * side);
+    let mut normals = Vec::with_capacity(positions.capacity());
+    let mut uvs = Vec::with_capacity(positions.capacity());
+    let mut triangles = Vec::with_capacity(patches.len() * 2 * resolution * resolution);
+    for patch in patches {
+        let start = positions.len();
+        for j in 0..side {
+            for i in 0..side {
+                let (u, v) = (i as f32 / resolution as f32, j as f32 / resolution as f32);
+                positions.push(patch.point(u, v));
+                normals.push(patch.normal(u, v));
+                uvs.push((u, v));
+            }
+        }
+        for j in 0..resolution {
+            for i in 0..resolution {
+                let a = start + j * side + i;
+                let (b, c, d) = (a + 1, a + side + 1, a + side);
+                triangles.push([a, b, c]);
+                triangles.push([a, c, d]);
+            }
+        }
+    }
+    Mesh::new(positions, normals, uvs, triangles)
+}
+
+fn bernstein(t: f32) -> [f32; 4] {
+    let s = 1.0 - t;
+    [s * s * s, 3.0 * t * s * s, 3.0 * t * t * s, t * t * t]
+}
+
+fn bernstein_derivative(t: f32) -> [f32; 4] {
+    let s = 1.0 - t;
+    [
+        -3.0 * s * s,
+        3.0 * s * s - 6.0 * t * s,
+        6.0 * t * s - 3.0 * t * t,
+        3.0 * t * t,
+    ]
+}
@@ -0,0 +1,145 @@
+use crate::vector3::Vector3;
+use std::f32::consts::PI;
+
+// smallest roughness, below it the distribution is too sharp to evaluate
+const MIN_ROUGHNESS: f32 = 1e-3;
+
+// Rough reflection from microfacets with the distribution of Trowbridge and Reitz (GGX),
+// stretched by separate roughness along the tangent and across it like brushed metal. The
+// frame is the tangent, the bitangent and the normal.
+pub struct Microfacet {
+    roughness: (f32, f32),
+    frame: [Vector3; 3],
+}
+
+impl Microfacet {
+    // Without a tangent the roughness across is taken along an arbitrary direction, which
+    // only matters when it differs from the roughness along
+    pub fn new(roughness: (f32, f32), normal: Vector3, tangent: Option<Vector3>) -> Self {
+        let normal = normal.normalized();
+        let tangent = tangent
+            .map(|t| t - normal * t.inner_product(normal))
+            .filter(|t| t.length_squared() > 1e-12)
+            .map(|t| t.normalized())
+            .unwrap_or_else(|| normal.create_coord_system().1);
+        Self {
+            roughness: (
+                roughness.0.max(MIN_ROUGHNESS),
+                roughness.1.max(MIN_ROUGHNESS),
+            ),
+            frame: [tangent, normal.outer_product(tangent), normal],
+        }
+    }
+
+    // the BRDF times the cosine of wi with the normal, both directions point away from the
+    // surface
+    pub fn evaluate(&self, wo: Vector3, wi: Vector3) -> f32 {
+        let (wo, wi) = (self.to_local(wo), self.to_local(wi));
+        if wo.z <= 0.0 || wi.z <= 0.0 {
+            return 0.0;
+        }
+        let half = (wo + wi).normalized();
+        self.distribution(half) * self.shadowing(wo, wi) / (4.0 * wo.z)
+    }
+
+    // A direction towards where the light comes from, chosen by the normals of the microfacets
+    // that wo sees, and the BRDF times the cosine divided by the density of choosing it. None
+    // when the reflection goes below the surface.
+    pub fn sample(&self, wo: Vector3, (u1, u2): (f32, f32)) -> Option<(Vector3, f32)> {
+        let wo = self.to_local(wo);
+        if wo.z <= 0.0 {
+            return None;
+        }
+        let (ax, ay) = self.roughness;
+        // Heitz, "Sampling the GGX Distribution of Visible Normals"
+        let stretched = Vector3::new(ax * wo.x, ay * wo.y, wo.z).normalized();
+        let length_squared = stretched.x * stretched.x + stretched.y * stretched.y;
+        let t1 = if length_squared > 0.0 {
+            Vector3::new(-stretched.y, stretched.x, 0.0) / length_squared.sqrt()
+        } else {
+            Vector3::new(1.0, 0.0, 0.0)
+        };
+        let t2 = stretched.outer_product(t1);
+        let (r, phi) = (u1.sqrt(), 2.0 * PI * u2);
+        let p1 = r * phi.cos();
+        let s = 0.5 * (1.0 + stretched.z);
+        let p2 = (1.0 - s) * (1.0 - p1 * p1).max(0.0).sqrt() + s * r * phi.sin();
+        let pz = (1.0 - p1 * p1 - p2 * p2).max(0.0).sqrt();
+        let normal = t1 * p1 + t2 * p2 + stretched * pz;
+        let half = Vector3::new(ax * normal.x, ay * normal.y, normal.z.max(0.0)).normalized();
+
+        let wi = half * (2.0 * wo.inner_product(half)) - wo;
+        if wi.z <= 0.0 {
+            return None;
+        }
+        let weight = self.shadowing(wo, wi) / self.masking(wo);
+        Some((self.to_world(wi), weight))
+    }
+
+    fn distribution(&self, half: Vector3) -> f32 {
+        let (ax, ay) = self.roughness;
+        let e = (half.x / ax).powi(2) + (half.y / ay).powi(2) + half.z * half.z;
+        1.0 / (PI * ax * ay * e * e)
+    }
+
+    // Smith's auxiliary function, how much of the surface seen along w the microfacets hide
+    fn lambda(&self, w: Vector3) -> f32 {
+        let (ax, ay) = self.roughness;
+        let tan_squared = ((ax * w.x).powi(2) + (ay * w.y).powi(2)) / (w.z * w.z);
+        ((1.0 + tan_squared).sqrt() - 1.0) / 2.0
+    }
+
+    fn masking(&self, w: Vector3) -> f32 {
+        1.0 / (1.0 + self.lambda(w))
+    }
+
+    // masking and shadowing together, correlated by height
+    fn shadowing(&self, wo: Vector3, wi: Vector3) -> f32 {
+        1.0 / (1.0 + self.lambda(wo) + self.lambda(wi))
+    }
+
+    fn to_local(&self, v: Vector3) -> Vector3 {
+        let [t, b, n] = self.frame;
+        Vector3::new(v.inner_product(t), v.inner_product(b), v.inner_product(n))
+    }
+
+    fn to_world(&self, v: Vector3) -> Vector3 {
+        let [t, b, n] = self.frame;
+        t * v.x + b * v.y + n * v.z
+    }
+}
+
+// Schlick's approximation of the Fresnel reflectance of a dielectric in air, at the cosine
+// of the angle of incidence
+pub fn schlick(refractive_index: f32, cos: f32) -> f32 {
+    let r0 = ((refractive_index - 1.0) / (refractive_index + 1.0)).powi(2);
+    r0 + (1.0 - r0) * (1.0 - cos.clamp(0.0, 1.0)).powi(5)
+}
+
+// Sheen of the "Charlie" distribution of Estevez and Kulla with the visibility of Neubelt and
+// Pettineo, the BRDF times the cosine of wi like Microfacet::evaluate
+pub fn sheen(roughness: f32, normal: Vector3, wo: Vector3, wi: Vector3) -> f32 {
+    let (cos_o, cos_i) = (wo.inner_product(normal), wi.inner_product(normal));
+    if cos_o <= 0.0 || cos_i <= 0.0 {
+        return 0.0;
+    }
+    let inverse = 1.0 / roughness.clamp(MIN_ROUGHNESS, 1.0);
+    let cos_h = (wo + wi).normalized().inner_product(normal);
+    let sin_h = (1.0 - cos_h * cos_h).max(0.0).sqrt();
+    let distribution = (2.0 + inverse) * sin_h.powf(inverse) / (2.0 * PI);
+    let visibility = 1.0 / (4.0 * (cos_i + cos_o - cos_i * cos_o));
+    distribution * visibility * cos_i
+}
+
+// Burley's subsurface term of the Disney BRDF relative to a lambertian one, darker towards the
+// light and brighter at grazing angles of the viewer
+pub fn flattening(roughness: f32, normal: Vector3, wo: Vector3, wi: Vector3) -> f32 {
+    let (cos_o, cos_i) = (wo.inner_product(normal), wi.inner_product(normal));
+    if cos_o <= 0.0 || cos_i <= 0.0 {
+        return 0.0;
+    }
+    let cos_d = (wo + wi).normalized().inner_product(wi);
+    let grazing = roughness * cos_d * cos_d;
+    let weight = |cos: f32| 1.0 + (grazing - 1.0) * (1.0 - cos).powi(5);
+    1.25 * (weight(cos_i) * weight(cos_o) * (1.0 / (cos_i + cos_o) - 0.5) + 0.5)
+}
@@ -0,0 +1,142 @@
+use crate::vector3::Vector3;
+
+// primitives in a leaf
+const LEAF_SIZE: usize = 4;
+
+// Bounding volume hierarchy over the boxes of primitives kept elsewhere, which are found by
+// their index
+#[derive(Debug)]
+pub struct Bvh {
+    nodes: Vec<Node>,
+    // indices of the primitives in the order the leaves refer to them
+    order: Vec<usize>,
+}
+
+// Box around a range of primitives. Leaves hold count primitives from first on, the children
+// of other nodes follow them directly and at second.
+#[derive(Debug)]
+struct Node {
+    min: Vector3,
+    max: Vector3,
+    first: usize,
+    second: usize,
+    count: usize,
+}
+
+impl Bvh {
+    // the boxes by the corners with the smallest and largest coordinates
+    pub fn new(bounds: &[(Vector3, Vector3)]) -> Self {
+        let mut bvh = Self {
+            nodes: vec![],
+            order: (0..bounds.len()).collect(),
+        };
+        if !bounds.is_empty() {
+            bvh.build(bounds, 0, bounds.len());
+        }
+        bvh
+    }
+
+    // None without primitives
+    pub fn bounds(&self) -> Option<(Vector3, Vector3)> {
+        self.nodes.first().map(|node| (node.min, node.max))
+    }
+
+    // The closest hit found by intersect, which is given the index of a primitive whose box the
+    // ray reaches before the closest hit so far and returns t with anything else of the hit
+    pub fn closest<T>(
+        &self,
+        origin: Vector3,
+        direction: Vector3,
+        mut intersect: impl FnMut(usize) -> Option<(f32, T)>,
+    ) -> Option<(f32, T)> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+        let inverse = Vector3::new(1.0 / direction.x, 1.0 / direction.y, 1.0 / direction.z);
+        let mut closest: Option<(f32, T)> = None;
+        let mut stack = vec![0];
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            let limit = closest.as_ref().map_or(f32::INFINITY, |(t, _)| *t);
+            if !hits_box(origin, inverse, node.min, node.max, limit) {
+                continue;
+            }
+            if node.count == 0 {
+                stack.push(node.second);
+                stack.push(index + 1);
+                continue;
+            }
+            for primitive in &self.order[node.first..node.first + node.count] {
+                if let Some((t, hit)) = intersect(*primitive) {
+                    if closest.as_ref().is_none_or(|(closest, _)| t < *closest) {
+                        closest = Some((t, hit));
+                    }
+                }
+            }
+        }
+        closest
+    }
+
+    // Splits the primitives at the middle of the longest axis of the centres of their boxes,
+    // or in half by count when the centres all fall on one side
+    fn build(&mut self, bounds: &[(Vector3, Vector3)], first: usize, count: usize) -> usize {
+        let center = |i: &usize| (bounds[*i].0 + bounds[*i].1) / 2.0;
+        let range = &mut self.order[first..first + count];
+        let (mut min, mut max) = bounds[range[0]];
+        let (mut center_min, mut center_max) = (center(&range[0]), center(&range[0]));
+        for i in range.iter() {
+            min = min.min(bounds[*i].0);
+            max = max.max(bounds[*i].1);
+            center_min = center_min.min(center(i));
+            center_max = center_max.max(center(i));
+        }
+
+        let index = self.nodes.len();
+        self.nodes.push(Node {
+            min,
+            max,
+            first,
+            second: 0,
+            count,
+        });
+        if count <= LEAF_SIZE {
+            return index;
+        }
+
+        let extent = center_max - center_min;
+        let axis = (0..3)
+            .max_by(|a, b| extent.coord(*a).total_cmp(&extent.coord(*b)))
+            .unwrap_or(0);
+        let middle = center_min.coord(axis) + extent.coord(axis) / 2.0;
+        let mut split = 0;
+        for i in 0..range.len() {
+            if center(&range[i]).coord(axis) < middle {
+                range.swap(i, split);
+                split += 1;
+            }
+        }
+        if split == 0 || split == count {
+            range.sort_by(|a, b| center(a).coord(axis).total_cmp(&center(b).coord(axis)));
+            split = count / 2;
+        }
+
+        self.build(bounds, first, split);
+        let second = self.build(bounds, first + split, count - split);
+        self.nodes[index].count = 0;
+        self.nodes[index].second = second;
+        index
+    }
+}
+
+// slab test against a box, for hits closer than limit
+fn hits_box(origin: Vector3, inverse: Vector3, min: Vector3, max: Vector3, limit: f32) -> bool {
+    let (mut near, mut far) = (0.0_f32, limit);
+    for i in 0..3 {
+        let t1 = (min.coord(i) - origin.coord(i)) * inverse.coord(i);
+        let t2 = (max.coord(i) - origin.coord(i)) * inverse.coord(i);
+        // a zero direction with the origin on a face gives nan, which the min and max skip
+        near = near.max(t1.min(t2));
+        far = far.min(t1.max(t2));
+    }
+    near <= far
+}
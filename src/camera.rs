@@ -1,9 +1,43 @@
+use crate::lens::{Aperture, ApertureShape, LensElement, LensSystem, MILLIMETRE};
+use crate::motion::Shutter;
 use crate::ray::Ray;
 use crate::vector3::Vector3;
 use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
 
-#[derive(Copy, Clone)]
-pub struct Camera {
+// Orthonormal camera basis: u points right and v up in the image, the camera looks along -w
+#[derive(Copy, Clone, Hash)]
+pub struct Frame {
+    origin: Vector3,
+    u: Vector3,
+    v: Vector3,
+    w: Vector3,
+}
+
+impl Frame {
+    fn new(look_from: Vector3, look_at: Vector3, vup: Vector3) -> Self {
+        let w = (look_from - look_at).normalized();
+        let u = vup.outer_product(w).normalized();
+        let v = w.outer_product(u);
+        Self {
+            origin: look_from,
+            u,
+            v,
+            w,
+        }
+    }
+
+    // direction given in camera coordinates, x right, y up and z forward
+    fn direction(&self, x: f32, y: f32, z: f32) -> Vector3 {
+        self.u * x + self.v * y - self.w * z
+    }
+}
+
+// Thin lens camera. With cat's eye vignetting, light also has to pass a second circle the size of
+// the aperture that moves towards the edge of the image with the image position, scaled by
+// cats_eye, like the barrel of a lens in front of its stop.
+#[derive(Clone)]
+pub struct PerspectiveCamera {
     origin: Vector3,
     screen_dl: Vector3,
     horizontal: Vector3,
@@ -11,10 +45,175 @@ pub struct Camera {
     u: Vector3,
     v: Vector3,
     lens_radius: f32,
+    aperture: Aperture,
+    cats_eye: f32,
+}
+
+#[derive(Copy, Clone)]
+pub struct OrthographicCamera {
+    frame: Frame,
+    half_width: f32,
+    half_height: f32,
+}
+
+// full sphere, longitude along the width and latitude along the height of the image
+#[derive(Copy, Clone, Hash)]
+pub struct EquirectangularCamera {
+    frame: Frame,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum FisheyeMapping {
+    // distance from the image center proportional to the angle
+    Equidistant,
+    // equal areas in the image cover equal solid angles
+    Equisolid,
+}
+
+// circular image inscribed in the shorter side of the image, outside of it nothing is seen
+#[derive(Copy, Clone)]
+pub struct FisheyeCamera {
+    frame: Frame,
+    field_of_view: f32,
+    mapping: FisheyeMapping,
+    aspect: f32,
+}
+
+// angle along the width and height on a cylinder around the vertical axis along the height
+#[derive(Copy, Clone)]
+pub struct CylindricalCamera {
+    frame: Frame,
+    horizontal_field_of_view: f32,
+    half_height: f32,
+}
+
+// lens system traced from the film at the origin of the frame
+#[derive(Clone)]
+pub struct RealisticCamera {
+    frame: Frame,
+    lens: LensSystem,
+}
+
+#[derive(Clone)]
+pub enum CameraModel {
+    Perspective(PerspectiveCamera),
+    Realistic(RealisticCamera),
+    Orthographic(OrthographicCamera),
+    Equirectangular(EquirectangularCamera),
+    Fisheye(FisheyeCamera),
+    Cylindrical(CylindricalCamera),
+}
+
+// A camera model that is open during the shutter interval. A moving camera is placed at the
+// end camera when the shutter closes, the rays of both are interpolated in between.
+#[derive(Clone)]
+pub struct Camera {
+    model: CameraModel,
+    end: Option<Box<CameraModel>>,
+    shutter: Shutter,
+}
+
+// The projection of a camera, angles in degrees. Perspective and realistic are the projections
+// with a lens, they are focused at the distance passed to create.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Projection {
+    Perspective {
+        field_of_view: f32,
+        aperture: f32,
+        aperture_shape: ApertureShape,
+        cats_eye: f32,
+    },
+    // lens prescription, aperture and sensor size in millimetres
+    Realistic {
+        lens: &'static [LensElement],
+        aperture_diameter: f32,
+        aperture_shape: ApertureShape,
+        sensor_diagonal: f32,
+    },
+    // height of the view in world units
+    Orthographic {
+        height: f32,
+    },
+    Equirectangular,
+    Fisheye {
+        field_of_view: f32,
+        mapping: FisheyeMapping,
+    },
+    Cylindrical {
+        horizontal_field_of_view: f32,
+        field_of_view: f32,
+    },
+}
+
+impl Projection {
+    pub fn create(
+        &self,
+        look_from: Vector3,
+        look_at: Vector3,
+        vup: Vector3,
+        aspect: f32,
+        focus_distance: f32,
+    ) -> std::io::Result<Camera> {
+        Ok(match *self {
+            Projection::Perspective {
+                field_of_view,
+                aperture,
+                aperture_shape,
+                cats_eye,
+            } => Camera::perspective(
+                look_from,
+                look_at,
+                vup,
+                field_of_view,
+                aspect,
+                aperture,
+                focus_distance,
+            )
+            .with_aperture(aperture_shape.load()?)
+            .with_cats_eye(cats_eye),
+            Projection::Realistic {
+                lens,
+                aperture_diameter,
+                aperture_shape,
+                sensor_diagonal,
+            } => Camera::realistic(
+                look_from,
+                look_at,
+                vup,
+                LensSystem::new(
+                    lens,
+                    aperture_diameter,
+                    sensor_diagonal,
+                    aspect,
+                    focus_distance / MILLIMETRE,
+                )?,
+            )
+            .with_aperture(aperture_shape.load()?),
+            Projection::Orthographic { height } => {
+                Camera::orthographic(look_from, look_at, vup, height, aspect)
+            }
+            Projection::Equirectangular => Camera::equirectangular(look_from, look_at, vup),
+            Projection::Fisheye {
+                field_of_view,
+                mapping,
+            } => Camera::fisheye(look_from, look_at, vup, field_of_view, mapping, aspect),
+            Projection::Cylindrical {
+                horizontal_field_of_view,
+                field_of_view,
+            } => Camera::cylindrical(
+                look_from,
+                look_at,
+                vup,
+                horizontal_field_of_view,
+                field_of_view,
+            ),
+        })
+    }
 }
 
 impl Camera {
-    pub fn new(
+    // thin lens camera, vofv is the vertical field of view in degrees
+    pub fn perspective(
         look_from: Vector3,
         look_at: Vector3,
         vup: Vector3,
@@ -26,10 +225,8 @@ impl Camera {
         let theta = vofv * PI / 180.0;
         let half_height = (theta / 2.0).tan();
         let half_width = aspect * half_height;
-        let w = (look_from - look_at).normalized();
-        let u = vup.outer_product(w).normalized();
-        let v = w.outer_product(u);
-        Camera {
+        let Frame { u, v, w, .. } = Frame::new(look_from, look_at, vup);
+        Camera::new(CameraModel::Perspective(PerspectiveCamera {
             origin: look_from,
             screen_dl: look_from
                 - u * half_width * focus_distance
@@ -40,13 +237,262 @@ impl Camera {
             u,
             v,
             lens_radius: aperture / 2.0,
+            aperture: Aperture::Circular,
+            cats_eye: 0.0,
+        }))
+    }
+
+    pub fn realistic(look_from: Vector3, look_at: Vector3, vup: Vector3, lens: LensSystem) -> Self {
+        Camera::new(CameraModel::Realistic(RealisticCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            lens,
+        }))
+    }
+
+    // shape of the aperture of the cameras with a lens
+    pub fn with_aperture(mut self, aperture: Aperture) -> Self {
+        match &mut self.model {
+            CameraModel::Perspective(camera) => camera.aperture = aperture,
+            CameraModel::Realistic(camera) => {
+                camera.lens = camera.lens.clone().with_aperture(aperture);
+            }
+            _ => {}
+        }
+        self
+    }
+
+    // strength of the cat's eye vignetting of the perspective camera, 0 turns it off
+    pub fn with_cats_eye(mut self, cats_eye: f32) -> Self {
+        if let CameraModel::Perspective(camera) = &mut self.model {
+            camera.cats_eye = cats_eye;
         }
+        self
+    }
+
+    // the camera is open from shutter.open to shutter.close, it is closed in no time by default
+    pub fn with_shutter(mut self, shutter: Shutter) -> Self {
+        self.shutter = shutter;
+        self
+    }
+
+    // moves the camera towards the placement of end by the time the shutter closes, end
+    // should be the same model with its aperture and lens
+    pub fn with_motion(mut self, end: Camera) -> Self {
+        self.end = Some(Box::new(end.model));
+        self
     }
 
-    pub fn create_ray(&self, with_lens_focus: bool, x: f32, z: f32) -> Ray {
+    pub fn shutter(&self) -> Shutter {
+        self.shutter
+    }
+
+    // height is the extent of the view in world units
+    pub fn orthographic(
+        look_from: Vector3,
+        look_at: Vector3,
+        vup: Vector3,
+        height: f32,
+        aspect: f32,
+    ) -> Self {
+        Camera::new(CameraModel::Orthographic(OrthographicCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            half_width: aspect * height / 2.0,
+            half_height: height / 2.0,
+        }))
+    }
+
+    pub fn equirectangular(look_from: Vector3, look_at: Vector3, vup: Vector3) -> Self {
+        Camera::new(CameraModel::Equirectangular(EquirectangularCamera {
+            frame: Frame::new(look_from, look_at, vup),
+        }))
+    }
+
+    // field_of_view is the angle across the image circle in degrees, up to 360
+    pub fn fisheye(
+        look_from: Vector3,
+        look_at: Vector3,
+        vup: Vector3,
+        field_of_view: f32,
+        mapping: FisheyeMapping,
+        aspect: f32,
+    ) -> Self {
+        Camera::new(CameraModel::Fisheye(FisheyeCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            field_of_view: field_of_view * PI / 180.0,
+            mapping,
+            aspect,
+        }))
+    }
+
+    // horizontal_field_of_view in degrees, up to 360, vofv is the vertical field of view
+    pub fn cylindrical(
+        look_from: Vector3,
+        look_at: Vector3,
+        vup: Vector3,
+        horizontal_field_of_view: f32,
+        vofv: f32,
+    ) -> Self {
+        Camera::new(CameraModel::Cylindrical(CylindricalCamera {
+            frame: Frame::new(look_from, look_at, vup),
+            horizontal_field_of_view: horizontal_field_of_view * PI / 180.0,
+            half_height: (vofv * PI / 360.0).tan(),
+        }))
+    }
+
+    fn new(model: CameraModel) -> Self {
+        Self {
+            model,
+            end: None,
+            shutter: Shutter {
+                open: 0.0,
+                close: 0.0,
+            },
+        }
+    }
+
+    // x and z are the position in the image in [0, 1], None where the camera sees nothing. The
+    // lens is only used by the perspective and realistic cameras, the time sample picks the time
+    // of the ray within the shutter interval. The radiance along the ray is scaled by the
+    // weight, which is 1 for all cameras but the realistic one.
+    pub fn create_ray(
+        &self,
+        with_lens_focus: bool,
+        x: f32,
+        z: f32,
+        lens_sample: (f32, f32),
+        time_sample: f32,
+    ) -> Option<(Ray, f32)> {
+        let time = self.shutter.time(time_sample);
+        let (start, weight) = self.model.create_ray(with_lens_focus, x, z, lens_sample)?;
+        let (ray, weight) = match &self.end {
+            Some(end) => {
+                let (end, end_weight) = end.create_ray(with_lens_focus, x, z, lens_sample)?;
+                let f = self.shutter.fraction(time);
+                let ray = Ray {
+                    origin: start.origin + (end.origin - start.origin) * f,
+                    direction: (start.direction + (end.direction - start.direction) * f)
+                        .normalized(),
+                    time,
+                };
+                (ray, weight + (end_weight - weight) * f)
+            }
+            None => (start, weight),
+        };
+        Some((Ray { time, ..ray }, weight))
+    }
+}
+
+impl CameraModel {
+    fn create_ray(
+        &self,
+        with_lens_focus: bool,
+        x: f32,
+        z: f32,
+        lens_sample: (f32, f32),
+    ) -> Option<(Ray, f32)> {
+        // image position in [-1, 1]
+        let (sx, sy) = (2.0 * x - 1.0, 2.0 * z - 1.0);
+        let ray = match self {
+            CameraModel::Perspective(camera) => {
+                camera.create_ray(with_lens_focus, (x, z), (sx, sy), lens_sample)
+            }
+            CameraModel::Realistic(camera) => {
+                let (origin, direction, weight) = camera.lens.create_ray(sx, sy, lens_sample)?;
+                let frame = &camera.frame;
+                let ray = Ray {
+                    origin: frame.origin
+                        + frame.direction(origin.x, origin.y, origin.z) * MILLIMETRE,
+                    direction: frame
+                        .direction(direction.x, direction.y, direction.z)
+                        .normalized(),
+                    time: 0.0,
+                };
+                return Some((ray, weight));
+            }
+            CameraModel::Orthographic(camera) => {
+                let frame = &camera.frame;
+                Some(Ray {
+                    origin: frame.origin
+                        + frame.u * (sx * camera.half_width)
+                        + frame.v * (sy * camera.half_height),
+                    direction: -frame.w,
+                    time: 0.0,
+                })
+            }
+            CameraModel::Equirectangular(camera) => {
+                let phi = sx * PI;
+                let theta = sy * PI / 2.0;
+                let direction = camera.frame.direction(
+                    theta.cos() * phi.sin(),
+                    theta.sin(),
+                    theta.cos() * phi.cos(),
+                );
+                Some(Ray {
+                    origin: camera.frame.origin,
+                    direction,
+                    time: 0.0,
+                })
+            }
+            CameraModel::Fisheye(camera) => {
+                let (px, py) = if camera.aspect >= 1.0 {
+                    (sx * camera.aspect, sy)
+                } else {
+                    (sx, sy / camera.aspect)
+                };
+                let r = (px * px + py * py).sqrt();
+                if r > 1.0 {
+                    return None;
+                }
+                let theta = match camera.mapping {
+                    FisheyeMapping::Equidistant => r * camera.field_of_view / 2.0,
+                    FisheyeMapping::Equisolid => {
+                        2.0 * (r * (camera.field_of_view / 4.0).sin()).min(1.0).asin()
+                    }
+                };
+                let phi = py.atan2(px);
+                let direction = camera.frame.direction(
+                    theta.sin() * phi.cos(),
+                    theta.sin() * phi.sin(),
+                    theta.cos(),
+                );
+                Some(Ray {
+                    origin: camera.frame.origin,
+                    direction,
+                    time: 0.0,
+                })
+            }
+            CameraModel::Cylindrical(camera) => {
+                let phi = sx * camera.horizontal_field_of_view / 2.0;
+                let direction = camera
+                    .frame
+                    .direction(phi.sin(), sy * camera.half_height, phi.cos())
+                    .normalized();
+                Some(Ray {
+                    origin: camera.frame.origin,
+                    direction,
+                    time: 0.0,
+                })
+            }
+        };
+        ray.map(|ray| (ray, 1.0))
+    }
+}
+
+impl PerspectiveCamera {
+    fn create_ray(
+        &self,
+        with_lens_focus: bool,
+        (x, z): (f32, f32),
+        (sx, sy): (f32, f32),
+        lens_sample: (f32, f32),
+    ) -> Option<Ray> {
         let offset = if with_lens_focus {
-            let rd = Vector3::random_in_unit_disk() * self.lens_radius;
-            self.u * rd.x + self.v * rd.y
+            let (ax, ay) = self.aperture.sample(lens_sample);
+            let (cx, cy) = (ax - self.cats_eye * sx, ay - self.cats_eye * sy);
+            if self.cats_eye > 0.0 && cx * cx + cy * cy > 1.0 {
+                return None;
+            }
+            self.u * (ax * self.lens_radius) + self.v * (ay * self.lens_radius)
         } else {
             Vector3 {
                 x: 0.0,
@@ -57,9 +503,78 @@ impl Camera {
         let direction =
             (self.screen_dl + self.horizontal * x + self.vertical * z - self.origin - offset)
                 .normalized();
-        Ray {
+        Some(Ray {
             origin: self.origin + offset,
             direction,
+            time: 0.0,
+        })
+    }
+}
+
+impl Hash for PerspectiveCamera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.origin.hash(state);
+        self.screen_dl.hash(state);
+        self.horizontal.hash(state);
+        self.vertical.hash(state);
+        self.u.hash(state);
+        self.v.hash(state);
+        self.lens_radius.to_bits().hash(state);
+        self.aperture.hash(state);
+        self.cats_eye.to_bits().hash(state);
+    }
+}
+
+impl Hash for RealisticCamera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.frame.hash(state);
+        self.lens.hash(state);
+    }
+}
+
+impl Hash for OrthographicCamera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.frame.hash(state);
+        self.half_width.to_bits().hash(state);
+        self.half_height.to_bits().hash(state);
+    }
+}
+
+impl Hash for FisheyeCamera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.frame.hash(state);
+        self.field_of_view.to_bits().hash(state);
+        self.mapping.hash(state);
+        self.aspect.to_bits().hash(state);
+    }
+}
+
+impl Hash for CylindricalCamera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.frame.hash(state);
+        self.horizontal_field_of_view.to_bits().hash(state);
+        self.half_height.to_bits().hash(state);
+    }
+}
+
+impl Hash for Camera {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.model.hash(state);
+        self.end.hash(state);
+        self.shutter.hash(state);
+    }
+}
+
+impl Hash for CameraModel {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            CameraModel::Perspective(camera) => camera.hash(state),
+            CameraModel::Realistic(camera) => camera.hash(state),
+            CameraModel::Orthographic(camera) => camera.hash(state),
+            CameraModel::Equirectangular(camera) => camera.hash(state),
+            CameraModel::Fisheye(camera) => camera.hash(state),
+            CameraModel::Cylindrical(camera) => camera.hash(state),
         }
     }
 }
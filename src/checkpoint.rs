@@ -0,0 +1,317 @@
+use crate::adaptive::PixelStatistics;
+use crate::aov::AovBuffers;
+use crate::film::Film;
+use crate::photon_cache::{write_photon_maps, PhotonCache, PhotonMapKind};
+use crate::photon_map::PhotonMap;
+use crate::render::{RenderProgress, RenderSettings};
+use std::fs::{rename, File};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 8] = b"RENDERCP";
+const VERSION: u32 = 2;
+
+// An unfinished render together with the photon maps it renders with. Continuing the progress
+// with the same settings, camera and scene gives the same image as an uninterrupted render,
+// the key identifies these so a checkpoint of a different render is not picked up. The photon
+// maps do not change during a render and are written once, to photons_file_name next to the
+// progress.
+pub struct Checkpoint {
+    pub progress: RenderProgress,
+    pub photons: PhotonCache,
+}
+
+impl Checkpoint {
+    pub fn read_file(
+        file_name: &str,
+        key: u64,
+        settings: &RenderSettings,
+    ) -> std::io::Result<Self> {
+        let progress = read_progress(&mut BufReader::new(File::open(file_name)?), key, settings)?;
+        let photons = PhotonCache::read_file(&photons_file_name(file_name))?;
+        if photons.key != key {
+            return Err(different_render());
+        }
+        Ok(Self { progress, photons })
+    }
+}
+
+pub fn photons_file_name(file_name: &str) -> String {
+    format!("{}.photons", file_name)
+}
+
+// The header with the key and image size is checked before anything else is read, so a
+// checkpoint of a different render is rejected without allocating for its image.
+pub fn read_progress<R: Read>(
+    reader: &mut R,
+    key: u64,
+    settings: &RenderSettings,
+) -> std::io::Result<RenderProgress> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(Error::new(ErrorKind::InvalidData, "not a checkpoint file"));
+    }
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("unsupported checkpoint file version {}", version),
+        ));
+    }
+    if read_u64(reader)? != key {
+        return Err(different_render());
+    }
+    let size = (settings.width, settings.height);
+    if read_u64(reader)? != size.0 as u64 || read_u64(reader)? != size.1 as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "checkpoint has a different image size",
+        ));
+    }
+    let pixel_count = size.0 * size.1;
+
+    let round = read_u64(reader)? as usize;
+    let budget = read_u64(reader)? as usize;
+    let tiles_done = read_u64(reader)? as usize;
+    let film = Film::read_from(reader, size, settings.pixel_filter, settings.filter_radius)?;
+    let allocation_length = read_u64(reader)? as usize;
+    if allocation_length != 0 && allocation_length != pixel_count {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "invalid sample allocation",
+        ));
+    }
+    let allocation = (0..allocation_length)
+        .map(|_| read_u32(reader))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let statistics = (0..pixel_count)
+        .map(|_| PixelStatistics::read_from(reader))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    let mut has_aovs = [0; 1];
+    reader.read_exact(&mut has_aovs)?;
+    let aovs = if has_aovs[0] != 0 {
+        Some(AovBuffers::read_from(
+            reader,
+            size,
+            settings.pixel_filter,
+            settings.filter_radius,
+        )?)
+    } else {
+        None
+    };
+
+    Ok(RenderProgress {
+        round,
+        budget,
+        allocation,
+        tiles_done,
+        film,
+        statistics,
+        aovs,
+    })
+}
+
+pub fn write_checkpoint(
+    file_name: &str,
+    key: u64,
+    progress: &RenderProgress,
+) -> std::io::Result<()> {
+    write_renamed(file_name, |writer| {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&key.to_le_bytes())?;
+        writer.write_all(&(progress.film.width() as u64).to_le_bytes())?;
+        writer.write_all(&(progress.film.height() as u64).to_le_bytes())?;
+
+        writer.write_all(&(progress.round as u64).to_le_bytes())?;
+        writer.write_all(&(progress.budget as u64).to_le_bytes())?;
+        writer.write_all(&(progress.tiles_done as u64).to_le_bytes())?;
+        progress.film.write_to(writer)?;
+        writer.write_all(&(progress.allocation.len() as u64).to_le_bytes())?;
+        for samples in &progress.allocation {
+            writer.write_all(&samples.to_le_bytes())?;
+        }
+        for pixel in &progress.statistics {
+            pixel.write_to(writer)?;
+        }
+        match &progress.aovs {
+            Some(aovs) => {
+                writer.write_all(&[1])?;
+                aovs.write_to(writer)
+            }
+            None => writer.write_all(&[0]),
+        }
+    })
+}
+
+// written once per render, before the first progress that needs them
+pub fn write_checkpoint_photons(
+    file_name: &str,
+    key: u64,
+    photon_maps: &[(PhotonMapKind, &PhotonMap)],
+) -> std::io::Result<()> {
+    write_renamed(&photons_file_name(file_name), |writer| {
+        write_photon_maps(writer, key, photon_maps)
+    })
+}
+
+// The file is written next to the target and then renamed, so an interrupted write leaves the
+// previous file intact.
+fn write_renamed(
+    file_name: &str,
+    write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let temporary_name = format!("{}.tmp", file_name);
+    let mut writer = BufWriter::new(File::create(&temporary_name)?);
+    write(&mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    rename(temporary_name, file_name)
+}
+
+fn different_render() -> Error {
+    Error::new(ErrorKind::InvalidData, "checkpoint of a different render")
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> std::io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> std::io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::adaptive::AdaptiveSampling;
+    use crate::film::PixelFilter;
+    use crate::material::Color;
+    use crate::photon_map::Photon;
+    use crate::sampler::SamplerKind;
+    use crate::tiles::TileOrder;
+    use crate::vector3::Vector3;
+    use std::time::Duration;
+
+    const KEY: u64 = 0x0123_4567_89ab_cdef;
+
+    fn settings(width: usize, height: usize) -> RenderSettings {
+        RenderSettings {
+            width,
+            height,
+            samples_per_pixel: 4,
+            adaptive_sampling: AdaptiveSampling {
+                min_samples: 2,
+                max_samples: 8,
+                batch_size: 2,
+                noise_threshold: 0.1,
+                progressive_passes: &[1],
+            },
+            sampler: SamplerKind::Sobol,
+            seed: 0,
+            pixel_filter: PixelFilter::Tent,
+            filter_radius: 1.5,
+            tile_size: 2,
+            tile_order: TileOrder::Scanline,
+            aovs: true,
+            preview_file: None,
+            preview_interval: Duration::from_secs(60),
+            checkpoint_file: None,
+            checkpoint_interval: Duration::from_secs(60),
+        }
+    }
+
+    // a render that is partway through its second round
+    fn progress(settings: &RenderSettings) -> RenderProgress {
+        let mut progress = RenderProgress::new(settings);
+        let pixel_count = settings.width * settings.height;
+        progress.round = 1;
+        progress.budget = 7;
+        progress.tiles_done = 2;
+        progress.allocation = (0..pixel_count as u32).collect();
+        let mut tile = progress
+            .film
+            .tile((0, 0), (settings.width, settings.height));
+        tile.add_sample((1.5, 0.5), Color::new(1.0, 0.5, 0.25));
+        progress.film.merge_tile(&tile);
+        for (i, statistics) in progress.statistics.iter_mut().enumerate() {
+            statistics.add(Color::new(i as f32, 1.0, 0.0));
+            statistics.add(Color::new(0.0, 2.0, i as f32));
+        }
+        progress
+    }
+
+    // removed again when the test is done, also when it fails
+    struct TemporaryFile(String);
+
+    impl TemporaryFile {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+            Self(path.to_string_lossy().into_owned())
+        }
+    }
+
+    impl Drop for TemporaryFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_file(photons_file_name(&self.0));
+        }
+    }
+
+    fn write(file: &TemporaryFile, settings: &RenderSettings) {
+        let mut photon_map = PhotonMap::new();
+        let up = Vector3::new(0.0, 1.0, 0.0);
+        photon_map.store(Photon::new(up, up, up, Color::white()));
+        photon_map.balance();
+        write_checkpoint_photons(&file.0, KEY, &[(PhotonMapKind::Global, &photon_map)]).unwrap();
+        write_checkpoint(&file.0, KEY, &progress(settings)).unwrap();
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let file = TemporaryFile::new("checkpoint_read_back");
+        let settings = settings(4, 3);
+        write(&file, &settings);
+
+        let mut checkpoint = Checkpoint::read_file(&file.0, KEY, &settings).unwrap();
+        let (expected, read) = (progress(&settings), checkpoint.progress);
+        assert_eq!(read.round, expected.round);
+        assert_eq!(read.budget, expected.budget);
+        assert_eq!(read.tiles_done, expected.tiles_done);
+        assert_eq!(read.allocation, expected.allocation);
+        for y in 0..settings.height {
+            for x in 0..settings.width {
+                let (read, expected) = (read.film.color(x, y, 1.0), expected.film.color(x, y, 1.0));
+                assert_eq!(read.to_array(), expected.to_array());
+            }
+        }
+        for (read, expected) in read.statistics.iter().zip(&expected.statistics) {
+            assert_eq!(read.count(), expected.count());
+            assert_eq!(read.mean().to_array(), expected.mean().to_array());
+            assert_eq!(read.variance().to_array(), expected.variance().to_array());
+        }
+        assert!(read.aovs.is_some());
+        let photons = checkpoint.photons.take(PhotonMapKind::Global).unwrap();
+        assert_eq!(photons.len(), 1);
+    }
+
+    #[test]
+    fn rejects_checkpoints_of_other_renders() {
+        let file = TemporaryFile::new("checkpoint_other_render");
+        let other_size = settings(3, 4);
+        let settings = settings(4, 3);
+        write(&file, &settings);
+
+        assert!(Checkpoint::read_file(&file.0, KEY + 1, &settings).is_err());
+        assert!(Checkpoint::read_file(&file.0, KEY, &other_size).is_err());
+        let missing = TemporaryFile::new("checkpoint_missing");
+        let error = Checkpoint::read_file(&missing.0, KEY, &settings)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), ErrorKind::NotFound);
+    }
+}
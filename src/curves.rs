@@ -0,0 +1,277 @@
+use crate::bvh::Bvh;
+use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind};
+
+// limit on how often a segment is halved before it is taken as straight
+const MAX_SUBDIVISIONS: i32 = 10;
+
+// Curves are flat strips that always face the ray, they differ in the normal across them
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum CurveKind {
+    // the normal faces the ray all the way across
+    Ribbon,
+    // the normal turns across the width like on a tube, for thin strands seen from afar
+    Cylinder,
+}
+
+// Cubic Bézier segment whose width changes linearly from its start to its end
+#[derive(Debug, Copy, Clone)]
+pub struct CurveSegment {
+    pub control_points: [Vector3; 4],
+    pub widths: (f32, f32),
+}
+
+// Many curve segments, like the strands of hair, in a bounding volume hierarchy of their own
+#[derive(Debug)]
+pub struct Curves {
+    segments: Vec<CurveSegment>,
+    kind: CurveKind,
+    bvh: Bvh,
+}
+
+// hit on a curve: t, the normal, the tangent along the curve and surface coordinates, u along
+// the segment and v across it
+pub type CurveHit = (f32, Vector3, Vector3, (f32, f32));
+
+impl CurveSegment {
+    pub fn point(&self, u: f32) -> Vector3 {
+        evaluate(&self.control_points, u).0
+    }
+
+    pub fn tangent(&self, u: f32) -> Vector3 {
+        evaluate(&self.control_points, u).1
+    }
+
+    pub fn width(&self, u: f32) -> f32 {
+        self.widths.0 + (self.widths.1 - self.widths.0) * u
+    }
+
+    // the curve lies within the hull of its control points
+    pub fn bounds(&self) -> (Vector3, Vector3) {
+        let radius = self.widths.0.max(self.widths.1) / 2.0;
+        let radius = Vector3::new(radius, radius, radius);
+        let (min, max) = hull_bounds(&self.control_points);
+        (min - radius, max + radius)
+    }
+}
+
+impl Curves {
+    pub fn new(segments: Vec<CurveSegment>, kind: CurveKind) -> Self {
+        let bounds = segments.iter().map(|s| s.bounds()).collect::<Vec<_>>();
+        Self {
+            bvh: Bvh::new(&bounds),
+            segments,
+            kind,
+        }
+    }
+
+    // Reads strands from a text file with one strand per line, as 3 * n + 1 control points of
+    // n segments that share their ends. Each point is given as x y z and the width there,
+    // lines starting with # are skipped.
+    pub fn read_file(file_name: &str, kind: CurveKind) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(file_name)?;
+        let invalid = |line: usize| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid curve data on line {}", line + 1),
+            )
+        };
+        let mut segments = vec![];
+        for (line_number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let numbers = line
+                .split_whitespace()
+                .map(|t| t.parse::<f32>().map_err(|_| invalid(line_number)))
+                .collect::<Result<Vec<_>, _>>()?;
+            let count = numbers.len() / 4;
+            if numbers.len() % 4 != 0 || count < 4 || (count - 1) % 3 != 0 {
+                return Err(invalid(line_number));
+            }
+            let points = numbers
+                .chunks(4)
+                .map(|c| (Vector3::new(c[0], c[1], c[2]), c[3]))
+                .collect::<Vec<_>>();
+            for start in (0..points.len() - 1).step_by(3) {
+                let points = &points[start..start + 4];
+                segments.push(CurveSegment {
+                    control_points: [points[0].0, points[1].0, points[2].0, points[3].0],
+                    widths: (points[0].1, points[3].1),
+                });
+            }
+        }
+        Ok(Self::new(segments, kind))
+    }
+
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    // corners with the smallest and largest coordinates, None without segments
+    pub fn bounds(&self) -> Option<(Vector3, Vector3)> {
+        self.bvh.bounds()
+    }
+
+    // closest hit in front of the origin
+    pub fn intersect(&self, origin: Vector3, direction: Vector3) -> Option<CurveHit> {
+        let length = direction.length_squared().sqrt();
+        if length == 0.0 {
+            return None;
+        }
+        // the ray runs along z from the origin of its own space, where distances stay the same
+        let (z, x, y) = (direction / length).create_coord_system();
+        let to_ray = |p: Vector3| {
+            let p = p - origin;
+            Vector3::new(p.inner_product(x), p.inner_product(y), p.inner_product(z))
+        };
+
+        let (t, (segment, u)) = self.bvh.closest(origin, direction, |index| {
+            let segment = &self.segments[index];
+            let [a, b, c, d] = segment.control_points;
+            let control_points = [to_ray(a), to_ray(b), to_ray(c), to_ray(d)];
+            let depth = subdivisions(&control_points, segment.widths);
+            let mut closest = None;
+            intersect_segment(segment, control_points, 0.0, 1.0, depth, &mut closest);
+            closest.map(|(distance, u)| (distance / length, (index, u)))
+        })?;
+
+        let segment = &self.segments[segment];
+        let tangent = segment.tangent(u).normalized();
+        // faces the ray, across the tangent
+        let facing = direction * -1.0;
+        let normal = facing - tangent * facing.inner_product(tangent);
+        let normal = if normal.length_squared() > 0.0 {
+            normal.normalized()
+        } else {
+            tangent.create_coord_system().1
+        };
+        let side = normal.outer_product(tangent);
+        let offset = origin + direction * t - segment.point(u);
+        let h = (offset.inner_product(side) * 2.0 / segment.width(u)).clamp(-1.0, 1.0);
+        let normal = match self.kind {
+            CurveKind::Ribbon => normal,
+            CurveKind::Cylinder => normal * (1.0 - h * h).sqrt() + side * h,
+        };
+        Some((t, normal, tangent, (u, (h + 1.0) / 2.0)))
+    }
+}
+
+// Halves the segment until its pieces are flat enough to be taken as lines, skipping pieces
+// whose bounds miss the ray, and keeps the closest hit as the distance along the ray and u.
+// The control points are in the space of the ray.
+fn intersect_segment(
+    segment: &CurveSegment,
+    control_points: [Vector3; 4],
+    u0: f32,
+    u1: f32,
+    depth: i32,
+    closest: &mut Option<(f32, f32)>,
+) {
+    let limit = closest.map_or(f32::INFINITY, |(distance, _)| distance);
+    let radius = segment.width(u0).max(segment.width(u1)) / 2.0;
+    let (min, max) = hull_bounds(&control_points);
+    if min.x - radius > 0.0
+        || max.x + radius < 0.0
+        || min.y - radius > 0.0
+        || max.y + radius < 0.0
+        || max.z + radius < 0.0
+        || min.z - radius > limit
+    {
+        return;
+    }
+
+    if depth > 0 {
+        let [p0, p1, p2, p3] = control_points;
+        let middle = (p0 + (p1 + p2) * 3.0 + p3) / 8.0;
+        let first = [p0, (p0 + p1) / 2.0, (p0 + p1 * 2.0 + p2) / 4.0, middle];
+        let second = [middle, (p1 + p2 * 2.0 + p3) / 4.0, (p2 + p3) / 2.0, p3];
+        let u_middle = (u0 + u1) / 2.0;
+        intersect_segment(segment, first, u0, u_middle, depth - 1, closest);
+        intersect_segment(segment, second, u_middle, u1, depth - 1, closest);
+        return;
+    }
+
+    let [p0, p1, p2, p3] = control_points;
+    // the ray has to pass between the lines across the piece at its ends
+    if (p1.y - p0.y) * -p0.y + p0.x * (p0.x - p1.x) < 0.0
+        || (p2.y - p3.y) * -p3.y + p3.x * (p3.x - p2.x) < 0.0
+    {
+        return;
+    }
+    // where the line from the start to the end of the piece comes closest to the ray
+    let (dx, dy) = (p3.x - p0.x, p3.y - p0.y);
+    let denominator = dx * dx + dy * dy;
+    if denominator == 0.0 {
+        return;
+    }
+    let w = (-p0.x * dx - p0.y * dy) / denominator;
+    let u = (u0 + (u1 - u0) * w).clamp(u0, u1);
+    let width = segment.width(u);
+    let (point, _) = evaluate(&control_points, w.clamp(0.0, 1.0));
+    if point.x * point.x + point.y * point.y > width * width / 4.0 {
+        return;
+    }
+    // a ray leaving the curve passes it within its width
+    if point.z < width || point.z > limit {
+        return;
+    }
+    *closest = Some((point.z, u));
+}
+
+// Halvings after which the piece is within a twentieth of the width of a straight line, from
+// the second differences of the control points
+fn subdivisions(control_points: &[Vector3; 4], widths: (f32, f32)) -> i32 {
+    let mut bend = 0.0_f32;
+    for i in 0..2 {
+        let difference = control_points[i] - control_points[i + 1] * 2.0 + control_points[i + 2];
+        bend = bend
+            .max(difference.x.abs())
+            .max(difference.y.abs())
+            .max(difference.z.abs());
+    }
+    let epsilon = widths.0.max(widths.1) * 0.05;
+    let levels = (2.0_f32.sqrt() * 6.0 * bend / (8.0 * epsilon)).log2() / 2.0;
+    if levels.is_nan() {
+        return 0;
+    }
+    (levels.ceil() as i32).clamp(0, MAX_SUBDIVISIONS)
+}
+
+fn hull_bounds(control_points: &[Vector3; 4]) -> (Vector3, Vector3) {
+    control_points
+        .iter()
+        .fold((control_points[0], control_points[0]), |(min, max), p| {
+            (min.min(*p), max.max(*p))
+        })
+}
+
+// de Casteljau: the point at u and the derivative there
+fn evaluate(control_points: &[Vector3; 4], u: f32) -> (Vector3, Vector3) {
+    let lerp = |a: Vector3, b: Vector3| a + (b - a) * u;
+    let [p0, p1, p2, p3] = *control_points;
+    let (a, b, c) = (lerp(p0, p1), lerp(p1, p2), lerp(p2, p3));
+    let (d, e) = (lerp(a, b), lerp(b, c));
+    let derivative = (e - d) * 3.0;
+    // at ends where control points coincide the derivative vanishes
+    let derivative = if derivative.length_squared() > 0.0 {
+        derivative
+    } else {
+        p3 - p0
+    };
+    (lerp(d, e), derivative)
+}
+
+// the hierarchy follows from the segments
+impl Hash for Curves {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for segment in &self.segments {
+            segment.control_points.hash(state);
+            segment.widths.0.to_bits().hash(state);
+            segment.widths.1.to_bits().hash(state);
+        }
+        self.kind.hash(state);
+    }
+}
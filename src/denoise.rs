@@ -0,0 +1,144 @@
+use crate::adaptive::PixelStatistics;
+use crate::aov::SurfacePixel;
+use crate::material::Color;
+use rayon::prelude::*;
+
+// B3 spline, from the center outwards
+const KERNEL: [f32; 3] = [3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+// Edge-avoiding à-trous wavelet filter (Dammertz et al., "Edge-Avoiding À-Trous Wavelet
+// Transform for fast Global Illumination Filtering"). Every iteration applies a 5x5 kernel
+// with holes of twice the size of the previous one, neighbours only contribute when their
+// normal, depth and albedo match. Colors are compared relative to the noise of the pixel,
+// which is estimated from the sample variance and filtered along with the colors (as in
+// Schied et al., "Spatiotemporal Variance-Guided Filtering").
+#[derive(Debug, Copy, Clone)]
+pub struct Denoiser {
+    pub iterations: u32,
+    // allowed luminance difference in standard deviations of the noise, larger values blur more
+    pub color_sigma: f32,
+    // exponent of the cosine between normals
+    pub normal_power: f32,
+    // allowed depth difference relative to the depth, per pixel of distance
+    pub depth_sigma: f32,
+    pub albedo_sigma: f32,
+}
+
+impl Denoiser {
+    // colors, statistics and surfaces hold one entry per pixel, row by row
+    pub fn denoise(
+        &self,
+        width: usize,
+        height: usize,
+        colors: &[Color],
+        statistics: &[PixelStatistics],
+        surfaces: &[SurfacePixel],
+    ) -> Vec<Color> {
+        let mut colors = colors.to_vec();
+        // variance of the mean of the pixel luminance
+        let mut variances = statistics
+            .iter()
+            .map(|s| {
+                if s.count() > 0 {
+                    s.variance().luminance().max(0.0) / (s.count() as f32)
+                } else {
+                    0.0
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for iteration in 0..self.iterations {
+            let step = 1 << iteration;
+            let (next_colors, next_variances) = (0..width * height)
+                .into_par_iter()
+                .map(|pixel| {
+                    self.filter_pixel(
+                        (width, height),
+                        (pixel % width, pixel / width),
+                        step,
+                        &colors,
+                        &variances,
+                        surfaces,
+                    )
+                })
+                .unzip();
+            colors = next_colors;
+            variances = next_variances;
+        }
+        colors
+    }
+
+    fn filter_pixel(
+        &self,
+        (width, height): (usize, usize),
+        (x, y): (usize, usize),
+        step: isize,
+        colors: &[Color],
+        variances: &[f32],
+        surfaces: &[SurfacePixel],
+    ) -> (Color, f32) {
+        let center = y * width + x;
+        let surface = &surfaces[center];
+        let luminance = colors[center].luminance();
+        let color_scale = self.color_sigma * variances[center].sqrt() + 1e-4;
+
+        let mut color_sum = Color::black();
+        let mut weight_sum = 0.0;
+        let mut variance_sum = 0.0;
+        for dy in -2..=2_isize {
+            for dx in -2..=2_isize {
+                let qx = x as isize + dx * step;
+                let qy = y as isize + dy * step;
+                if qx < 0 || qy < 0 || qx >= width as isize || qy >= height as isize {
+                    continue;
+                }
+                let neighbour = qy as usize * width + qx as usize;
+                let other = &surfaces[neighbour];
+
+                let geometry_weight = match (surface.is_hit(), other.is_hit()) {
+                    (true, true) => {
+                        let (normal, other_normal) = (surface.normal(), other.normal());
+                        // normals that cancelled out say nothing about the geometry
+                        let normal_weight = if normal.length_squared() == 0.0
+                            || other_normal.length_squared() == 0.0
+                        {
+                            1.0
+                        } else {
+                            normal
+                                .inner_product(other_normal)
+                                .max(0.0)
+                                .powf(self.normal_power)
+                        };
+                        let distance = ((dx * dx + dy * dy) as f32).sqrt() * step as f32;
+                        let depth_weight = (-(surface.depth() - other.depth()).abs()
+                            / (self.depth_sigma * surface.depth() * distance + 1e-4))
+                            .exp();
+                        let albedo_difference = (surface.albedo() - other.albedo()).to_array();
+                        let albedo_distance = albedo_difference.iter().map(|d| d * d).sum::<f32>();
+                        let albedo_weight =
+                            (-albedo_distance / (self.albedo_sigma * self.albedo_sigma)).exp();
+                        normal_weight * depth_weight * albedo_weight
+                    }
+                    (false, false) => 1.0,
+                    _ => 0.0,
+                };
+                let color_weight =
+                    (-(luminance - colors[neighbour].luminance()).abs() / color_scale).exp();
+
+                let weight = KERNEL[dx.unsigned_abs()]
+                    * KERNEL[dy.unsigned_abs()]
+                    * geometry_weight
+                    * color_weight;
+                color_sum += colors[neighbour] * weight;
+                weight_sum += weight;
+                variance_sum += weight * weight * variances[neighbour];
+            }
+        }
+
+        // the center pixel always has a weight, so weight_sum is never zero
+        (
+            color_sum / weight_sum,
+            variance_sum / (weight_sum * weight_sum),
+        )
+    }
+}
@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const MAGIC: [u8; 4] = [0x76, 0x2f, 0x31, 0x01];
+
+pub enum ChannelData {
+    Uint(Vec<u32>),
+    Float(Vec<f32>),
+}
+
+impl ChannelData {
+    fn pixel_type(&self) -> i32 {
+        match self {
+            ChannelData::Uint(_) => 0,
+            ChannelData::Float(_) => 2,
+        }
+    }
+
+    fn write_row<W: Write>(&self, writer: &mut W, start: usize, end: usize) -> std::io::Result<()> {
+        match self {
+            ChannelData::Uint(values) => {
+                for value in &values[start..end] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+            ChannelData::Float(values) => {
+                for value in &values[start..end] {
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// Uncompressed single part scanline OpenEXR image. Channels are named layer.channel for
+// multi-layer images, e.g. "diffuse.R", channels without a layer make up the main image.
+pub struct ExrImage {
+    width: usize,
+    height: usize,
+    channels: Vec<(String, ChannelData)>,
+}
+
+impl ExrImage {
+    pub fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            channels: vec![],
+        }
+    }
+
+    // data holds one value per pixel, row by row
+    pub fn add_channel(&mut self, name: &str, data: ChannelData) {
+        self.channels.push((name.to_string(), data));
+    }
+
+    pub fn write_file(&self, file_name: &str) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        // the file format requires the channels in alphabetical order
+        let mut channels = self.channels.iter().collect::<Vec<_>>();
+        channels.sort_by(|a, b| a.0.as_bytes().cmp(b.0.as_bytes()));
+
+        let mut header = vec![];
+        header.extend_from_slice(&MAGIC);
+        header.extend_from_slice(&2_i32.to_le_bytes());
+
+        let mut channel_list = vec![];
+        for (name, data) in &channels {
+            channel_list.extend_from_slice(name.as_bytes());
+            channel_list.push(0);
+            channel_list.extend_from_slice(&data.pixel_type().to_le_bytes());
+            // linear flag and three reserved bytes, followed by the x and y sampling
+            channel_list.extend_from_slice(&[0, 0, 0, 0]);
+            channel_list.extend_from_slice(&1_i32.to_le_bytes());
+            channel_list.extend_from_slice(&1_i32.to_le_bytes());
+        }
+        channel_list.push(0);
+        add_attribute(&mut header, "channels", "chlist", &channel_list);
+
+        add_attribute(&mut header, "compression", "compression", &[0]);
+        let mut window = vec![];
+        for value in [0, 0, self.width as i32 - 1, self.height as i32 - 1] {
+            window.extend_from_slice(&value.to_le_bytes());
+        }
+        add_attribute(&mut header, "dataWindow", "box2i", &window);
+        add_attribute(&mut header, "displayWindow", "box2i", &window);
+        add_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        add_attribute(
+            &mut header,
+            "pixelAspectRatio",
+            "float",
+            &1.0_f32.to_le_bytes(),
+        );
+        let mut center = vec![];
+        center.extend_from_slice(&0.0_f32.to_le_bytes());
+        center.extend_from_slice(&0.0_f32.to_le_bytes());
+        add_attribute(&mut header, "screenWindowCenter", "v2f", &center);
+        add_attribute(
+            &mut header,
+            "screenWindowWidth",
+            "float",
+            &1.0_f32.to_le_bytes(),
+        );
+        header.push(0);
+        writer.write_all(&header)?;
+
+        // every scanline is its own chunk, preceded by its y coordinate and size
+        let row_size = 4 * self.width * channels.len();
+        let first_chunk = header.len() + 8 * self.height;
+        for y in 0..self.height {
+            let offset = first_chunk + y * (8 + row_size);
+            writer.write_all(&(offset as u64).to_le_bytes())?;
+        }
+        for y in 0..self.height {
+            writer.write_all(&(y as i32).to_le_bytes())?;
+            writer.write_all(&(row_size as i32).to_le_bytes())?;
+            for (_, data) in &channels {
+                data.write_row(writer, y * self.width, (y + 1) * self.width)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn add_attribute(header: &mut Vec<u8>, name: &str, attribute_type: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(attribute_type.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
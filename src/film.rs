@@ -0,0 +1,341 @@
+use crate::material::Color;
+use crate::photon_map::{read_floats, write_floats};
+use crate::ppm::PPM;
+use core::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind, Read, Write};
+
+// smallest filter weight a pixel needs to count as covered
+const MIN_WEIGHT_SUM: f32 = 1e-6;
+
+// Separable reconstruction filters, evaluated per axis on offsets within the filter radius
+#[derive(Debug, Copy, Clone)]
+pub enum PixelFilter {
+    Box,
+    Tent,
+    // the argument is the falloff alpha
+    Gaussian(f32),
+    MitchellNetravali { b: f32, c: f32 },
+    // the argument is the number of lobes tau of the sinc window
+    Lanczos(f32),
+}
+
+impl PixelFilter {
+    pub fn evaluate(&self, x: f32, y: f32, radius: f32) -> f32 {
+        self.evaluate_1d(x, radius) * self.evaluate_1d(y, radius)
+    }
+
+    fn evaluate_1d(&self, x: f32, radius: f32) -> f32 {
+        let x = x.abs();
+        if x > radius {
+            return 0.0;
+        }
+        match *self {
+            PixelFilter::Box => 1.0,
+            PixelFilter::Tent => radius - x,
+            PixelFilter::Gaussian(alpha) => {
+                ((-alpha * x * x).exp() - (-alpha * radius * radius).exp()).max(0.0)
+            }
+            PixelFilter::MitchellNetravali { b, c } => {
+                let x = 2.0 * x / radius;
+                if x > 1.0 {
+                    ((-b - 6.0 * c) * x * x * x
+                        + (6.0 * b + 30.0 * c) * x * x
+                        + (-12.0 * b - 48.0 * c) * x
+                        + (8.0 * b + 24.0 * c))
+                        / 6.0
+                } else {
+                    ((12.0 - 9.0 * b - 6.0 * c) * x * x * x
+                        + (-18.0 + 12.0 * b + 6.0 * c) * x * x
+                        + (6.0 - 2.0 * b))
+                        / 6.0
+                }
+            }
+            PixelFilter::Lanczos(tau) => sinc(x) * sinc(x / tau),
+        }
+    }
+}
+
+impl Hash for PixelFilter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match *self {
+            PixelFilter::Box => 0.hash(state),
+            PixelFilter::Tent => 1.hash(state),
+            PixelFilter::Gaussian(alpha) => {
+                2.hash(state);
+                alpha.to_bits().hash(state);
+            }
+            PixelFilter::MitchellNetravali { b, c } => {
+                3.hash(state);
+                b.to_bits().hash(state);
+                c.to_bits().hash(state);
+            }
+            PixelFilter::Lanczos(tau) => {
+                4.hash(state);
+                tau.to_bits().hash(state);
+            }
+        }
+    }
+}
+
+fn sinc(x: f32) -> f32 {
+    if x < 1e-5 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct FilmPixel {
+    color_sum: Color,
+    weight_sum: f32,
+    splat: Color,
+}
+
+// Accumulates filtered samples over the whole image. Samples are positioned in raster space,
+// where pixel (x, y) covers [x, x + 1) x [y, y + 1), and are added to every pixel whose
+// center lies within the filter radius. Rendering threads fill FilmTiles that are merged in.
+#[derive(Clone)]
+pub struct Film {
+    width: usize,
+    height: usize,
+    filter: PixelFilter,
+    radius: f32,
+    pixels: Vec<FilmPixel>,
+}
+
+impl Film {
+    pub fn new(width: usize, height: usize, filter: PixelFilter, radius: f32) -> Self {
+        Self {
+            width,
+            height,
+            filter,
+            radius,
+            pixels: vec![FilmPixel::default(); width * height],
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // tile receiving the samples of pixels [x0, x1) x [y0, y1), grown by the filter radius
+    pub fn tile(&self, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> FilmTile {
+        let margin = (self.radius - 0.5).ceil().max(0.0) as usize;
+        let x0 = x0.saturating_sub(margin);
+        let y0 = y0.saturating_sub(margin);
+        let x1 = (x1 + margin).min(self.width);
+        let y1 = (y1 + margin).min(self.height);
+        FilmTile {
+            x0,
+            y0,
+            x1,
+            y1,
+            filter: self.filter,
+            radius: self.radius,
+            pixels: vec![FilmPixel::default(); (x1 - x0) * (y1 - y0)],
+        }
+    }
+
+    pub fn merge_tile(&mut self, tile: &FilmTile) {
+        for y in tile.y0..tile.y1 {
+            for x in tile.x0..tile.x1 {
+                let source = &tile.pixels[(y - tile.y0) * (tile.x1 - tile.x0) + (x - tile.x0)];
+                let target = &mut self.pixels[y * self.width + x];
+                target.color_sum += source.color_sum;
+                target.weight_sum += source.weight_sum;
+            }
+        }
+    }
+
+    // unfiltered contribution to an arbitrary pixel, as produced by tracing paths from the
+    // lights towards the camera
+    pub fn add_splat(&mut self, (x, y): (f32, f32), color: Color) {
+        if x < 0.0 || y < 0.0 {
+            return;
+        }
+        let (x, y) = (x as usize, y as usize);
+        if x < self.width && y < self.height {
+            self.pixels[y * self.width + x].splat += color;
+        }
+    }
+
+    // splats are scaled by splat_scale, typically one over the number of light paths
+    pub fn color(&self, x: usize, y: usize, splat_scale: f32) -> Color {
+        let pixel = &self.pixels[y * self.width + x];
+        // the negative lobes of the Mitchell-Netravali and Lanczos filters can leave pixels
+        // with little or no coverage near zero weight, which would blow the color up
+        let filtered = if pixel.weight_sum > MIN_WEIGHT_SUM {
+            (pixel.color_sum / pixel.weight_sum).non_negative()
+        } else {
+            Color::black()
+        };
+        filtered + pixel.splat * splat_scale
+    }
+
+    pub fn write_ppm(&self, file_name: &str, splat_scale: f32) -> std::io::Result<()> {
+        self.to_ppm(file_name, splat_scale).write_file()
+    }
+
+    // the image in memory, to be written later with PPM::write_file
+    pub fn to_ppm(&self, file_name: &str, splat_scale: f32) -> PPM {
+        let mut ppm = PPM::new(&file_name.to_string(), self.width, self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                ppm.add_pixel(x, y, self.color(x, y, splat_scale));
+            }
+        }
+        ppm
+    }
+
+    // the accumulated sums are stored bit exact, so a film read back continues to accumulate
+    // exactly as the original would have
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&(self.width as u64).to_le_bytes())?;
+        writer.write_all(&(self.height as u64).to_le_bytes())?;
+        for pixel in &self.pixels {
+            write_floats(writer, &pixel.color_sum.to_array())?;
+            writer.write_all(&pixel.weight_sum.to_le_bytes())?;
+            write_floats(writer, &pixel.splat.to_array())?;
+        }
+        Ok(())
+    }
+
+    // the film has to be of the given size, which is checked before anything is allocated
+    pub fn read_from<R: Read>(
+        reader: &mut R,
+        (width, height): (usize, usize),
+        filter: PixelFilter,
+        radius: f32,
+    ) -> std::io::Result<Self> {
+        let mut word = [0; 8];
+        reader.read_exact(&mut word)?;
+        let stored_width = u64::from_le_bytes(word);
+        reader.read_exact(&mut word)?;
+        let stored_height = u64::from_le_bytes(word);
+        if stored_width != width as u64 || stored_height != height as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "film has a different size",
+            ));
+        }
+        let mut film = Self::new(width, height, filter, radius);
+        for pixel in &mut film.pixels {
+            let [r, g, b] = read_floats(reader)?;
+            pixel.color_sum = Color::new(r, g, b);
+            let mut weight = [0; 4];
+            reader.read_exact(&mut weight)?;
+            pixel.weight_sum = f32::from_le_bytes(weight);
+            let [r, g, b] = read_floats(reader)?;
+            pixel.splat = Color::new(r, g, b);
+        }
+        Ok(film)
+    }
+}
+
+pub struct FilmTile {
+    x0: usize,
+    y0: usize,
+    x1: usize,
+    y1: usize,
+    filter: PixelFilter,
+    radius: f32,
+    pixels: Vec<FilmPixel>,
+}
+
+impl FilmTile {
+    pub fn add_sample(&mut self, (x, y): (f32, f32), color: Color) {
+        // pixels whose centers x + 0.5 lie within the radius of the sample
+        let min_x = ((x - 0.5 - self.radius).ceil().max(self.x0 as f32)) as usize;
+        let max_x = ((x - 0.5 + self.radius).floor()).min((self.x1 - 1) as f32);
+        let min_y = ((y - 0.5 - self.radius).ceil().max(self.y0 as f32)) as usize;
+        let max_y = ((y - 0.5 + self.radius).floor()).min((self.y1 - 1) as f32);
+        if max_x < 0.0 || max_y < 0.0 {
+            return;
+        }
+        for py in min_y..=(max_y as usize) {
+            for px in min_x..=(max_x as usize) {
+                let weight =
+                    self.filter
+                        .evaluate(px as f32 + 0.5 - x, py as f32 + 0.5 - y, self.radius);
+                let pixel = &mut self.pixels[(py - self.y0) * (self.x1 - self.x0) + (px - self.x0)];
+                pixel.color_sum += color * weight;
+                pixel.weight_sum += weight;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn film_pixel(color_sum: Color, weight_sum: f32) -> Color {
+        let mut film = Film::new(1, 1, PixelFilter::Lanczos(2.0), 2.0);
+        film.pixels[0].color_sum = color_sum;
+        film.pixels[0].weight_sum = weight_sum;
+        film.color(0, 0, 0.0)
+    }
+
+    // the negative lobes can cancel the weight of a pixel or turn it negative
+    #[test]
+    fn uncovered_pixels_are_black() {
+        let color = Color::new(0.5, 0.25, 1.0);
+        assert_eq!(film_pixel(color, 0.0).to_array(), [0.0; 3]);
+        assert_eq!(film_pixel(color * 1e-8, 1e-8).to_array(), [0.0; 3]);
+        assert_eq!(film_pixel(color * -0.5, -0.5).to_array(), [0.0; 3]);
+        assert_eq!(film_pixel(color * 2.0, 2.0).to_array(), color.to_array());
+        assert_eq!(
+            film_pixel(Color::new(-1.0, 1.0, 0.0), 1.0).to_array(),
+            [0.0, 1.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn samples_are_weighted_by_the_filter() {
+        let mut film = Film::new(8, 8, PixelFilter::Tent, 2.0);
+        let mut tile = film.tile((0, 0), (8, 8));
+        tile.add_sample((4.5, 4.5), Color::new(1.0, 0.0, 0.0));
+        tile.add_sample((5.5, 4.5), Color::new(0.0, 1.0, 0.0));
+        film.merge_tile(&tile);
+        // weights 4 and 2 at the first pixel, the second sample is out of reach of the pixel to its left
+        let [r, g, b] = film.color(4, 4, 0.0).to_array();
+        assert!((r - 2.0 / 3.0).abs() < 1e-6 && (g - 1.0 / 3.0).abs() < 1e-6 && b == 0.0);
+        assert_eq!(film.color(3, 4, 0.0).to_array(), [1.0, 0.0, 0.0]);
+        assert_eq!(film.color(0, 0, 0.0).to_array(), [0.0; 3]);
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let filter = PixelFilter::Gaussian(2.0);
+        let mut film = Film::new(5, 3, filter, 1.5);
+        let mut tile = film.tile((0, 0), (5, 3));
+        tile.add_sample((1.25, 2.5), Color::new(1.0, 2.0, 3.0));
+        tile.add_sample((3.75, 0.5), Color::new(0.5, 0.0, 0.25));
+        film.merge_tile(&tile);
+        film.add_splat((4.5, 1.5), Color::new(0.0, 4.0, 0.0));
+        let mut bytes = vec![];
+        film.write_to(&mut bytes).unwrap();
+
+        let read = Film::read_from(&mut bytes.as_slice(), (5, 3), filter, 1.5).unwrap();
+        for y in 0..3 {
+            for x in 0..5 {
+                assert_eq!(
+                    read.pixels[y * 5 + x].weight_sum,
+                    film.pixels[y * 5 + x].weight_sum
+                );
+                assert_eq!(
+                    read.color(x, y, 0.5).to_array(),
+                    film.color(x, y, 0.5).to_array()
+                );
+            }
+        }
+        assert!(Film::read_from(&mut bytes.as_slice(), (3, 5), filter, 1.5).is_err());
+        assert!(Film::read_from(&mut &bytes[..bytes.len() - 1], (5, 3), filter, 1.5).is_err());
+    }
+}
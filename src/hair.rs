@@ -0,0 +1,358 @@
+use crate::material::Color;
+use crate::vector3::Vector3;
+use std::f32::consts::{LN_2, PI};
+
+// Scattering paths followed on their own: reflection, transmission through the fiber and one
+// internal reflection. The last term stands for all longer paths together.
+const P_MAX: usize = 3;
+
+// Hair fiber after d'Eon et al. and Chiang et al., as in pbrt: a rough dielectric cylinder
+// that absorbs light inside, with the scales on its surface tilting the highlights. The
+// absorption is per unit of the fiber radius.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Hair {
+    pub absorption: Color,
+    // widths of the highlights along the fiber and around it, within (0, 1]
+    pub longitudinal_roughness: f32,
+    pub azimuthal_roughness: f32,
+    // tilt of the scales in degrees
+    pub scale_angle: f32,
+    pub refractive_index: f32,
+}
+
+impl Hair {
+    // the absorption that makes the fiber look the given color after many bounces
+    pub fn from_color(color: Color, longitudinal_roughness: f32, azimuthal_roughness: f32) -> Self {
+        let b = azimuthal_roughness;
+        let scale = 5.969 - 0.215 * b + 2.532 * b.powi(2) - 10.73 * b.powi(3)
+            + 5.574 * b.powi(4)
+            + 0.245 * b.powi(5);
+        let absorption = map(color, |c| (c.clamp(1e-4, 1.0).ln() / scale).powi(2));
+        Self::new(absorption, longitudinal_roughness, azimuthal_roughness)
+    }
+
+    // the absorption of the dark eumelanin and the red pheomelanin in natural hair, from none
+    // for white hair to around 8 for black hair
+    pub fn from_melanin(
+        eumelanin: f32,
+        pheomelanin: f32,
+        longitudinal_roughness: f32,
+        azimuthal_roughness: f32,
+    ) -> Self {
+        let absorption =
+            Color::new(0.419, 0.697, 1.37) * eumelanin + Color::new(0.187, 0.4, 1.05) * pheomelanin;
+        Self::new(absorption, longitudinal_roughness, azimuthal_roughness)
+    }
+
+    fn new(absorption: Color, longitudinal_roughness: f32, azimuthal_roughness: f32) -> Self {
+        Self {
+            absorption,
+            longitudinal_roughness,
+            azimuthal_roughness,
+            scale_angle: 2.0,
+            refractive_index: 1.55,
+        }
+    }
+}
+
+// The hair model at one point across a fiber. Directions are in its frame, with x along the
+// fiber, z facing the viewer and y across the fiber towards h = 1.
+pub struct HairScattering {
+    hair: Hair,
+    // offset across the fiber, from -1 to 1
+    h: f32,
+    gamma_o: f32,
+    // longitudinal variance and logistic scale of the azimuthal distribution
+    v: [f32; P_MAX + 1],
+    s: f32,
+    // sine and cosine of 2^k times the scale angle
+    sin_2k_alpha: [f32; 3],
+    cos_2k_alpha: [f32; 3],
+}
+
+impl HairScattering {
+    pub fn new(hair: &Hair, h: f32) -> Self {
+        let h = h.clamp(-1.0, 1.0);
+        let beta_m = hair.longitudinal_roughness.clamp(1e-3, 1.0);
+        let beta_n = hair.azimuthal_roughness.clamp(1e-3, 1.0);
+        let v0 = (0.726 * beta_m + 0.812 * beta_m.powi(2) + 3.7 * beta_m.powi(20)).powi(2);
+        let mut v = [4.0 * v0; P_MAX + 1];
+        v[0] = v0;
+        v[1] = 0.25 * v0;
+        let s =
+            (PI / 8.0).sqrt() * (0.265 * beta_n + 1.194 * beta_n.powi(2) + 5.372 * beta_n.powi(22));
+
+        let mut sin_2k_alpha = [hair.scale_angle.to_radians().sin(), 0.0, 0.0];
+        let mut cos_2k_alpha = [safe_sqrt(1.0 - sin_2k_alpha[0].powi(2)), 0.0, 0.0];
+        for i in 1..3 {
+            sin_2k_alpha[i] = 2.0 * cos_2k_alpha[i - 1] * sin_2k_alpha[i - 1];
+            cos_2k_alpha[i] = cos_2k_alpha[i - 1].powi(2) - sin_2k_alpha[i - 1].powi(2);
+        }
+        Self {
+            hair: *hair,
+            h,
+            gamma_o: h.asin(),
+            v,
+            s,
+            sin_2k_alpha,
+            cos_2k_alpha,
+        }
+    }
+
+    // Radiance scattered towards wo per unit of radiance arriving from wi and solid angle. The
+    // cosine of the light with the fiber is part of the longitudinal terms.
+    pub fn evaluate(&self, wo: Vector3, wi: Vector3) -> Color {
+        let (sin_theta_o, cos_theta_o) = (wo.x, safe_sqrt(1.0 - wo.x * wo.x));
+        let (sin_theta_i, cos_theta_i) = (wi.x, safe_sqrt(1.0 - wi.x * wi.x));
+        let phi = wi.z.atan2(wi.y) - wo.z.atan2(wo.y);
+        let (ap, gamma_t) = self.attenuations(sin_theta_o, cos_theta_o);
+
+        let mut sum = Color::black();
+        for (p, ap) in ap.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            let longitudinal = mp(
+                cos_theta_i,
+                cos_theta_op.abs(),
+                sin_theta_i,
+                sin_theta_op,
+                self.v[p],
+            );
+            sum += ap * longitudinal * self.np(phi, p, gamma_t);
+        }
+        let longitudinal = mp(
+            cos_theta_i,
+            cos_theta_o,
+            sin_theta_i,
+            sin_theta_o,
+            self.v[P_MAX],
+        );
+        sum + ap[P_MAX] * longitudinal / (2.0 * PI)
+    }
+
+    // A direction towards the light chosen like the model scatters, and the model divided by
+    // the density of choosing it. The first sample picks the path and its azimuth, the second
+    // the longitudinal angle.
+    pub fn sample(&self, wo: Vector3, u0: (f32, f32), u1: (f32, f32)) -> (Vector3, Color) {
+        let (sin_theta_o, cos_theta_o) = (wo.x, safe_sqrt(1.0 - wo.x * wo.x));
+        let phi_o = wo.z.atan2(wo.y);
+        let (ap, gamma_t) = self.attenuations(sin_theta_o, cos_theta_o);
+        let weights = path_weights(&ap);
+
+        let mut choice = u0.0;
+        let mut p = 0;
+        while p < P_MAX && choice >= weights[p] {
+            choice -= weights[p];
+            p += 1;
+        }
+
+        let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+        let v = self.v[p];
+        let u = u1.0.max(1e-5);
+        let cos_theta = 1.0 + v * (u + (1.0 - u) * (-2.0 / v).exp()).ln();
+        let sin_theta = safe_sqrt(1.0 - cos_theta * cos_theta);
+        let cos_phi = (2.0 * PI * u1.1).cos();
+        let sin_theta_i =
+            (-cos_theta * sin_theta_op + sin_theta * cos_phi * cos_theta_op).clamp(-1.0, 1.0);
+        let cos_theta_i = safe_sqrt(1.0 - sin_theta_i * sin_theta_i);
+
+        let dphi = if p < P_MAX {
+            phi(p, self.gamma_o, gamma_t) + sample_trimmed_logistic(u0.1, self.s, -PI, PI)
+        } else {
+            2.0 * PI * u0.1
+        };
+        let phi_i = phi_o + dphi;
+        let wi = Vector3::new(
+            sin_theta_i,
+            cos_theta_i * phi_i.cos(),
+            cos_theta_i * phi_i.sin(),
+        );
+
+        let mut pdf = 0.0;
+        for (p, weight) in weights.iter().enumerate().take(P_MAX) {
+            let (sin_theta_op, cos_theta_op) = self.tilted(p, sin_theta_o, cos_theta_o);
+            pdf += mp(
+                cos_theta_i,
+                cos_theta_op.abs(),
+                sin_theta_i,
+                sin_theta_op,
+                self.v[p],
+            ) * weight
+                * self.np(dphi, p, gamma_t);
+        }
+        pdf += mp(
+            cos_theta_i,
+            cos_theta_o,
+            sin_theta_i,
+            sin_theta_o,
+            self.v[P_MAX],
+        ) * weights[P_MAX]
+            / (2.0 * PI);
+
+        let value = self.evaluate(wo, wi);
+        if pdf > 0.0 {
+            (wi, value / pdf)
+        } else {
+            (wi, Color::black())
+        }
+    }
+
+    // Fraction of the light that leaves along each path, from the Fresnel reflectance at the
+    // surface and the absorption along the chords through the fiber. Also the angle of the
+    // refracted ray to the axis of the fiber seen along it.
+    fn attenuations(&self, sin_theta_o: f32, cos_theta_o: f32) -> ([Color; P_MAX + 1], f32) {
+        let eta = self.hair.refractive_index;
+        let sin_theta_t = sin_theta_o / eta;
+        let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+        // the refractive index of the fiber seen along it
+        let etap = safe_sqrt(eta * eta - sin_theta_o * sin_theta_o) / cos_theta_o.max(1e-6);
+        let sin_gamma_t = (self.h / etap).clamp(-1.0, 1.0);
+        let cos_gamma_t = safe_sqrt(1.0 - sin_gamma_t * sin_gamma_t);
+        let length = 2.0 * cos_gamma_t / cos_theta_t.max(1e-6);
+        let transmittance = map(self.hair.absorption, |a| (-a * length).exp());
+
+        let cos_gamma_o = safe_sqrt(1.0 - self.h * self.h);
+        let f = fresnel(cos_theta_o * cos_gamma_o, eta);
+        let mut ap = [Color::black(); P_MAX + 1];
+        ap[0] = Color::white() * f;
+        ap[1] = transmittance * (1.0 - f).powi(2);
+        for p in 2..P_MAX {
+            ap[p] = ap[p - 1] * transmittance * f;
+        }
+        // the geometric series of all longer paths
+        let last = ap[P_MAX - 1] * transmittance * f;
+        ap[P_MAX] = zip(last, transmittance, |a, t| a / (1.0 - t * f));
+        (ap, sin_gamma_t.asin())
+    }
+
+    // The scales tilt the highlight of reflection towards the root by twice their angle, and
+    // the transmitted paths the other way
+    fn tilted(&self, p: usize, sin_theta_o: f32, cos_theta_o: f32) -> (f32, f32) {
+        let (sin, cos) = (self.sin_2k_alpha, self.cos_2k_alpha);
+        match p {
+            0 => (
+                sin_theta_o * cos[1] - cos_theta_o * sin[1],
+                cos_theta_o * cos[1] + sin_theta_o * sin[1],
+            ),
+            1 => (
+                sin_theta_o * cos[0] + cos_theta_o * sin[0],
+                cos_theta_o * cos[0] - sin_theta_o * sin[0],
+            ),
+            2 => (
+                sin_theta_o * cos[2] + cos_theta_o * sin[2],
+                cos_theta_o * cos[2] - sin_theta_o * sin[2],
+            ),
+            _ => (sin_theta_o, cos_theta_o),
+        }
+    }
+
+    // azimuthal distribution of path p around where a smooth fiber would send it
+    fn np(&self, phi_difference: f32, p: usize, gamma_t: f32) -> f32 {
+        let mut dphi = phi_difference - phi(p, self.gamma_o, gamma_t);
+        while dphi > PI {
+            dphi -= 2.0 * PI;
+        }
+        while dphi < -PI {
+            dphi += 2.0 * PI;
+        }
+        trimmed_logistic(dphi, self.s, -PI, PI)
+    }
+}
+
+// chance of sampling each path, by the luminance it carries
+fn path_weights(ap: &[Color; P_MAX + 1]) -> [f32; P_MAX + 1] {
+    let total: f32 = ap.iter().map(|a| a.luminance()).sum();
+    let mut weights = [1.0 / (P_MAX + 1) as f32; P_MAX + 1];
+    if total > 0.0 {
+        for (weight, a) in weights.iter_mut().zip(ap) {
+            *weight = a.luminance() / total;
+        }
+    }
+    weights
+}
+
+// longitudinal scattering with variance v, normalized over the sphere
+fn mp(cos_theta_i: f32, cos_theta_o: f32, sin_theta_i: f32, sin_theta_o: f32, v: f32) -> f32 {
+    let a = cos_theta_i * cos_theta_o / v;
+    let b = sin_theta_i * sin_theta_o / v;
+    if v <= 0.1 {
+        // in logarithms, since the terms overflow for small variances
+        (log_i0(a) - b - 1.0 / v + LN_2 + (1.0 / (2.0 * v)).ln()).exp()
+    } else {
+        (-b).exp() * i0(a) / ((1.0 / v).sinh() * 2.0 * v)
+    }
+}
+
+// modified Bessel function of the first kind and order zero, by its series
+fn i0(x: f32) -> f32 {
+    let (mut value, mut x2i, mut factorial, mut four_i) = (0.0, 1.0, 1.0, 1.0);
+    for i in 0..10 {
+        if i > 1 {
+            factorial *= i as f32;
+        }
+        value += x2i / (four_i * factorial * factorial);
+        x2i *= x * x;
+        four_i *= 4.0;
+    }
+    value
+}
+
+fn log_i0(x: f32) -> f32 {
+    if x > 12.0 {
+        x + 0.5 * (-(2.0 * PI).ln() + (1.0 / x).ln() + 1.0 / (8.0 * x))
+    } else {
+        i0(x).ln()
+    }
+}
+
+// azimuth at which path p leaves a smooth fiber, relative to where the light arrives from
+fn phi(p: usize, gamma_o: f32, gamma_t: f32) -> f32 {
+    let p = p as f32;
+    2.0 * p * gamma_t - 2.0 * gamma_o + p * PI
+}
+
+fn logistic(x: f32, s: f32) -> f32 {
+    let x = x.abs();
+    (-x / s).exp() / (s * (1.0 + (-x / s).exp()).powi(2))
+}
+
+fn logistic_cdf(x: f32, s: f32) -> f32 {
+    1.0 / (1.0 + (-x / s).exp())
+}
+
+// the logistic distribution restricted to [a, b]
+fn trimmed_logistic(x: f32, s: f32, a: f32, b: f32) -> f32 {
+    logistic(x, s) / (logistic_cdf(b, s) - logistic_cdf(a, s))
+}
+
+fn sample_trimmed_logistic(u: f32, s: f32, a: f32, b: f32) -> f32 {
+    let k = logistic_cdf(b, s) - logistic_cdf(a, s);
+    let x = -s * (1.0 / (u * k + logistic_cdf(a, s)) - 1.0).ln();
+    x.clamp(a, b)
+}
+
+// reflectance of a dielectric for unpolarized light arriving from outside
+fn fresnel(cos_theta_i: f32, eta: f32) -> f32 {
+    let cos_theta_i = cos_theta_i.clamp(0.0, 1.0);
+    let sin_theta_t = safe_sqrt(1.0 - cos_theta_i * cos_theta_i) / eta;
+    if sin_theta_t >= 1.0 {
+        return 1.0;
+    }
+    let cos_theta_t = safe_sqrt(1.0 - sin_theta_t * sin_theta_t);
+    let parallel = (eta * cos_theta_i - cos_theta_t) / (eta * cos_theta_i + cos_theta_t);
+    let perpendicular = (cos_theta_i - eta * cos_theta_t) / (cos_theta_i + eta * cos_theta_t);
+    (parallel * parallel + perpendicular * perpendicular) / 2.0
+}
+
+fn safe_sqrt(x: f32) -> f32 {
+    x.max(0.0).sqrt()
+}
+
+fn map(color: Color, f: impl Fn(f32) -> f32) -> Color {
+    let [r, g, b] = color.to_array();
+    Color::new(f(r), f(g), f(b))
+}
+
+fn zip(a: Color, b: Color, f: impl Fn(f32, f32) -> f32) -> Color {
+    let ([r1, g1, b1], [r2, g2, b2]) = (a.to_array(), b.to_array());
+    Color::new(f(r1, r2), f(g1, g2), f(b1, b2))
+}
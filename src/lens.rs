@@ -0,0 +1,598 @@
+use crate::ppm::PPM;
+use crate::vector3::Vector3;
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+// lens prescriptions are given in millimetres, the scene in metres
+pub const MILLIMETRE: f32 = 0.001;
+
+// radial bins of the film over which the exit pupil is bounded
+const PUPIL_BINS: usize = 64;
+// samples along each side of the rear element when bounding the exit pupil
+const PUPIL_SAMPLES: usize = 64;
+
+// Shape of the aperture as it can be given in the scene description, images are loaded by
+// load. Polygons are inscribed in the aperture circle, images are fit into the square around it.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ApertureShape {
+    Circular,
+    // rotation in degrees
+    Polygon { blades: u32, rotation: f32 },
+    // binary ppm file, the brightness of a pixel is its transmission
+    Image(&'static str),
+}
+
+impl ApertureShape {
+    pub fn load(&self) -> std::io::Result<Aperture> {
+        Ok(match *self {
+            ApertureShape::Circular => Aperture::Circular,
+            ApertureShape::Polygon { blades, rotation } => Aperture::Polygon {
+                blades: blades.max(3),
+                rotation: rotation * PI / 180.0,
+            },
+            ApertureShape::Image(file_name) => {
+                let image = PPM::read_file(file_name)?;
+                if image.width() == 0 || image.height() == 0 {
+                    return Err(Error::new(ErrorKind::InvalidData, "empty aperture image"));
+                }
+                Aperture::Image(Arc::new(ApertureImage::new(&image)))
+            }
+        })
+    }
+}
+
+// The opening of a lens with radius 1, points are given relative to its center
+#[derive(Clone)]
+pub enum Aperture {
+    Circular,
+    Polygon { blades: u32, rotation: f32 },
+    Image(Arc<ApertureImage>),
+}
+
+impl Aperture {
+    // point on the aperture with a density proportional to its transmission
+    pub fn sample(&self, (u, v): (f32, f32)) -> (f32, f32) {
+        match self {
+            Aperture::Circular => {
+                let point = Vector3::in_unit_disk((u, v));
+                (point.x, point.y)
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // all blades span a triangle of the same area with the center
+                let n = *blades as f32;
+                let blade = ((u * n) as u32).min(blades - 1);
+                let u = u * n - blade as f32;
+                let (x0, y0) = polygon_vertex(blade, *blades, *rotation);
+                let (x1, y1) = polygon_vertex(blade + 1, *blades, *rotation);
+                let s = u.sqrt();
+                let (b0, b1) = (s * (1.0 - v), s * v);
+                (b0 * x0 + b1 * x1, b0 * y0 + b1 * y1)
+            }
+            Aperture::Image(image) => image.sample((u, v)),
+        }
+    }
+
+    // images let light pass where their transmission is above one half
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        match self {
+            Aperture::Circular => x * x + y * y <= 1.0,
+            Aperture::Polygon { blades, rotation } => {
+                // distance to the center along the normal of the edge of the blade at the point
+                let sector = 2.0 * PI / *blades as f32;
+                let angle = (y.atan2(x) - rotation).rem_euclid(sector) - sector / 2.0;
+                (x * x + y * y).sqrt() * angle.cos() <= (sector / 2.0).cos()
+            }
+            Aperture::Image(image) => image.transmission(x, y) > 0.5,
+        }
+    }
+}
+
+fn polygon_vertex(index: u32, blades: u32, rotation: f32) -> (f32, f32) {
+    let angle = rotation + 2.0 * PI * (index % blades) as f32 / blades as f32;
+    (angle.cos(), angle.sin())
+}
+
+impl Hash for Aperture {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Aperture::Circular => {}
+            Aperture::Polygon { blades, rotation } => {
+                blades.hash(state);
+                rotation.to_bits().hash(state);
+            }
+            Aperture::Image(image) => {
+                image.width.hash(state);
+                image.height.hash(state);
+                for weight in &image.weights {
+                    weight.to_bits().hash(state);
+                }
+            }
+        }
+    }
+}
+
+// Aperture image sampled through the distribution of its rows and the pixels within a row
+pub struct ApertureImage {
+    width: usize,
+    height: usize,
+    weights: Vec<f32>,
+    // cumulative weights of the rows, and of the pixels of every row, normalized to 1
+    row_cdf: Vec<f32>,
+    pixel_cdfs: Vec<f32>,
+    // half the size of the image in aperture radii
+    half_width: f32,
+    half_height: f32,
+}
+
+impl ApertureImage {
+    pub fn new(image: &PPM) -> Self {
+        let (width, height) = (image.width(), image.height());
+        let weights = (0..height)
+            .flat_map(|y| (0..width).map(move |x| image.pixel(x, y).luminance()))
+            .collect::<Vec<_>>();
+        let mut row_cdf = vec![0.0; height + 1];
+        let mut pixel_cdfs = vec![0.0; height * (width + 1)];
+        for y in 0..height {
+            let cdf = &mut pixel_cdfs[y * (width + 1)..(y + 1) * (width + 1)];
+            for x in 0..width {
+                cdf[x + 1] = cdf[x] + weights[y * width + x];
+            }
+            let row_weight = cdf[width];
+            if row_weight > 0.0 {
+                cdf.iter_mut().for_each(|c| *c /= row_weight);
+            }
+            row_cdf[y + 1] = row_cdf[y] + row_weight;
+        }
+        let total = row_cdf[height];
+        if total > 0.0 {
+            row_cdf.iter_mut().for_each(|c| *c /= total);
+        }
+        let size = width.max(height) as f32;
+        Self {
+            width,
+            height,
+            weights,
+            row_cdf,
+            pixel_cdfs,
+            half_width: width as f32 / size,
+            half_height: height as f32 / size,
+        }
+    }
+
+    // the first row of the image is at the top of the aperture
+    fn sample(&self, (u, v): (f32, f32)) -> (f32, f32) {
+        let (y, fy) = sample_cdf(&self.row_cdf, u);
+        let (x, fx) = sample_cdf(
+            &self.pixel_cdfs[y * (self.width + 1)..(y + 1) * (self.width + 1)],
+            v,
+        );
+        (
+            ((x as f32 + fx) / self.width as f32 * 2.0 - 1.0) * self.half_width,
+            (1.0 - (y as f32 + fy) / self.height as f32 * 2.0) * self.half_height,
+        )
+    }
+
+    fn transmission(&self, x: f32, y: f32) -> f32 {
+        let px = ((x / self.half_width + 1.0) / 2.0 * self.width as f32).floor();
+        let py = ((1.0 - y / self.half_height) / 2.0 * self.height as f32).floor();
+        if px < 0.0 || py < 0.0 || px >= self.width as f32 || py >= self.height as f32 {
+            0.0
+        } else {
+            self.weights[py as usize * self.width + px as usize]
+        }
+    }
+}
+
+// index of the interval of the normalized cdf that u falls in and the position within it
+fn sample_cdf(cdf: &[f32], u: f32) -> (usize, f32) {
+    let count = cdf.len() - 1;
+    let index = cdf
+        .partition_point(|&c| c <= u)
+        .saturating_sub(1)
+        .min(count - 1);
+    let width = cdf[index + 1] - cdf[index];
+    let offset = if width > 0.0 {
+        ((u - cdf[index]) / width).clamp(0.0, 1.0)
+    } else {
+        0.5
+    };
+    (index, offset)
+}
+
+// One surface of a lens prescription, listed from the front of the lens towards the film.
+// A positive curvature radius puts the center of the surface on the side of the film, a
+// radius of 0 marks the aperture stop. The thickness is the distance to the next surface and
+// the index of refraction that of the medium up to it, 0 or 1 for air. The thickness of the
+// last surface is ignored, the film is placed to focus the lens.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LensElement {
+    pub curvature_radius: f32,
+    pub thickness: f32,
+    pub refractive_index: f32,
+    pub aperture_diameter: f32,
+}
+
+impl Hash for LensElement {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.curvature_radius.to_bits().hash(state);
+        self.thickness.to_bits().hash(state);
+        self.refractive_index.to_bits().hash(state);
+        self.aperture_diameter.to_bits().hash(state);
+    }
+}
+
+// A lens traced surface by surface, in millimetres. The film lies in the plane z = 0 with the
+// lens in front of it towards positive z.
+#[derive(Clone)]
+pub struct LensSystem {
+    elements: Vec<LensElement>,
+    // z of the vertex of every surface
+    positions: Vec<f32>,
+    aperture: Aperture,
+    stop_radius: f32,
+    film_half_width: f32,
+    film_half_height: f32,
+    // bounds (x0, y0, x1, y1) on the plane of the rear surface of the rays that leave the lens,
+    // for film positions on the x axis
+    pupil_bounds: Vec<[f32; 4]>,
+    // area of the largest bounds, normally those on the axis
+    pupil_area: f32,
+}
+
+impl LensSystem {
+    // aperture_diameter opens the stop up to its diameter in the prescription, the film has
+    // the given diagonal and aspect ratio. The lens is focused at focus_distance millimetres
+    // from the film, which fails for lenses that do not focus there.
+    pub fn new(
+        elements: &[LensElement],
+        aperture_diameter: f32,
+        sensor_diagonal: f32,
+        aspect: f32,
+        focus_distance: f32,
+    ) -> std::io::Result<Self> {
+        let stop_radius = elements
+            .iter()
+            .find(|e| e.curvature_radius == 0.0)
+            .map_or(aperture_diameter, |e| {
+                e.aperture_diameter.min(aperture_diameter)
+            })
+            / 2.0;
+        let film_half_height = sensor_diagonal / (1.0 + aspect * aspect).sqrt() / 2.0;
+        let mut lens = Self {
+            elements: elements.to_vec(),
+            positions: vec![],
+            aperture: Aperture::Circular,
+            stop_radius,
+            film_half_width: aspect * film_half_height,
+            film_half_height,
+            pupil_bounds: vec![],
+            pupil_area: 0.0,
+        };
+        lens.focus(focus_distance)?;
+        lens.pupil_bounds = lens.bound_exit_pupil();
+        lens.pupil_area = lens
+            .pupil_bounds
+            .iter()
+            .filter(|[x0, _, x1, _]| x0 <= x1)
+            .map(|[x0, y0, x1, y1]| (x1 - x0) * (y1 - y0))
+            .fold(0.0, f32::max);
+        Ok(lens)
+    }
+
+    pub fn with_aperture(mut self, aperture: Aperture) -> Self {
+        self.aperture = aperture;
+        self
+    }
+
+    // Moves the lens so an object on the axis at the focus distance is imaged onto the film,
+    // by tracing a ray close to the axis. The distance to the film depends on where the lens
+    // ends up, so the placement is refined a few times.
+    fn focus(&mut self, focus_distance: f32) -> std::io::Result<()> {
+        let mut film_distance = 0.0;
+        for _ in 0..8 {
+            self.place(film_distance);
+            let object = Vector3::new(0.0, 0.0, focus_distance);
+            let height = 0.01 * self.elements[0].aperture_diameter / 2.0;
+            let target = Vector3::new(height, 0.0, self.positions[0]);
+            let (origin, direction) = self
+                .trace(object, (target - object).normalized(), false, false)
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidInput,
+                        "paraxial ray blocked by the lens system",
+                    )
+                })?;
+            let image = origin.z - origin.x / direction.x * direction.z;
+            if !image.is_finite() || image >= self.positions[self.positions.len() - 1] {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "the lens system does not focus at the focus distance",
+                ));
+            }
+            film_distance -= image;
+        }
+        self.place(film_distance);
+        Ok(())
+    }
+
+    // puts the rear surface at film_distance from the film
+    fn place(&mut self, film_distance: f32) {
+        let mut z = film_distance;
+        self.positions = vec![0.0; self.elements.len()];
+        for i in (0..self.elements.len()).rev() {
+            self.positions[i] = z;
+            if i > 0 {
+                z += self.elements[i - 1].thickness;
+            }
+        }
+    }
+
+    // Bounds the rays through the rear surface that make it through the lens, ignoring the
+    // shape of the aperture so the bounds hold for film positions in every direction.
+    fn bound_exit_pupil(&self) -> Vec<[f32; 4]> {
+        let rear_radius = self.elements[self.elements.len() - 1].aperture_diameter / 2.0;
+        let rear_z = self.positions[self.positions.len() - 1];
+        let film_radius = self.film_radius();
+        let cell = 2.0 * rear_radius / PUPIL_SAMPLES as f32;
+        (0..PUPIL_BINS)
+            .map(|bin| {
+                let mut bounds = [f32::MAX, f32::MAX, f32::MIN, f32::MIN];
+                for step in 0..3 {
+                    let x = film_radius * (bin as f32 + step as f32 / 2.0) / PUPIL_BINS as f32;
+                    let film = Vector3::new(x, 0.0, 0.0);
+                    for i in 0..PUPIL_SAMPLES {
+                        for j in 0..PUPIL_SAMPLES {
+                            let px = -rear_radius + (i as f32 + 0.5) * cell;
+                            let py = -rear_radius + (j as f32 + 0.5) * cell;
+                            if px * px + py * py > rear_radius * rear_radius {
+                                continue;
+                            }
+                            let pupil = Vector3::new(px, py, rear_z);
+                            let direction = (pupil - film).normalized();
+                            if self.trace(film, direction, true, false).is_some() {
+                                bounds[0] = bounds[0].min(px - cell);
+                                bounds[1] = bounds[1].min(py - cell);
+                                bounds[2] = bounds[2].max(px + cell);
+                                bounds[3] = bounds[3].max(py + cell);
+                            }
+                        }
+                    }
+                }
+                bounds
+            })
+            .collect()
+    }
+
+    fn film_radius(&self) -> f32 {
+        (self.film_half_width * self.film_half_width
+            + self.film_half_height * self.film_half_height)
+            .sqrt()
+    }
+
+    // Ray leaving the front of the lens for the film position (x, y) in [-1, 1], in
+    // millimetres, with its weight. The lens flips the image, so the film position is
+    // mirrored. None when the ray is blocked inside the lens. The weight is cos^4 of the angle
+    // to the axis times the area the ray was sampled from, relative to the largest area, which
+    // darkens the corners of the image like a real lens while its center keeps the exposure of
+    // the other cameras.
+    pub fn create_ray(
+        &self,
+        x: f32,
+        y: f32,
+        (u, v): (f32, f32),
+    ) -> Option<(Vector3, Vector3, f32)> {
+        let film = Vector3::new(-x * self.film_half_width, -y * self.film_half_height, 0.0);
+        let r = (film.x * film.x + film.y * film.y).sqrt();
+        let bin = ((r / self.film_radius() * PUPIL_BINS as f32) as usize).min(PUPIL_BINS - 1);
+        let [x0, y0, x1, y1] = self.pupil_bounds[bin];
+        if x0 > x1 {
+            return None;
+        }
+        // the bounds are for the x axis, rotate them to the film position
+        let (px, py) = (x0 + (x1 - x0) * u, y0 + (y1 - y0) * v);
+        let (cos, sin) = if r > 0.0 {
+            (film.x / r, film.y / r)
+        } else {
+            (1.0, 0.0)
+        };
+        let pupil = Vector3::new(
+            px * cos - py * sin,
+            px * sin + py * cos,
+            self.positions[self.positions.len() - 1],
+        );
+        let direction = (pupil - film).normalized();
+        let (origin, direction_out) = self.trace(film, direction, true, true)?;
+        let cos_squared = direction.z * direction.z;
+        let weight = cos_squared * cos_squared * (x1 - x0) * (y1 - y0) / self.pupil_area;
+        Some((origin, direction_out, weight))
+    }
+
+    // Traces a ray through the surfaces towards the scene or towards the film, None when it
+    // misses a surface, is blocked by its rim or the stop, or is totally reflected.
+    fn trace(
+        &self,
+        mut origin: Vector3,
+        mut direction: Vector3,
+        towards_scene: bool,
+        with_aperture_shape: bool,
+    ) -> Option<(Vector3, Vector3)> {
+        let count = self.elements.len();
+        for k in 0..count {
+            let i = if towards_scene { count - 1 - k } else { k };
+            let element = &self.elements[i];
+            let z = self.positions[i];
+            let film_side = medium(element.refractive_index);
+            let scene_side = if i == 0 {
+                1.0
+            } else {
+                medium(self.elements[i - 1].refractive_index)
+            };
+
+            if element.curvature_radius == 0.0 {
+                let t = (z - origin.z) / direction.z;
+                if t.is_nan() || t <= 0.0 {
+                    return None;
+                }
+                origin += direction * t;
+                let (x, y) = (origin.x / self.stop_radius, origin.y / self.stop_radius);
+                let passes = if with_aperture_shape {
+                    self.aperture.contains(x, y)
+                } else {
+                    x * x + y * y <= 1.0
+                };
+                if !passes {
+                    return None;
+                }
+                continue;
+            }
+
+            let center = Vector3::new(0.0, 0.0, z - element.curvature_radius);
+            let hit = intersect_surface(origin, direction, center, element.curvature_radius, z)?;
+            let radius = element.aperture_diameter / 2.0;
+            if hit.x * hit.x + hit.y * hit.y > radius * radius {
+                return None;
+            }
+            let mut normal = (hit - center).normalized();
+            if normal.inner_product(direction) > 0.0 {
+                normal = -normal;
+            }
+            let eta = if towards_scene {
+                film_side / scene_side
+            } else {
+                scene_side / film_side
+            };
+            direction = refract(direction, normal, eta)?;
+            origin = hit;
+        }
+        Some((origin, direction))
+    }
+}
+
+fn medium(refractive_index: f32) -> f32 {
+    if refractive_index == 0.0 {
+        1.0
+    } else {
+        refractive_index
+    }
+}
+
+// the intersection with the sphere that lies on the cap around the vertex at z
+fn intersect_surface(
+    origin: Vector3,
+    direction: Vector3,
+    center: Vector3,
+    radius: f32,
+    z: f32,
+) -> Option<Vector3> {
+    let oc = origin - center;
+    let b = oc.inner_product(direction);
+    let c = oc.length_squared() - radius * radius;
+    let discriminant = b * b - c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let root = discriminant.sqrt();
+    [-b - root, -b + root]
+        .iter()
+        .filter(|&&t| t > 0.0)
+        .map(|&t| origin + direction * t)
+        .min_by(|a, b| (a.z - z).abs().total_cmp(&(b.z - z).abs()))
+}
+
+// eta is the ratio of the index of refraction the ray comes from to the one it enters, the
+// normal faces the incoming ray
+fn refract(direction: Vector3, normal: Vector3, eta: f32) -> Option<Vector3> {
+    let cos_i = -normal.inner_product(direction);
+    let k = 1.0 - eta * eta * (1.0 - cos_i * cos_i);
+    if k < 0.0 {
+        None
+    } else {
+        Some(direction * eta + normal * (eta * cos_i - k.sqrt()))
+    }
+}
+
+impl Hash for LensSystem {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.elements.hash(state);
+        for position in &self.positions {
+            position.to_bits().hash(state);
+        }
+        self.aperture.hash(state);
+        self.stop_radius.to_bits().hash(state);
+        self.film_half_width.to_bits().hash(state);
+        self.film_half_height.to_bits().hash(state);
+    }
+}
+
+// 50mm f/2 double Gauss lens (Smith, "Modern Lens Design")
+pub const DOUBLE_GAUSS_50MM: [LensElement; 11] = [
+    LensElement {
+        curvature_radius: 29.475,
+        thickness: 3.76,
+        refractive_index: 1.67,
+        aperture_diameter: 25.2,
+    },
+    LensElement {
+        curvature_radius: 84.83,
+        thickness: 0.12,
+        refractive_index: 1.0,
+        aperture_diameter: 25.2,
+    },
+    LensElement {
+        curvature_radius: 19.275,
+        thickness: 4.025,
+        refractive_index: 1.67,
+        aperture_diameter: 23.0,
+    },
+    LensElement {
+        curvature_radius: 40.77,
+        thickness: 3.275,
+        refractive_index: 1.699,
+        aperture_diameter: 23.0,
+    },
+    LensElement {
+        curvature_radius: 12.75,
+        thickness: 5.705,
+        refractive_index: 1.0,
+        aperture_diameter: 18.0,
+    },
+    LensElement {
+        curvature_radius: 0.0,
+        thickness: 4.5,
+        refractive_index: 1.0,
+        aperture_diameter: 17.1,
+    },
+    LensElement {
+        curvature_radius: -14.495,
+        thickness: 1.18,
+        refractive_index: 1.603,
+        aperture_diameter: 17.0,
+    },
+    LensElement {
+        curvature_radius: 40.77,
+        thickness: 6.065,
+        refractive_index: 1.658,
+        aperture_diameter: 20.0,
+    },
+    LensElement {
+        curvature_radius: -20.385,
+        thickness: 0.19,
+        refractive_index: 1.0,
+        aperture_diameter: 20.0,
+    },
+    LensElement {
+        curvature_radius: 437.065,
+        thickness: 3.22,
+        refractive_index: 1.717,
+        aperture_diameter: 20.0,
+    },
+    LensElement {
+        curvature_radius: -39.73,
+        thickness: 0.0,
+        refractive_index: 1.0,
+        aperture_diameter: 20.0,
+    },
+];
@@ -1,7 +1,35 @@
+pub mod adaptive;
+pub mod animation;
+pub mod aov;
+pub mod bezier;
+pub mod brdf;
+pub mod bvh;
 pub mod camera;
+pub mod checkpoint;
+pub mod curves;
+pub mod denoise;
+pub mod exr;
+pub mod film;
+pub mod hair;
+pub mod lens;
 pub mod material;
+pub mod medium;
+pub mod mesh;
+pub mod motion;
 pub mod objects;
+pub mod photon_cache;
+pub mod photon_map;
+pub mod png;
+pub mod polynomial;
 pub mod ppm;
+pub mod projection_map;
 pub mod ray;
+pub mod render;
+pub mod sampler;
 pub mod scene;
+pub mod sdf;
+pub mod stats;
+pub mod subdivision;
+pub mod texture;
+pub mod tiles;
 pub mod vector3;
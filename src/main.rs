@@ -1,66 +1,158 @@
-extern crate rand;
-extern crate rand_distr;
-extern crate kdtree;
-
-use rand_distr::{Distribution, Uniform};
-use rayon::prelude::*;
-use rust_raytracer::camera::Camera;
-use rust_raytracer::material::{Color, Material};
+use rust_raytracer::adaptive::{heat_map_color, AdaptiveSampling};
+use rust_raytracer::animation::{Curve, Key, Track};
+use rust_raytracer::aov::AovLayout;
+use rust_raytracer::camera::{Camera, Projection};
+use rust_raytracer::checkpoint::{photons_file_name, Checkpoint};
+use rust_raytracer::denoise::Denoiser;
+use rust_raytracer::film::PixelFilter;
+use rust_raytracer::lens::ApertureShape;
+use rust_raytracer::material::{Color, Material, Surface};
+use rust_raytracer::motion::{Motion, Shutter};
+use rust_raytracer::objects::{Light, Object, Shape};
+use rust_raytracer::photon_cache::{PhotonCache, PhotonMapKind, StableHasher};
+use rust_raytracer::photon_map::{Filter, PhotonMap, RadianceEstimate};
+use rust_raytracer::png::PNG;
 use rust_raytracer::ppm::PPM;
-use rust_raytracer::scene::{BounceType, Scene};
-use rust_raytracer::objects::{Object, Shape, Light};
+use rust_raytracer::render::{render, RenderProgress, RenderResult, RenderSettings};
+use rust_raytracer::sampler::SamplerKind;
+use rust_raytracer::scene::{BounceType, PhotonPass, Scene};
+use rust_raytracer::stats::{self, Counter, Phase, Report};
+use rust_raytracer::tiles::TileOrder;
 use rust_raytracer::vector3::Vector3;
-use std::sync::atomic::AtomicU32;
-use std::sync::atomic::Ordering::Relaxed;
-
-use kdtree::KdTree;
+use std::hash::{Hash, Hasher};
+use std::io::ErrorKind;
+use std::time::Duration;
 
 const THREAD_COUNT: usize = 8;
 const SAMPLING_AMOUNT: usize = 100;
-const NUMBER_OF_PHOTONS: usize = 800000;
+const ADAPTIVE_SAMPLING: AdaptiveSampling = AdaptiveSampling {
+    min_samples: 16,
+    max_samples: 4 * SAMPLING_AMOUNT as u32,
+    batch_size: 16,
+    noise_threshold: 0.02,
+    progressive_passes: &[1, 4, 16],
+};
+const SAMPLE_HEAT_MAP: Option<&str> = Some("samples.ppm");
+const PIXEL_FILTER: PixelFilter = PixelFilter::MitchellNetravali {
+    b: 1.0 / 3.0,
+    c: 1.0 / 3.0,
+};
+const FILTER_RADIUS: f32 = 2.0;
+const TILE_SIZE: usize = 32;
+const TILE_ORDER: TileOrder = TileOrder::Spiral;
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(60);
+const CHECKPOINT_FILE: Option<&str> = Some("checkpoint.bin");
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(600);
+// writes image.exr with the render passes, None skips rendering them
+const AOV_LAYOUT: Option<AovLayout> = Some(AovLayout::Layers);
+// writes image_denoised.ppm, requires the render passes
+const DENOISER: Option<Denoiser> = Some(Denoiser {
+    iterations: 5,
+    color_sigma: 4.0,
+    normal_power: 64.0,
+    depth_sigma: 0.05,
+    albedo_sigma: 0.1,
+});
+const STATISTICS_FILE: Option<&str> = Some("statistics.json");
+const PROJECTION: Projection = Projection::Perspective {
+    field_of_view: 90.0,
+    aperture: 0.8,
+    aperture_shape: ApertureShape::Circular,
+    cats_eye: 0.0,
+};
+// in frames, objects with a motion are blurred over the interval
+const SHUTTER: Shutter = Shutter {
+    open: 0.0,
+    close: 0.5,
+};
+// first and last frame of an animation, written to frame_0001.png and on. None renders frame 0
+// as a still to image.ppm.
+const FRAMES: Option<(u32, u32)> = None;
+// frames reuse the photon maps of the frame before them while the lights and geometry are static
+const REUSE_PHOTON_MAPS: bool = true;
+const SAMPLER: SamplerKind = SamplerKind::Sobol;
+const CAMERA_SEED: u32 = 0;
+const PHOTON_SEED: u32 = 1;
+const NUMBER_OF_GLOBAL_PHOTONS: usize = 800000;
+const NUMBER_OF_CAUSTIC_PHOTONS: usize = 400000;
+const PROJECTION_MAP_RESOLUTION: usize = 64;
+const PHOTON_CACHE: &str = "photons.bin";
 
 const SIZE: usize = 1200;
 const ASPECT_RATIO: f32 = 1.66;
 const HEIGHT: usize = 2 * SIZE;
 const WIDTH: usize = (2.0 * ASPECT_RATIO * (SIZE as f32)) as usize;
 
-fn create_scene() -> Scene {
-    let ivory = Material {
+// the scene while the shutter is open, lights and materials keep their values of the time it
+// opens
+fn create_scene(shutter: &Shutter) -> Scene {
+    let time = shutter.open;
+    let ivory = Material::Surface(Surface {
         refractive_index: 0.0,
         diffuse_color: Color::new(0.1, 0.1, 0.15),
         reflect_color: Color::new(0.85, 0.85, 0.85),
         specular_exponent: 50.0,
-    };
-    // let ivory2 = Material {
+        roughness: (0.0, 0.0),
+        hair: None,
+        medium: None,
+    });
+    // let ivory2 = Material::Surface(Surface {
     //     refractive_index: 0.0,
     //     diffuse_color: Color::new(0.1, 0.8, 0.1),
     //     reflect_color: Color::black(),
     //     specular_exponent: 50.0,
-    // };
-    let ivory3 = Material {
+    //     roughness: (0.0, 0.0),
+    //     hair: None,
+    //     medium: None,
+    // });
+    let ivory3_color = Track::new(vec![
+        Key {
+            time: 0.0,
+            value: Color::new(0.3, 0.7, 0.7),
+            curve: Curve::Smooth,
+        },
+        Key {
+            time: 48.0,
+            value: Color::new(0.7, 0.5, 0.3),
+            curve: Curve::Smooth,
+        },
+    ]);
+    let ivory3 = Material::Surface(Surface {
         refractive_index: 0.0,
-        diffuse_color: Color::new(0.3, 0.7, 0.7),
+        diffuse_color: ivory3_color.at(time),
         reflect_color: Color::new(0.2, 0.1, 0.3),
         specular_exponent: 50.0,
-    };
-    let glass = Material {
+        roughness: (0.0, 0.0),
+        hair: None,
+        medium: None,
+    });
+    let glass = Material::Surface(Surface {
         refractive_index: 1.5,
         diffuse_color: Color::new(0.6, 0.7, 0.8),
         reflect_color: Color::black(),
         specular_exponent: 125.0,
-    };
-    // let mirror = Material {
+        roughness: (0.0, 0.0),
+        hair: None,
+        medium: None,
+    });
+    // let mirror = Material::Surface(Surface {
     //     refractive_index: 0.0,
     //     diffuse_color: Color::black(),
     //     reflect_color: Color::new(1.0, 1.0, 1.0) * 0.8,
     //     specular_exponent: 125.0,
-    // };
-    let rubber = Material {
+    //     roughness: (0.0, 0.0),
+    //     hair: None,
+    //     medium: None,
+    // });
+    let rubber = Material::Surface(Surface {
         refractive_index: 0.0,
         diffuse_color: Color::new(0.3, 0.1, 0.1),
         reflect_color: Color::black(),
         specular_exponent: 10000000000.0,
-    };
+        roughness: (0.0, 0.0),
+        hair: None,
+        medium: None,
+    });
 
     let objects: Vec<Object> = vec![
         Object {
@@ -70,19 +162,25 @@ fn create_scene() -> Scene {
                 Vector3::new(3.0, 2.0, -2.3),
                 Vector3::new(1.0, -0.999, -1.0),
             ),
-            material: glass,
+            material: glass.clone(),
+            motion: Motion::Static,
         },
         Object {
             shape: Shape::sphere(Vector3::new(-1.0, 0.0, -2.0), 1.0),
-            material: ivory,
+            material: ivory.clone(),
+            motion: Motion::Linear {
+                velocity: Vector3::new(0.0, 0.0, 0.5),
+            },
         },
         Object {
             shape: Shape::sphere(Vector3::new(3.0, 0.0, 0.0), 1.0),
             material: glass,
+            motion: Motion::Static,
         },
         Object {
             shape: Shape::plane(Vector3::new(0.0, -1.0, 0.0), Vector3::new(0.0, 1.0, 0.0)),
             material: rubber,
+            motion: Motion::Static,
         },
         Object {
             shape: Shape::pyramid(
@@ -92,128 +190,522 @@ fn create_scene() -> Scene {
                 Vector3::new(3.5, -0.999, -15.0),
             ),
             material: ivory,
+            motion: Motion::Static,
         },
         Object {
             shape: Shape::sphere(Vector3::new(-5.0, 1.0, 3.0), 2.0),
             material: ivory3,
+            motion: Motion::Static,
         },
     ];
 
     let lights = vec![
         Light {
-            position: Vector3::new(5.0, 10.0, -4.0),
+            position: Track::new(vec![
+                Key {
+                    time: 0.0,
+                    value: Vector3::new(5.0, 10.0, -4.0),
+                    curve: Curve::Smooth,
+                },
+                Key {
+                    time: 48.0,
+                    value: Vector3::new(2.0, 10.0, -5.0),
+                    curve: Curve::Smooth,
+                },
+            ])
+            .at(time),
             color: Color::new(1.0, 1.0, 1.0),
             intensity: 5000.0,
         },
         Light {
             position: Vector3::new(-4.0, 12.0, 3.0),
             color: Color::new(1.0, 1.0, 1.0),
-            intensity: 5000.0,
+            intensity: Track::new(vec![
+                Key {
+                    time: 0.0,
+                    value: 5000.0,
+                    curve: Curve::Linear,
+                },
+                Key {
+                    time: 48.0,
+                    value: 2000.0,
+                    curve: Curve::Linear,
+                },
+            ])
+            .at(time),
         },
     ];
 
-    Scene::new(objects, lights)
-}
-
-fn create_camera() -> Camera {
-    let origin = Vector3::new(7.0, 2.0, 5.0);
-    let look_at = Vector3::new(3.0, 0.0, 0.0);
-    let vup = Vector3::new(0.0, -1.0, 0.0);
-    let focus_distance = (origin - look_at).length_squared().sqrt();
-    let field_of_view = 90.0;
-    let aperture = 0.8;
-    Camera::new(
-        origin,
-        look_at,
-        vup,
-        field_of_view,
-        ASPECT_RATIO,
-        aperture,
-        focus_distance,
-    )
+    let global_estimate = RadianceEstimate {
+        photon_count: 400,
+        max_distance: 1.0,
+        filter: Filter::Epanechnikov,
+        normal_tolerance: 0.9,
+        disc_flattening: 3.0,
+    };
+    let caustic_estimate = RadianceEstimate {
+        photon_count: 200,
+        max_distance: 0.25,
+        filter: Filter::Cone(1.1),
+        normal_tolerance: 0.9,
+        disc_flattening: 3.0,
+    };
+
+    Scene::new(objects, lights, shutter).with_radiance_estimates(global_estimate, caustic_estimate)
 }
 
-fn main() {
-    rayon::ThreadPoolBuilder::new()
-        .num_threads(THREAD_COUNT)
-        .build_global()
-        .unwrap();
+// position and target of the camera at a time in frames
+fn camera_placement(time: f32) -> (Vector3, Vector3) {
+    let origin = Track::new(vec![
+        Key {
+            time: 0.0,
+            value: Vector3::new(7.0, 2.0, 5.0),
+            curve: Curve::CatmullRom,
+        },
+        Key {
+            time: 24.0,
+            value: Vector3::new(4.0, 2.5, 7.0),
+            curve: Curve::CatmullRom,
+        },
+        Key {
+            time: 48.0,
+            value: Vector3::new(0.0, 3.0, 8.0),
+            curve: Curve::CatmullRom,
+        },
+    ]);
+    let look_at = Track::constant(Vector3::new(3.0, 0.0, 0.0));
+    (origin.at(time), look_at.at(time))
+}
+
+// the projection at a time in frames, the perspective projection zooms in and stops down from
+// the field of view and aperture it is given
+fn projection_at(time: f32) -> Projection {
+    match PROJECTION {
+        Projection::Perspective {
+            field_of_view,
+            aperture,
+            aperture_shape,
+            cats_eye,
+        } => {
+            let field_of_view = Track::new(vec![
+                Key {
+                    time: 0.0,
+                    value: field_of_view,
+                    curve: Curve::Smooth,
+                },
+                Key {
+                    time: 48.0,
+                    value: field_of_view * 0.75,
+                    curve: Curve::Smooth,
+                },
+            ]);
+            let aperture = Track::new(vec![
+                Key {
+                    time: 24.0,
+                    value: aperture,
+                    curve: Curve::Linear,
+                },
+                Key {
+                    time: 48.0,
+                    value: aperture / 4.0,
+                    curve: Curve::Linear,
+                },
+            ]);
+            Projection::Perspective {
+                field_of_view: field_of_view.at(time),
+                aperture: aperture.at(time),
+                aperture_shape,
+                cats_eye,
+            }
+        }
+        projection => projection,
+    }
+}
+
+// the camera follows its placement and projection while the shutter is open
+fn create_camera(shutter: Shutter) -> Camera {
+    let camera_at = |(origin, look_at): (Vector3, Vector3), projection: Projection| {
+        let vup = Vector3::new(0.0, -1.0, 0.0);
+        let focus_distance = (origin - look_at).length_squared().sqrt();
+        projection
+            .create(origin, look_at, vup, ASPECT_RATIO, focus_distance)
+            .expect("Creating the camera failed!")
+    };
+    let (open_origin, open_look_at) = camera_placement(shutter.open);
+    let (close_origin, close_look_at) = camera_placement(shutter.close);
+    let (open_projection, close_projection) =
+        (projection_at(shutter.open), projection_at(shutter.close));
+    let camera = camera_at((open_origin, open_look_at), open_projection).with_shutter(shutter);
+    if open_origin.to_array() == close_origin.to_array()
+        && open_look_at.to_array() == close_look_at.to_array()
+        && open_projection == close_projection
+    {
+        camera
+    } else {
+        camera.with_motion(camera_at((close_origin, close_look_at), close_projection))
+    }
+}
+
+fn trace_photon_maps(scene: &Scene, shutter: &Shutter) -> (PhotonMap, PhotonMap) {
+    let mut photon_map_global = PhotonMap::new();
+    let mut photon_map_caustic = PhotonMap::new();
+
+    // photons use their own sampler, the global and caustic pass are told apart by their pixel
+    let mut sampler = SAMPLER.create(1, PHOTON_SEED);
+    stats::phase(Phase::PhotonTracing, || {
+        for i in 0..NUMBER_OF_GLOBAL_PHOTONS {
+            sampler.start_sample((0, 0), i as u32);
+            let (ray, color) =
+                scene.random_photon_ray(sampler.as_mut(), shutter, NUMBER_OF_GLOBAL_PHOTONS);
+            scene.trace_photon(
+                &mut photon_map_global,
+                PhotonPass::Global,
+                sampler.as_mut(),
+                &ray,
+                color,
+                0,
+                BounceType::NONE,
+                false,
+            );
+        }
+
+        let projection_maps = scene.caustic_projection_maps(PROJECTION_MAP_RESOLUTION, shutter);
+        for i in 0..NUMBER_OF_CAUSTIC_PHOTONS {
+            sampler.start_sample((1, 0), i as u32);
+            if let Some((ray, color)) = scene.random_caustic_photon_ray(
+                sampler.as_mut(),
+                &projection_maps,
+                shutter,
+                NUMBER_OF_CAUSTIC_PHOTONS,
+            ) {
+                scene.trace_photon(
+                    &mut photon_map_caustic,
+                    PhotonPass::Caustic,
+                    sampler.as_mut(),
+                    &ray,
+                    color,
+                    0,
+                    BounceType::NONE,
+                    false,
+                );
+            }
+        }
+    });
+
+    stats::phase(Phase::PhotonBalancing, || {
+        photon_map_global.balance();
+        photon_map_caustic.balance();
+    });
+
+    (photon_map_global, photon_map_caustic)
+}
 
-    let camera = create_camera();
-    let scene = create_scene();
+// the photons of a scene without motion do not depend on the time, so frames can share them
+fn photon_cache_key(scene: &Scene, shutter: &Shutter) -> u64 {
+    let mut hasher = StableHasher::default();
+    scene.hash(&mut hasher);
+    NUMBER_OF_GLOBAL_PHOTONS.hash(&mut hasher);
+    NUMBER_OF_CAUSTIC_PHOTONS.hash(&mut hasher);
+    PROJECTION_MAP_RESOLUTION.hash(&mut hasher);
+    SAMPLER.hash(&mut hasher);
+    PHOTON_SEED.hash(&mut hasher);
+    if scene.has_motion() {
+        shutter.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+fn create_photon_maps(scene: &Scene, shutter: &Shutter, key: u64) -> (PhotonMap, PhotonMap) {
+    let cached = stats::phase(Phase::PhotonCache, || PhotonCache::read_file(PHOTON_CACHE));
+    if let Ok(mut cache) = cached {
+        if cache.key == key {
+            if let (Some(global), Some(caustic)) = (
+                cache.take(PhotonMapKind::Global),
+                cache.take(PhotonMapKind::Caustic),
+            ) {
+                println!("Reusing photon maps from {}", PHOTON_CACHE);
+                return (global, caustic);
+            }
+        }
+    }
 
     println!("Calculating Photon map...");
-    let mut photon_map_global = KdTree::new(3);
-    let mut photon_map_caustic = KdTree::new(3);
-
-    for _ in 0..NUMBER_OF_PHOTONS {
-        let (ray, color) = scene.random_photon_ray(NUMBER_OF_PHOTONS);
-        scene.trace_photon(
-            &mut photon_map_global,
-            &mut photon_map_caustic,
-            &ray,
-            color,
-            0,
-            BounceType::NONE,
-        );
+    let (photon_map_global, photon_map_caustic) = trace_photon_maps(scene, shutter);
+
+    let mut cache = PhotonCache::new(key);
+    cache.maps.push((PhotonMapKind::Global, photon_map_global));
+    cache
+        .maps
+        .push((PhotonMapKind::Caustic, photon_map_caustic));
+    if let Err(e) = stats::phase(Phase::PhotonCache, || cache.write_file(PHOTON_CACHE)) {
+        println!("Could not write photon maps to {}: {}", PHOTON_CACHE, e);
     }
+    (
+        cache.take(PhotonMapKind::Global).unwrap(),
+        cache.take(PhotonMapKind::Caustic).unwrap(),
+    )
+}
+
+fn read_checkpoint(
+    settings: &RenderSettings,
+    key: u64,
+) -> Option<(PhotonMap, PhotonMap, RenderProgress)> {
+    let file_name = settings.checkpoint_file.as_ref()?;
+    let mut checkpoint = match Checkpoint::read_file(file_name, key, settings) {
+        Ok(checkpoint) => checkpoint,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => {
+            println!("Ignoring checkpoint {}: {}", file_name, e);
+            return None;
+        }
+    };
+    let global = checkpoint.photons.take(PhotonMapKind::Global)?;
+    let caustic = checkpoint.photons.take(PhotonMapKind::Caustic)?;
+    println!("Resuming render from {}", file_name);
+    Some((global, caustic, checkpoint.progress))
+}
 
-    let mut ppm = PPM::new(&String::from("image.ppm"), WIDTH, HEIGHT);
-
-    let counter = AtomicU32::new(0);
-    let ys = (0..HEIGHT).collect::<Vec<usize>>();
-    let result = ys
-        .par_iter()
-        .map(|y| {
-            let between = Uniform::new(-0.5, 0.5);
-            let mut rng = rand::thread_rng();
-            let xs = (0..WIDTH).collect::<Vec<usize>>();
-            let colors = xs
-                .into_iter()
-                .map(|x| {
-                    let samples = (0..SAMPLING_AMOUNT).collect::<Vec<usize>>();
-                    samples
-                        .into_iter()
-                        .map(|_| {
-                            let ra = if SAMPLING_AMOUNT == 1 {
-                                0.0
-                            } else {
-                                between.sample(&mut rng)
-                            };
-                            let rb = if SAMPLING_AMOUNT == 1 {
-                                0.0
-                            } else {
-                                between.sample(&mut rng)
-                            };
-                            let a = (x as f32 + ra) / (WIDTH as f32);
-                            let b = (*y as f32 + rb) / (HEIGHT as f32);
-                            let ray = camera.create_ray(true, a, b);
-                            scene.trace_ray(
-                                &photon_map_global,
-                                &photon_map_caustic,
-                                &ray,
-                                0,
-                            )
-                        })
-                        .reduce(|a, b| a + b)
-                        .unwrap()
-                        * (1.0 / (SAMPLING_AMOUNT as f32))
-                })
+// What a frame is rendered from, the checkpoint key covers all of it
+struct FrameSetup {
+    shutter: Shutter,
+    camera: Camera,
+    scene: Scene,
+    photon_key: u64,
+    checkpoint_key: u64,
+}
+
+fn setup_frame(settings: &RenderSettings, frame: u32) -> FrameSetup {
+    let shutter = Shutter {
+        open: frame as f32 + SHUTTER.open,
+        close: frame as f32 + SHUTTER.close,
+    };
+    let camera = create_camera(shutter);
+    let scene = create_scene(&shutter);
+
+    let photon_key = photon_cache_key(&scene, &shutter);
+    let mut hasher = StableHasher::default();
+    photon_key.hash(&mut hasher);
+    camera.hash(&mut hasher);
+    settings.hash(&mut hasher);
+    let checkpoint_key = hasher.finish();
+
+    FrameSetup {
+        shutter,
+        camera,
+        scene,
+        photon_key,
+        checkpoint_key,
+    }
+}
+
+// Renders the frame, starting from a checkpoint of it when there is one. The photon maps of
+// the previous frame are passed in and replaced by the ones of this frame.
+fn render_frame(
+    settings: &RenderSettings,
+    setup: &FrameSetup,
+    photon_maps: &mut Option<(u64, PhotonMap, PhotonMap)>,
+) -> (RenderResult, Option<Vec<Color>>) {
+    let &FrameSetup {
+        shutter,
+        ref camera,
+        ref scene,
+        photon_key,
+        checkpoint_key,
+    } = setup;
+
+    let (photon_map_global, photon_map_caustic, progress) =
+        match read_checkpoint(settings, checkpoint_key) {
+            Some(resumed) => resumed,
+            None => {
+                let (global, caustic) = match photon_maps.take() {
+                    Some((key, global, caustic)) if REUSE_PHOTON_MAPS && key == photon_key => {
+                        println!("Reusing photon maps of the previous frame");
+                        (global, caustic)
+                    }
+                    _ => create_photon_maps(scene, &shutter, photon_key),
+                };
+                (global, caustic, RenderProgress::new(settings))
+            }
+        };
+
+    stats::add(Counter::GlobalPhotons, photon_map_global.len() as u64);
+    stats::add(Counter::CausticPhotons, photon_map_caustic.len() as u64);
+
+    let result = stats::phase(Phase::Rendering, || {
+        render(
+            settings,
+            camera,
+            scene,
+            &photon_map_global,
+            &photon_map_caustic,
+            progress,
+            checkpoint_key,
+        )
+    });
+    *photon_maps = Some((photon_key, photon_map_global, photon_map_caustic));
+
+    let denoised = DENOISER.and_then(|denoiser| match &result.aovs {
+        Some(aovs) => Some(stats::phase(Phase::Denoising, || {
+            let colors = (0..HEIGHT)
+                .flat_map(|y| (0..WIDTH).map(move |x| (x, y)))
+                .map(|(x, y)| result.film.color(x, y, 0.0))
                 .collect::<Vec<_>>();
+            denoiser.denoise(WIDTH, HEIGHT, &colors, &result.statistics, &aovs.surfaces)
+        })),
+        None => {
+            println!("The denoiser needs the render passes, set AOV_LAYOUT");
+            None
+        }
+    });
+    (result, denoised)
+}
 
-            let c = counter.load(Relaxed) + 1;
-            counter.store(c, Relaxed);
-            println!("{:.2}%", 100.0 * (c as f32) / (HEIGHT as f32));
+// the frame is written, a later run should start over instead of resuming
+fn remove_checkpoint() {
+    if let Some(file_name) = CHECKPOINT_FILE {
+        let _ = std::fs::remove_file(file_name);
+        let _ = std::fs::remove_file(photons_file_name(file_name));
+    }
+}
 
-            (y, colors)
-        })
-        .collect::<Vec<_>>();
+fn write_still(result: &RenderResult, denoised: &Option<Vec<Color>>) {
+    let RenderResult {
+        film,
+        statistics,
+        aovs,
+    } = result;
+    film.write_ppm("image.ppm", 0.0)
+        .expect("Writing to ppm file failed!");
+
+    if let (Some(aovs), Some(layout)) = (aovs, AOV_LAYOUT) {
+        aovs.write_exr(film, "image", layout)
+            .expect("Writing to exr file failed!");
+    }
 
-    for (y, colors) in result {
-        for (x, color) in colors.iter().enumerate() {
-            ppm.add_pixel(x, *y, *color)
+    if let Some(denoised) = denoised {
+        let mut ppm = PPM::new(&String::from("image_denoised.ppm"), WIDTH, HEIGHT);
+        for (i, color) in denoised.iter().enumerate() {
+            ppm.add_pixel(i % WIDTH, i / WIDTH, *color);
         }
+        ppm.write_file().expect("Writing to ppm file failed!");
     }
 
-    ppm.write_file().expect("Writing to ppm file failed!");
+    if let Some(file_name) = SAMPLE_HEAT_MAP {
+        let mut heat_map = PPM::new(&String::from(file_name), WIDTH, HEIGHT);
+        for (i, pixel) in statistics.iter().enumerate() {
+            heat_map.add_pixel(
+                i % WIDTH,
+                i / WIDTH,
+                heat_map_color(pixel.count(), ADAPTIVE_SAMPLING.max_samples),
+            );
+        }
+        heat_map.write_file().expect("Writing to ppm file failed!");
+    }
+}
+
+fn frame_name(frame: u32) -> String {
+    format!("frame_{:04}", frame)
+}
+
+fn frame_key_file_name(frame: u32) -> String {
+    format!("{}.key", frame_name(frame))
+}
+
+// a frame of an earlier run is kept when it was rendered with the same checkpoint key, so an
+// interrupted sequence continues while changes to the scene or settings render it again
+fn frame_done(frame: u32, key: u64) -> bool {
+    std::path::Path::new(&format!("{}.png", frame_name(frame))).exists()
+        && std::fs::read_to_string(frame_key_file_name(frame))
+            .is_ok_and(|text| text.trim() == key.to_string())
+}
+
+fn write_frame(frame: u32, key: u64, result: &RenderResult, denoised: &Option<Vec<Color>>) {
+    let name = frame_name(frame);
+    let film = &result.film;
+    if let (Some(aovs), Some(layout)) = (&result.aovs, AOV_LAYOUT) {
+        aovs.write_exr(film, &name, layout)
+            .expect("Writing to exr file failed!");
+    }
+
+    if let Some(denoised) = denoised {
+        let mut png = PNG::new(&format!("{}_denoised.png", name), WIDTH, HEIGHT);
+        for (i, color) in denoised.iter().enumerate() {
+            png.add_pixel(i % WIDTH, i / WIDTH, *color);
+        }
+        png.write_file().expect("Writing to png file failed!");
+    }
+
+    let mut png = PNG::new(&format!("{}.png", name), WIDTH, HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            png.add_pixel(x, y, film.color(x, y, 0.0));
+        }
+    }
+    png.write_file().expect("Writing to png file failed!");
+
+    // written last, it marks the frame as done
+    std::fs::write(frame_key_file_name(frame), key.to_string())
+        .expect("Writing the frame key failed!");
+}
+
+fn main() {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(THREAD_COUNT)
+        .build_global()
+        .unwrap();
+
+    let settings = RenderSettings {
+        width: WIDTH,
+        height: HEIGHT,
+        samples_per_pixel: SAMPLING_AMOUNT,
+        adaptive_sampling: ADAPTIVE_SAMPLING,
+        sampler: SAMPLER,
+        seed: CAMERA_SEED,
+        pixel_filter: PIXEL_FILTER,
+        filter_radius: FILTER_RADIUS,
+        tile_size: TILE_SIZE,
+        tile_order: TILE_ORDER,
+        aovs: AOV_LAYOUT.is_some(),
+        preview_file: Some(String::from(if FRAMES.is_some() {
+            "preview.ppm"
+        } else {
+            "image.ppm"
+        })),
+        preview_interval: PREVIEW_INTERVAL,
+        checkpoint_file: CHECKPOINT_FILE.map(String::from),
+        checkpoint_interval: CHECKPOINT_INTERVAL,
+    };
+
+    let mut photon_maps = None;
+    match FRAMES {
+        None => {
+            let setup = setup_frame(&settings, 0);
+            let (result, denoised) = render_frame(&settings, &setup, &mut photon_maps);
+            stats::phase(Phase::Output, || write_still(&result, &denoised));
+            remove_checkpoint();
+        }
+        Some((first, last)) => {
+            for frame in first..=last {
+                let setup = setup_frame(&settings, frame);
+                if frame_done(frame, setup.checkpoint_key) {
+                    println!("Skipping frame {}, it was rendered before", frame);
+                    continue;
+                }
+                println!("Rendering frame {}", frame);
+                let (result, denoised) = render_frame(&settings, &setup, &mut photon_maps);
+                stats::phase(Phase::Output, || {
+                    write_frame(frame, setup.checkpoint_key, &result, &denoised)
+                });
+                remove_checkpoint();
+            }
+        }
+    }
+
+    let report = Report::new();
+    report.print();
+    if let Some(file_name) = STATISTICS_FILE {
+        if let Err(e) = report.write_json(file_name) {
+            println!("Could not write statistics to {}: {}", file_name, e);
+        }
+    }
 }
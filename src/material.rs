@@ -1,8 +1,14 @@
 extern crate overload;
+use crate::hair::Hair;
+use crate::medium::Medium;
+use crate::texture::ScalarTexture;
+use crate::vector3::Vector3;
 use overload::overload;
+use std::hash::{Hash, Hasher};
 use std::ops; // <- don't forget this or you'll get nasty errors
+use std::sync::Arc;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
 pub struct Color {
     r: f32,
     g: f32,
@@ -46,20 +52,42 @@ impl Color {
         }
     }
 
+    pub fn non_negative(&self) -> Self {
+        Color {
+            r: self.r.max(0.0),
+            g: self.g.max(0.0),
+            b: self.b.max(0.0),
+        }
+    }
+
+    pub fn luminance(&self) -> f32 {
+        0.2126 * self.r + 0.7152 * self.g + 0.0722 * self.b
+    }
+
+    pub fn to_array(&self) -> [f32; 3] {
+        [self.r, self.g, self.b]
+    }
+
     pub fn to_buffer(&self) -> [u8; 3] {
         [self.r_byte(), self.g_byte(), self.b_byte()]
     }
 
     pub fn r_byte(&self) -> u8 {
-        ((self.r * 255.0).min(255.0)).max(0.0) as u8
+        (self.r * 255.0).clamp(0.0, 255.0) as u8
     }
 
     pub fn g_byte(&self) -> u8 {
-        ((self.g * 255.0).min(255.0)).max(0.0) as u8
+        (self.g * 255.0).clamp(0.0, 255.0) as u8
     }
 
     pub fn b_byte(&self) -> u8 {
-        ((self.b * 255.0).min(255.0)).max(0.0) as u8
+        (self.b * 255.0).clamp(0.0, 255.0) as u8
+    }
+}
+
+impl Hash for Color {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_array().map(f32::to_bits).hash(state);
     }
 }
 
@@ -74,10 +102,244 @@ overload!((a: &mut Color) -= (b: ?Color) { a.r -= b.r; a.g -= b.g; a.b -= b.b; }
 overload!((a: &mut Color) *= (b: f32) { a.r *= b; a.g *= b; a.b *= b; });
 overload!((a: &mut Color) /= (b: f32) { a.r /= b; a.g /= b; a.b /= b; });
 
-#[derive(Copy, Clone)]
-pub struct Material {
+// A surface of a single kind, the leaf of every material
+#[derive(Copy, Clone, PartialEq)]
+pub struct Surface {
     pub refractive_index: f32,
     pub diffuse_color: Color,
     pub specular_exponent: f32,
     pub reflect_color: Color,
+    // Roughness of the reflection along the surface tangent and across it, spreading it into
+    // a glossy lobe. At zero it is a mirror, and specular_exponent gives the highlight of the
+    // lights in it.
+    pub roughness: (f32, f32),
+    // shades curves as hair fibers instead, the diffuse color still takes indirect light
+    pub hair: Option<Hair>,
+    // Fills the shape with a medium that scatters the light below the surface instead of the
+    // diffuse and reflect colors. The refractive index, at least 1, gives its boundary.
+    pub medium: Option<Medium>,
+}
+
+// Clear dielectric layer like varnish or lacquer. It reflects by its Fresnel reflectance and
+// tints what it lets through to the surface below.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Coat {
+    pub refractive_index: f32,
+    // at zero the coat is a mirror, which reflects the lights only in its reflection rays
+    pub roughness: f32,
+    pub color: Color,
+}
+
+// Soft reflection towards grazing angles from fibers standing on the surface, like on velvet
+// or dust. It only reflects direct light.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Sheen {
+    pub color: Color,
+    // within (0, 1], how far the fibers lean away from the normal
+    pub roughness: f32,
+}
+
+// Flattens the diffuse part like light that scatters a little under the surface before it
+// leaves, after Burley's approximation of Hanrahan and Krueger. Only direct light is shaded
+// this way. It is the cheap stand-in for a Medium, surfaces with a medium walk through it
+// instead and ignore the flattening.
+#[derive(Copy, Clone, PartialEq)]
+pub struct Subsurface {
+    // within [0, 1], from lambertian to fully flattened
+    pub amount: f32,
+    pub roughness: f32,
+}
+
+// Materials are graphs of layers over surfaces. Children are shared, so cloning a material
+// is cheap.
+#[derive(Clone, PartialEq)]
+pub enum Material {
+    Surface(Surface),
+    Coat {
+        coat: Coat,
+        base: Arc<Material>,
+    },
+    // the first material where the mask is 0, the second where it is 1, and a random choice
+    // between them in proportion to the mask in between
+    Blend {
+        mask: ScalarTexture,
+        materials: Arc<(Material, Material)>,
+    },
+    Sheen {
+        sheen: Sheen,
+        base: Arc<Material>,
+    },
+    Subsurface {
+        subsurface: Subsurface,
+        base: Arc<Material>,
+    },
+}
+
+// A material resolved at a point of a surface. The layers lie in a fixed order from the
+// outside in: the coat, the sheen and the surface with its subsurface flattening.
+#[derive(Copy, Clone)]
+pub struct Shading {
+    pub surface: Surface,
+    pub coat: Option<Coat>,
+    pub sheen: Option<Sheen>,
+    pub subsurface: Option<Subsurface>,
+}
+
+impl Surface {
+    pub fn is_specular(&self) -> bool {
+        self.refractive_index != 0.0 || self.reflect_color.max() > 0.0
+    }
+
+    pub fn is_glossy(&self) -> bool {
+        self.roughness.0 > 0.0 || self.roughness.1 > 0.0
+    }
+}
+
+impl Material {
+    pub fn coat(base: Material, coat: Coat) -> Self {
+        Material::Coat {
+            coat,
+            base: Arc::new(base),
+        }
+    }
+
+    pub fn blend(mask: ScalarTexture, first: Material, second: Material) -> Self {
+        Material::Blend {
+            mask,
+            materials: Arc::new((first, second)),
+        }
+    }
+
+    pub fn sheen(base: Material, sheen: Sheen) -> Self {
+        Material::Sheen {
+            sheen,
+            base: Arc::new(base),
+        }
+    }
+
+    pub fn subsurface(base: Material, subsurface: Subsurface) -> Self {
+        assert!((0.0..=1.0).contains(&subsurface.amount));
+        Material::Subsurface {
+            subsurface,
+            base: Arc::new(base),
+        }
+    }
+
+    // whether any of its surfaces is filled with a medium
+    pub fn has_medium(&self) -> bool {
+        match self {
+            Material::Surface(surface) => surface.medium.is_some(),
+            Material::Blend { materials, .. } => {
+                materials.0.has_medium() || materials.1.has_medium()
+            }
+            Material::Coat { base, .. }
+            | Material::Sheen { base, .. }
+            | Material::Subsurface { base, .. } => base.has_medium(),
+        }
+    }
+
+    // whether any of its surfaces or coats reflects or refracts specularly
+    pub fn is_specular(&self) -> bool {
+        match self {
+            Material::Surface(surface) => surface.is_specular(),
+            Material::Coat { .. } => true,
+            Material::Blend { materials, .. } => {
+                materials.0.is_specular() || materials.1.is_specular()
+            }
+            Material::Sheen { base, .. } | Material::Subsurface { base, .. } => base.is_specular(),
+        }
+    }
+
+    // The layers at a point with the surface coordinates uv, u within [0, 1) chooses between
+    // blended materials. Coats over coats combine into the outer one with both their colors,
+    // the innermost sheen and subsurface count.
+    pub fn shading(&self, uv: (f32, f32), position: Vector3, u: f32) -> Shading {
+        match self {
+            Material::Surface(surface) => Shading {
+                surface: *surface,
+                coat: None,
+                sheen: None,
+                subsurface: None,
+            },
+            Material::Coat { coat, base } => {
+                let mut shading = base.shading(uv, position, u);
+                let color = shading.coat.map_or(coat.color, |c| c.color * coat.color);
+                shading.coat = Some(Coat { color, ..*coat });
+                shading
+            }
+            Material::Blend { mask, materials } => {
+                let weight = mask.value(uv, position).clamp(0.0, 1.0);
+                // the part of u past the choice chooses again further down
+                if u < weight {
+                    materials.1.shading(uv, position, u / weight)
+                } else {
+                    materials
+                        .0
+                        .shading(uv, position, (u - weight) / (1.0 - weight))
+                }
+            }
+            Material::Sheen { sheen, base } => {
+                let mut shading = base.shading(uv, position, u);
+                shading.sheen = shading.sheen.or(Some(*sheen));
+                shading
+            }
+            Material::Subsurface { subsurface, base } => {
+                let mut shading = base.shading(uv, position, u);
+                shading.subsurface = shading.subsurface.or(Some(*subsurface));
+                shading
+            }
+        }
+    }
+}
+
+impl Hash for Surface {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.refractive_index.to_bits().hash(state);
+        self.diffuse_color.hash(state);
+        self.specular_exponent.to_bits().hash(state);
+        self.reflect_color.hash(state);
+        self.roughness.0.to_bits().hash(state);
+        self.roughness.1.to_bits().hash(state);
+        if let Some(hair) = &self.hair {
+            hair.absorption.hash(state);
+            hair.longitudinal_roughness.to_bits().hash(state);
+            hair.azimuthal_roughness.to_bits().hash(state);
+            hair.scale_angle.to_bits().hash(state);
+            hair.refractive_index.to_bits().hash(state);
+        }
+        if let Some(medium) = &self.medium {
+            medium.mean_free_path.hash(state);
+            medium.albedo.hash(state);
+        }
+    }
+}
+
+impl Hash for Material {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Material::Surface(surface) => surface.hash(state),
+            Material::Coat { coat, base } => {
+                coat.refractive_index.to_bits().hash(state);
+                coat.roughness.to_bits().hash(state);
+                coat.color.hash(state);
+                base.hash(state);
+            }
+            Material::Blend { mask, materials } => {
+                mask.hash(state);
+                materials.0.hash(state);
+                materials.1.hash(state);
+            }
+            Material::Sheen { sheen, base } => {
+                sheen.color.hash(state);
+                sheen.roughness.to_bits().hash(state);
+                base.hash(state);
+            }
+            Material::Subsurface { subsurface, base } => {
+                subsurface.amount.to_bits().hash(state);
+                subsurface.roughness.to_bits().hash(state);
+                base.hash(state);
+            }
+        }
+    }
 }
@@ -0,0 +1,122 @@
+use crate::brdf;
+use crate::material::Color;
+use crate::objects::Object;
+use crate::ray::Ray;
+use crate::sampler::Sampler;
+use crate::vector3::Vector3;
+
+// limit on the scattering events of a walk, the light left after it is taken as absorbed
+const MAX_STEPS: usize = 1024;
+
+// Material that fills a closed shape like wax, skin, marble or milk. Light refracted into it
+// scatters evenly in all directions until it leaves again, usually near where it came in for
+// short mean free paths. It takes the place of the diffuse color of its surface, and with it
+// of any Subsurface flattening, which only approximates this for direct light.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Medium {
+    // average distance between scattering events per color channel, red light goes deeper
+    // into skin than blue light
+    pub mean_free_path: Color,
+    // share of the light that survives a scattering event per color channel
+    pub albedo: Color,
+}
+
+// where a walk leaves the medium, with the normal pointing out and the share of the light that
+// is left
+pub struct Exit {
+    pub point: Vector3,
+    pub normal: Vector3,
+    pub throughput: Color,
+}
+
+impl Medium {
+    pub fn new(mean_free_path: Color, albedo: Color) -> Self {
+        assert!(mean_free_path.to_array().iter().all(|d| *d > 0.0));
+        assert!(albedo.to_array().iter().all(|a| (0.0..=1.0).contains(a)));
+        Self {
+            mean_free_path,
+            albedo,
+        }
+    }
+
+    // Follows light that enters the object at the point along the direction through the
+    // medium, from scattering event to scattering event, until it leaves through the boundary
+    // of the object. At the boundary it reflects back in by the Fresnel reflectance. The
+    // distances follow one random channel for the whole walk, and the throughput is divided by
+    // the average density of the path over all three, which keeps it within 3 where the mean
+    // free paths differ. None when the light stays inside.
+    pub fn walk(
+        &self,
+        refractive_index: f32,
+        object: &Object,
+        point: Vector3,
+        direction: Vector3,
+        time: f32,
+        sampler: &mut dyn Sampler,
+    ) -> Option<Exit> {
+        let extinction = map(self.mean_free_path, |d| 1.0 / d);
+        let scattering = self.albedo * extinction;
+        let (mut point, mut direction) = (point, direction.normalized());
+        let channel = ((sampler.next_1d() * 3.0) as usize).min(2);
+        // the light along the path and its density when following each channel
+        let (mut light, mut density) = (Color::white(), Color::white());
+
+        for _ in 0..MAX_STEPS {
+            let distance = -(1.0 - sampler.next_1d()).ln() / extinction.to_array()[channel];
+            let ray = Ray::new(point, direction, time);
+            match ray.intersect(object) {
+                Some(boundary) if boundary.t <= distance => {
+                    // the chance of getting this far is the light that does
+                    let transmittance = map(extinction, |e| (-e * boundary.t).exp());
+                    light = light * transmittance;
+                    density = density * transmittance;
+                    let normal = if boundary.hit_normal.inner_product(direction) < 0.0 {
+                        boundary.hit_normal * -1.0
+                    } else {
+                        boundary.hit_normal
+                    };
+                    let cos = direction.inner_product(normal);
+                    let sin_squared = refractive_index.powi(2) * (1.0 - cos * cos);
+                    let reflectance = if sin_squared >= 1.0 {
+                        1.0
+                    } else {
+                        brdf::schlick(refractive_index, (1.0 - sin_squared).sqrt())
+                    };
+                    point = boundary.hit_point;
+                    if sampler.next_1d() >= reflectance {
+                        return Some(Exit {
+                            point,
+                            normal,
+                            throughput: light / average(density),
+                        });
+                    }
+                    direction = direction.reflect(normal).normalized();
+                }
+                _ => {
+                    let transmittance = map(extinction, |e| (-e * distance).exp());
+                    light = light * scattering * transmittance;
+                    density = density * extinction * transmittance;
+                    point += direction * distance;
+                    direction = Vector3::in_sphere(sampler.next_2d());
+                }
+            }
+            // only the ratio matters, which long walks would lose to underflow
+            let scale = density.max();
+            if scale <= 0.0 || light.max() <= 0.0 {
+                return None;
+            }
+            light /= scale;
+            density /= scale;
+        }
+        None
+    }
+}
+
+fn map(color: Color, f: impl Fn(f32) -> f32) -> Color {
+    let [r, g, b] = color.to_array();
+    Color::new(f(r), f(g), f(b))
+}
+
+fn average(color: Color) -> f32 {
+    color.sum() / 3.0
+}
@@ -0,0 +1,363 @@
+use crate::bvh::Bvh;
+use crate::motion::Transform;
+use crate::texture::ScalarTexture;
+use crate::vector3::Vector3;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+
+// rounds of splitting edges before displacement, each can make four triangles out of one
+const MAX_TESSELLATION_LEVELS: usize = 8;
+
+// Triangles sharing vertices by index, with a normal at each vertex that is interpolated over
+// the triangles. Triangles wind counter-clockwise seen from outside, like the normals point.
+// The triangles are kept in a bounding volume hierarchy, built when the mesh is created.
+#[derive(Debug)]
+pub struct Mesh {
+    positions: Vec<Vector3>,
+    normals: Vec<Vector3>,
+    // surface coordinates at each vertex, the barycentric coordinates on the triangle when
+    // empty
+    uvs: Vec<(f32, f32)>,
+    triangles: Vec<[usize; 3]>,
+    bvh: Bvh,
+}
+
+// hit on a mesh: t, the interpolated normal and surface coordinates
+pub type MeshHit = (f32, Vector3, (f32, f32));
+
+// Longest edge a mesh is split down to before it is displaced
+#[derive(Debug, Copy, Clone)]
+pub enum EdgeTarget {
+    // in scene units
+    World(f32),
+    // In pixels as seen from the eye, for a camera that is focal_length pixels from its image
+    // plane. Parts of the mesh close to the camera get finer.
+    Screen {
+        eye: Vector3,
+        focal_length: f32,
+        pixels: f32,
+    },
+}
+
+impl EdgeTarget {
+    fn exceeded_by(&self, a: Vector3, b: Vector3) -> bool {
+        let length = (b - a).length_squared().sqrt();
+        match *self {
+            EdgeTarget::World(target) => length > target,
+            EdgeTarget::Screen {
+                eye,
+                focal_length,
+                pixels,
+            } => {
+                let distance = ((a + b) / 2.0 - eye).length_squared().sqrt();
+                length * focal_length > pixels * distance
+            }
+        }
+    }
+}
+
+impl Mesh {
+    pub fn new(
+        positions: Vec<Vector3>,
+        normals: Vec<Vector3>,
+        uvs: Vec<(f32, f32)>,
+        triangles: Vec<[usize; 3]>,
+    ) -> Self {
+        assert_eq!(
+            positions.len(),
+            normals.len(),
+            "a normal is needed at every vertex"
+        );
+        assert!(
+            uvs.is_empty() || uvs.len() == positions.len(),
+            "surface coordinates are needed at every vertex"
+        );
+        let bounds = triangles
+            .iter()
+            .map(|[a, b, c]| {
+                let (a, b, c) = (positions[*a], positions[*b], positions[*c]);
+                (a.min(b).min(c), a.max(b).max(c))
+            })
+            .collect::<Vec<_>>();
+        Self {
+            bvh: Bvh::new(&bounds),
+            positions,
+            normals,
+            uvs,
+            triangles,
+        }
+    }
+
+    // normals at the vertices averaged from the triangles around them, weighted by their area
+    pub fn smooth(positions: Vec<Vector3>, triangles: Vec<[usize; 3]>) -> Self {
+        let groups = (0..positions.len()).collect::<Vec<_>>();
+        let normals = area_weighted_normals(&positions, &triangles, &groups);
+        Self::new(positions, normals, vec![], triangles)
+    }
+
+    // Moves the vertices along their normals by scale times the texture, after splitting the
+    // triangles until their edges are within the target. Vertices at the same position move
+    // together along their average normal, so seams and hard edges stay closed, and the normals
+    // are averaged again from the displaced triangles.
+    pub fn displaced(self, texture: &ScalarTexture, scale: f32, target: EdgeTarget) -> Self {
+        let Mesh {
+            mut positions,
+            mut normals,
+            mut uvs,
+            mut triangles,
+            ..
+        } = self;
+        for _ in 0..MAX_TESSELLATION_LEVELS {
+            match split_long_edges(&mut positions, &mut normals, &mut uvs, &triangles, &target) {
+                Some(split) => triangles = split,
+                None => break,
+            }
+        }
+
+        let mut welded: HashMap<[u32; 3], usize> = HashMap::new();
+        let groups = positions
+            .iter()
+            .map(|p| {
+                let count = welded.len();
+                *welded
+                    .entry([p.x.to_bits(), p.y.to_bits(), p.z.to_bits()])
+                    .or_insert(count)
+            })
+            .collect::<Vec<_>>();
+        let mut offsets = vec![(Vector3::default(), 0.0, 0); welded.len()];
+        for (vertex, group) in groups.iter().enumerate() {
+            // without surface coordinates a texture can only go by the position
+            let uv = uvs.get(vertex).copied().unwrap_or((0.0, 0.0));
+            let offset = &mut offsets[*group];
+            offset.0 += normals[vertex];
+            offset.1 += texture.value(uv, positions[vertex]);
+            offset.2 += 1;
+        }
+        for (vertex, group) in groups.iter().enumerate() {
+            let (normal, height, count) = offsets[*group];
+            if normal.length_squared() > 0.0 {
+                positions[vertex] += normal.normalized() * (scale * height / count as f32);
+            }
+        }
+        let normals = area_weighted_normals(&positions, &triangles, &groups);
+        Self::new(positions, normals, uvs, triangles)
+    }
+
+    // the mesh placed by the transform, which also turns the normals
+    pub fn transformed(self, transform: &Transform) -> Self {
+        let positions = self
+            .positions
+            .iter()
+            .map(|p| transform.apply_point(*p))
+            .collect();
+        let normals = self
+            .normals
+            .iter()
+            .map(|n| transform.apply_vector(*n))
+            .collect();
+        Self::new(positions, normals, self.uvs, self.triangles)
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    // corners with the smallest and largest coordinates, None without triangles
+    pub fn bounds(&self) -> Option<(Vector3, Vector3)> {
+        self.bvh.bounds()
+    }
+
+    // closest hit in front of the origin
+    pub fn intersect(&self, origin: Vector3, direction: Vector3) -> Option<MeshHit> {
+        let closest = self.bvh.closest(origin, direction, |triangle| {
+            self.intersect_triangle(triangle, origin, direction)
+                .map(|(t, u, v)| (t, (triangle, u, v)))
+        });
+        closest.map(|(t, (triangle, u, v))| {
+            let [a, b, c] = self.triangles[triangle];
+            let w = 1.0 - u - v;
+            let normal = self.normals[a] * w + self.normals[b] * u + self.normals[c] * v;
+            // Near silhouettes the interpolated normal can face the other way than the
+            // triangle, which would tell refraction the ray is on the wrong side
+            let (pa, pb, pc) = (self.positions[a], self.positions[b], self.positions[c]);
+            let geometric = (pb - pa).outer_product(pc - pa);
+            let normal = if (normal.inner_product(direction) < 0.0)
+                == (geometric.inner_product(direction) < 0.0)
+            {
+                normal
+            } else {
+                geometric
+            };
+            let uv = if self.uvs.is_empty() {
+                (u, v)
+            } else {
+                let (ua, va) = self.uvs[a];
+                let (ub, vb) = self.uvs[b];
+                let (uc, vc) = self.uvs[c];
+                (ua * w + ub * u + uc * v, va * w + vb * u + vc * v)
+            };
+            (t, normal.normalized(), uv)
+        })
+    }
+
+    // Möller and Trumbore: t and the barycentric coordinates of the hit
+    fn intersect_triangle(
+        &self,
+        triangle: usize,
+        origin: Vector3,
+        direction: Vector3,
+    ) -> Option<(f32, f32, f32)> {
+        let [a, b, c] = self.triangles[triangle];
+        let vertex = self.positions[a];
+        let edge1 = self.positions[b] - vertex;
+        let edge2 = self.positions[c] - vertex;
+        let p = direction.outer_product(edge2);
+        let determinant = edge1.inner_product(p);
+        if determinant == 0.0 {
+            return None;
+        }
+        let inverse = 1.0 / determinant;
+        let offset = origin - vertex;
+        let u = offset.inner_product(p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = offset.outer_product(edge1);
+        let v = direction.inner_product(q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = edge2.inner_product(q) * inverse;
+        if t <= 0.0 {
+            return None;
+        }
+        Some((t, u, v))
+    }
+}
+
+// Normals summed over the triangles around every group of vertices, so vertices of a group
+// share theirs
+fn area_weighted_normals(
+    positions: &[Vector3],
+    triangles: &[[usize; 3]],
+    groups: &[usize],
+) -> Vec<Vector3> {
+    let mut sums = vec![Vector3::default(); groups.len()];
+    for [a, b, c] in triangles {
+        let (a, b, c) = (*a, *b, *c);
+        let normal = (positions[b] - positions[a]).outer_product(positions[c] - positions[a]);
+        for vertex in [a, b, c].iter() {
+            sums[groups[*vertex]] += normal;
+        }
+    }
+    groups
+        .iter()
+        .map(|group| {
+            let n = sums[*group];
+            if n.length_squared() > 0.0 {
+                n.normalized()
+            } else {
+                n
+            }
+        })
+        .collect()
+}
+
+// One round of splitting every edge longer than the target at its middle, None when no edge
+// is. Triangles are split into two, three or four by how many of their edges are, and edges
+// between two triangles get one new vertex for both so no cracks open.
+fn split_long_edges(
+    positions: &mut Vec<Vector3>,
+    normals: &mut Vec<Vector3>,
+    uvs: &mut Vec<(f32, f32)>,
+    triangles: &[[usize; 3]],
+    target: &EdgeTarget,
+) -> Option<Vec<[usize; 3]>> {
+    let mut middles: HashMap<(usize, usize), Option<usize>> = HashMap::new();
+    let mut any = false;
+    for triangle in triangles {
+        for i in 0..3 {
+            let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+            let key = (a.min(b), a.max(b));
+            if middles.contains_key(&key) {
+                continue;
+            }
+            let middle = if target.exceeded_by(positions[a], positions[b]) {
+                positions.push((positions[a] + positions[b]) / 2.0);
+                let normal = normals[a] + normals[b];
+                normals.push(if normal.length_squared() > 0.0 {
+                    normal.normalized()
+                } else {
+                    normals[a]
+                });
+                if !uvs.is_empty() {
+                    let ((ua, va), (ub, vb)) = (uvs[a], uvs[b]);
+                    uvs.push(((ua + ub) / 2.0, (va + vb) / 2.0));
+                }
+                any = true;
+                Some(positions.len() - 1)
+            } else {
+                None
+            };
+            middles.insert(key, middle);
+        }
+    }
+    if !any {
+        return None;
+    }
+
+    let mut split = Vec::with_capacity(triangles.len() * 2);
+    for triangle in triangles {
+        let middle = |i: usize| {
+            let (a, b) = (triangle[i], triangle[(i + 1) % 3]);
+            middles[&(a.min(b), a.max(b))]
+        };
+        let edges = [middle(0), middle(1), middle(2)];
+        match edges.iter().filter(|m| m.is_some()).count() {
+            0 => split.push(*triangle),
+            1 => {
+                // turned so the split edge runs from a to b
+                let i = edges.iter().position(|m| m.is_some()).unwrap_or(0);
+                let (a, b, c) = (triangle[i], triangle[(i + 1) % 3], triangle[(i + 2) % 3]);
+                let ab = edges[i].unwrap_or(a);
+                split.push([a, ab, c]);
+                split.push([ab, b, c]);
+            }
+            2 => {
+                // turned so the edge from c to a is the whole one
+                let i = (edges.iter().position(|m| m.is_none()).unwrap_or(0) + 1) % 3;
+                let (a, b, c) = (triangle[i], triangle[(i + 1) % 3], triangle[(i + 2) % 3]);
+                let (ab, bc) = (edges[i].unwrap_or(a), edges[(i + 1) % 3].unwrap_or(b));
+                split.push([ab, b, bc]);
+                split.push([a, ab, bc]);
+                split.push([a, bc, c]);
+            }
+            _ => {
+                let [a, b, c] = *triangle;
+                let (ab, bc, ca) = (
+                    edges[0].unwrap_or(a),
+                    edges[1].unwrap_or(b),
+                    edges[2].unwrap_or(c),
+                );
+                split.push([a, ab, ca]);
+                split.push([b, bc, ab]);
+                split.push([c, ca, bc]);
+                split.push([ab, bc, ca]);
+            }
+        }
+    }
+    Some(split)
+}
+
+// the hierarchy follows from the triangles
+impl Hash for Mesh {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.positions.hash(state);
+        self.normals.hash(state);
+        for (u, v) in &self.uvs {
+            u.to_bits().hash(state);
+            v.to_bits().hash(state);
+        }
+        self.triangles.hash(state);
+    }
+}
@@ -0,0 +1,235 @@
+use crate::animation::{Interpolate, Track};
+use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
+
+// steps between keyframes over which the bounds of a moving object are taken
+const BOUND_STEPS: usize = 16;
+
+// Interval of time during which the camera is open, time is measured in frames. Rays get a
+// time uniformly distributed over the interval, open and close being equal turns motion blur
+// off.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Shutter {
+    pub open: f32,
+    pub close: f32,
+}
+
+impl Shutter {
+    pub fn time(&self, u: f32) -> f32 {
+        self.open + (self.close - self.open) * u
+    }
+
+    // position of time within the interval, 0 at open and 1 at close
+    pub fn fraction(&self, time: f32) -> f32 {
+        if self.close > self.open {
+            (time - self.open) / (self.close - self.open)
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Hash for Shutter {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.open.to_bits().hash(state);
+        self.close.to_bits().hash(state);
+    }
+}
+
+// Places a shape given around its own origin: scaled, rotated about the x, y and z axes in that
+// order, by angles in degrees, and then translated.
+#[derive(Debug, Copy, Clone)]
+pub struct Transform {
+    pub translation: Vector3,
+    pub rotation: Vector3,
+    pub scale: f32,
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Self {
+            translation: Vector3::default(),
+            rotation: Vector3::default(),
+            scale: 1.0,
+        }
+    }
+
+    pub fn translation(translation: Vector3) -> Self {
+        Self {
+            translation,
+            ..Self::identity()
+        }
+    }
+
+    pub fn apply_point(&self, point: Vector3) -> Vector3 {
+        self.apply_vector(point * self.scale) + self.translation
+    }
+
+    // rotates a direction or normal, the scale is uniform so it does not change them
+    pub fn apply_vector(&self, vector: Vector3) -> Vector3 {
+        let [x, y, z] = self.rotation.to_array().map(f32::to_radians);
+        let vector = rotate(vector, 1, 2, x);
+        let vector = rotate(vector, 2, 0, y);
+        rotate(vector, 0, 1, z)
+    }
+
+    pub fn inverse_point(&self, point: Vector3) -> Vector3 {
+        self.inverse_vector(point - self.translation) / self.scale
+    }
+
+    pub fn inverse_vector(&self, vector: Vector3) -> Vector3 {
+        let [x, y, z] = self.rotation.to_array().map(f32::to_radians);
+        let vector = rotate(vector, 0, 1, -z);
+        let vector = rotate(vector, 2, 0, -y);
+        rotate(vector, 1, 2, -x)
+    }
+}
+
+// rotation in the plane of the coordinates a and b, from a towards b
+fn rotate(vector: Vector3, a: usize, b: usize, angle: f32) -> Vector3 {
+    if angle == 0.0 {
+        return vector;
+    }
+    let (sin, cos) = angle.sin_cos();
+    let (va, vb) = (vector.coord(a), vector.coord(b));
+    vector
+        .replace_coord(a, va * cos - vb * sin)
+        .replace_coord(b, va * sin + vb * cos)
+}
+
+impl Interpolate for Transform {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            translation: self.translation.lerp(&other.translation, t),
+            rotation: self.rotation.lerp(&other.rotation, t),
+            scale: self.scale.lerp(&other.scale, t),
+        }
+    }
+}
+
+impl Hash for Transform {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.translation.hash(state);
+        self.rotation.hash(state);
+        self.scale.to_bits().hash(state);
+    }
+}
+
+// How an object moves, time in frames
+#[derive(Debug, Clone, Hash)]
+pub enum Motion {
+    Static,
+    // units per frame, the object is where its shape is at time 0
+    Linear { velocity: Vector3 },
+    Keyframes(Track<Transform>),
+}
+
+impl Motion {
+    // None when the object does not move
+    pub fn transform(&self, time: f32) -> Option<Transform> {
+        match self {
+            Motion::Static => None,
+            Motion::Linear { velocity } => Some(Transform::translation(velocity * time)),
+            Motion::Keyframes(track) => Some(track.at(time)),
+        }
+    }
+
+    // Box around a sphere given around the object's origin, wherever the motion takes it while
+    // the shutter is open, None when the object does not move. The interval is split at
+    // keyframes and in steps, and each step is bounded as a whole rather than at its ends.
+    pub fn bounds_during(
+        &self,
+        shutter: &Shutter,
+        center: Vector3,
+        radius: f32,
+    ) -> Option<(Vector3, Vector3)> {
+        let mut times = vec![shutter.open, shutter.close];
+        if let Motion::Keyframes(track) = self {
+            times.extend(
+                track
+                    .times()
+                    .filter(|t| *t > shutter.open && *t < shutter.close),
+            );
+        }
+        times.sort_by(f32::total_cmp);
+        let mut bounds = vec![];
+        for pair in times.windows(2) {
+            for step in 0..BOUND_STEPS {
+                let at = |i: usize| {
+                    // values may jump at a key, the next interval starts with those, so the
+                    // interval ends just before its last key
+                    if step + 1 == BOUND_STEPS && i == 3 {
+                        return self.transform(pair[1].next_down());
+                    }
+                    let u = (step as f32 + i as f32 / 3.0) / BOUND_STEPS as f32;
+                    self.transform(pair[0] + (pair[1] - pair[0]) * u)
+                };
+                let samples = [at(0)?, at(1)?, at(2)?, at(3)?];
+                bounds.push(step_bounds(samples, center, radius));
+            }
+        }
+        bounds.push(step_bounds(
+            [self.transform(shutter.close)?; 4],
+            center,
+            radius,
+        ));
+        bounds
+            .into_iter()
+            .reduce(|(min1, max1), (min2, max2)| (min1.min(min2), max1.max(max2)))
+    }
+}
+
+// Between two keys the translation, angles and scale follow cubics or lower, so the transforms
+// at a third and two thirds of a step give the Bezier control points of the step, and its
+// values stay within their hull. The sphere is bounded around the middle of the hull, moved by
+// at most the angle times its distance to the origin for each rotation.
+fn step_bounds(samples: [Transform; 4], center: Vector3, radius: f32) -> (Vector3, Vector3) {
+    let combine = |weights: [f32; 4]| Transform {
+        translation: (0..4).fold(Vector3::default(), |sum, i| {
+            sum + samples[i].translation * (weights[i] / 6.0)
+        }),
+        rotation: (0..4).fold(Vector3::default(), |sum, i| {
+            sum + samples[i].rotation * (weights[i] / 6.0)
+        }),
+        scale: (0..4).fold(0.0, |sum, i| sum + samples[i].scale * weights[i] / 6.0),
+    };
+    let controls = [
+        samples[0],
+        combine([-5.0, 18.0, -9.0, 2.0]),
+        combine([2.0, -9.0, 18.0, -5.0]),
+        samples[3],
+    ];
+    let hull = |value: fn(&Transform) -> Vector3| {
+        controls[1..].iter().fold(
+            (value(&controls[0]), value(&controls[0])),
+            |(min, max), c| (min.min(value(c)), max.max(value(c))),
+        )
+    };
+    let (translation_min, translation_max) = hull(|t| t.translation);
+    let (rotation_min, rotation_max) = hull(|t| t.rotation);
+    let (scale_min, scale_max) = controls
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), c| {
+            (min.min(c.scale), max.max(c.scale))
+        });
+
+    let middle = Transform {
+        translation: Vector3::default(),
+        rotation: (rotation_min + rotation_max) / 2.0,
+        scale: (scale_min + scale_max) / 2.0,
+    };
+    let angles = (rotation_max - rotation_min)
+        .to_array()
+        .map(f32::to_radians);
+    let distance = center.length_squared().sqrt();
+    let scale = scale_min.abs().max(scale_max.abs());
+    let reach = scale * distance * (angles[0] + angles[1] + angles[2]) / 2.0
+        + (scale_max - scale_min) * distance / 2.0
+        + scale * radius;
+    let extent = Vector3::new(reach, reach, reach);
+    let center = middle.apply_point(center);
+    (
+        translation_min + center - extent,
+        translation_max + center + extent,
+    )
+}
@@ -1,5 +1,10 @@
+use crate::curves::Curves;
 use crate::material::{Color, Material};
+use crate::mesh::Mesh;
+use crate::motion::{Motion, Shutter};
+use crate::sdf::{Sdf, SdfShape};
 use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
 
 pub struct Light {
     pub position: Vector3,
@@ -7,11 +12,14 @@ pub struct Light {
     pub intensity: f32,
 }
 
+#[derive(Hash)]
 pub struct Object {
     pub shape: Shape,
     pub material: Material,
+    pub motion: Motion,
 }
 
+#[derive(Hash)]
 pub struct Plane {
     pub position: Vector3,
     pub normal: Vector3,
@@ -22,12 +30,14 @@ pub struct Sphere {
     pub radius: f32,
 }
 
+#[derive(Hash)]
 pub struct Triangle {
     pub vertex1: Vector3,
     pub vertex2: Vector3,
     pub vertex3: Vector3,
 }
 
+#[derive(Hash)]
 pub struct Pyramid {
     pub vertex1: Vector3,
     pub vertex2: Vector3,
@@ -35,26 +45,171 @@ pub struct Pyramid {
     pub vertex4: Vector3,
 }
 
+// box around center, spanning half_size along each of its orthonormal axes
+pub struct Cuboid {
+    pub center: Vector3,
+    pub axes: [Vector3; 3],
+    pub half_size: Vector3,
+}
+
+// closed by disks at both ends, running from base along the unit axis
+pub struct Cylinder {
+    pub base: Vector3,
+    pub axis: Vector3,
+    pub radius: f32,
+    pub height: f32,
+}
+
+// closed by a disk at its base, the apex lies at height along the unit axis
+pub struct Cone {
+    pub base: Vector3,
+    pub axis: Vector3,
+    pub radius: f32,
+    pub height: f32,
+}
+
+pub struct Disk {
+    pub center: Vector3,
+    pub normal: Vector3,
+    pub radius: f32,
+}
+
+// parallelogram spanned by the edges from corner, a rectangle when they are perpendicular
+#[derive(Hash)]
+pub struct Quad {
+    pub corner: Vector3,
+    pub edge1: Vector3,
+    pub edge2: Vector3,
+}
+
+// the circle of major_radius around the unit axis swept by a circle of minor_radius
+pub struct Torus {
+    pub center: Vector3,
+    pub axis: Vector3,
+    pub major_radius: f32,
+    pub minor_radius: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum CsgOperation {
+    Union,
+    Intersection,
+    // the left shape with the right one cut away
+    Difference,
+}
+
+// Solid combined from two closed shapes, planes count as the half-space behind their normal
+#[derive(Hash)]
+pub struct Csg {
+    pub operation: CsgOperation,
+    pub left: Shape,
+    pub right: Shape,
+}
+
+#[derive(Hash)]
 pub enum Shape {
     Plane(Plane),
     Sphere(Sphere),
     Triangle(Triangle),
     Pyramid(Pyramid),
+    Cuboid(Cuboid),
+    Cylinder(Cylinder),
+    Cone(Cone),
+    Disk(Disk),
+    Quad(Quad),
+    Torus(Torus),
+    Csg(Box<Csg>),
+    Sdf(SdfShape),
+    Mesh(Mesh),
+    Curves(Curves),
+}
+
+impl Hash for Light {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.position.hash(state);
+        self.color.hash(state);
+        self.intensity.to_bits().hash(state);
+    }
+}
+
+impl Hash for Sphere {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.origin.hash(state);
+        self.radius.to_bits().hash(state);
+    }
+}
+
+impl Hash for Cuboid {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.center.hash(state);
+        self.axes.hash(state);
+        self.half_size.hash(state);
+    }
+}
+
+impl Hash for Cylinder {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.base.hash(state);
+        self.axis.hash(state);
+        self.radius.to_bits().hash(state);
+        self.height.to_bits().hash(state);
+    }
+}
+
+impl Hash for Cone {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.base.hash(state);
+        self.axis.hash(state);
+        self.radius.to_bits().hash(state);
+        self.height.to_bits().hash(state);
+    }
+}
+
+impl Hash for Disk {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.center.hash(state);
+        self.normal.hash(state);
+        self.radius.to_bits().hash(state);
+    }
+}
+
+impl Hash for Torus {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.center.hash(state);
+        self.axis.hash(state);
+        self.major_radius.to_bits().hash(state);
+        self.minor_radius.to_bits().hash(state);
+    }
+}
+
+impl Object {
+    // bounds of the shape over all the places it moves through while the shutter is open
+    pub fn bounding_sphere(&self, shutter: &Shutter) -> Option<(Vector3, f32)> {
+        let (center, radius) = self.shape.bounding_sphere()?;
+        Some(match self.motion.bounds_during(shutter, center, radius) {
+            Some((min, max)) => ((min + max) / 2.0, (max - min).length_squared().sqrt() / 2.0),
+            None => (center, radius),
+        })
+    }
+
+    // box around the shape wherever it moves while the shutter is open, None for unbounded
+    // shapes. Moving shapes are bounded through their bounding sphere, which rotations keep.
+    pub fn bounding_box(&self, shutter: &Shutter) -> Option<(Vector3, Vector3)> {
+        if let Motion::Static = self.motion {
+            return self.shape.bounding_box();
+        }
+        let (center, radius) = self.shape.bounding_sphere()?;
+        self.motion.bounds_during(shutter, center, radius)
+    }
 }
 
 impl Shape {
     pub fn plane(position: Vector3, normal: Vector3) -> Shape {
-        Shape::Plane(Plane {
-            position,
-            normal,
-        })
+        Shape::Plane(Plane { position, normal })
     }
 
     pub fn sphere(origin: Vector3, radius: f32) -> Shape {
-        Shape::Sphere(Sphere {
-            origin,
-            radius,
-        })
+        Shape::Sphere(Sphere { origin, radius })
     }
 
     pub fn triangle(vertex1: Vector3, vertex2: Vector3, vertex3: Vector3) -> Shape {
@@ -65,7 +220,12 @@ impl Shape {
         })
     }
 
-    pub fn pyramid(vertex1: Vector3, vertex2: Vector3, vertex3: Vector3, vertex4: Vector3) -> Shape {
+    pub fn pyramid(
+        vertex1: Vector3,
+        vertex2: Vector3,
+        vertex3: Vector3,
+        vertex4: Vector3,
+    ) -> Shape {
         Shape::Pyramid(Pyramid {
             vertex1,
             vertex2,
@@ -73,4 +233,228 @@ impl Shape {
             vertex4,
         })
     }
+
+    pub fn axis_aligned_box(min: Vector3, max: Vector3) -> Shape {
+        Shape::Cuboid(Cuboid {
+            center: (min + max) / 2.0,
+            axes: [
+                Vector3::new(1.0, 0.0, 0.0),
+                Vector3::new(0.0, 1.0, 0.0),
+                Vector3::new(0.0, 0.0, 1.0),
+            ],
+            half_size: (max - min) / 2.0,
+        })
+    }
+
+    // the third axis is perpendicular to the first two, the second is made perpendicular to
+    // the first
+    pub fn oriented_box(
+        center: Vector3,
+        x_axis: Vector3,
+        y_axis: Vector3,
+        half_size: Vector3,
+    ) -> Shape {
+        let x_axis = x_axis.normalized();
+        let z_axis = x_axis.outer_product(y_axis).normalized();
+        let y_axis = z_axis.outer_product(x_axis);
+        Shape::Cuboid(Cuboid {
+            center,
+            axes: [x_axis, y_axis, z_axis],
+            half_size,
+        })
+    }
+
+    pub fn cylinder(base: Vector3, axis: Vector3, radius: f32, height: f32) -> Shape {
+        Shape::Cylinder(Cylinder {
+            base,
+            axis: axis.normalized(),
+            radius,
+            height,
+        })
+    }
+
+    pub fn cone(base: Vector3, axis: Vector3, radius: f32, height: f32) -> Shape {
+        Shape::Cone(Cone {
+            base,
+            axis: axis.normalized(),
+            radius,
+            height,
+        })
+    }
+
+    pub fn disk(center: Vector3, normal: Vector3, radius: f32) -> Shape {
+        Shape::Disk(Disk {
+            center,
+            normal: normal.normalized(),
+            radius,
+        })
+    }
+
+    pub fn quad(corner: Vector3, edge1: Vector3, edge2: Vector3) -> Shape {
+        Shape::Quad(Quad {
+            corner,
+            edge1,
+            edge2,
+        })
+    }
+
+    pub fn torus(center: Vector3, axis: Vector3, major_radius: f32, minor_radius: f32) -> Shape {
+        Shape::Torus(Torus {
+            center,
+            axis: axis.normalized(),
+            major_radius,
+            minor_radius,
+        })
+    }
+
+    pub fn union(left: Shape, right: Shape) -> Shape {
+        Shape::csg(CsgOperation::Union, left, right)
+    }
+
+    pub fn intersection(left: Shape, right: Shape) -> Shape {
+        Shape::csg(CsgOperation::Intersection, left, right)
+    }
+
+    pub fn difference(left: Shape, right: Shape) -> Shape {
+        Shape::csg(CsgOperation::Difference, left, right)
+    }
+
+    pub fn mesh(mesh: Mesh) -> Shape {
+        Shape::Mesh(mesh)
+    }
+
+    pub fn curves(curves: Curves) -> Shape {
+        Shape::Curves(curves)
+    }
+
+    pub fn sdf(sdf: Sdf) -> Shape {
+        Shape::Sdf(SdfShape::new(sdf))
+    }
+
+    fn csg(operation: CsgOperation, left: Shape, right: Shape) -> Shape {
+        assert!(
+            left.is_closed() && right.is_closed(),
+            "csg needs shapes that enclose a solid"
+        );
+        Shape::Csg(Box::new(Csg {
+            operation,
+            left,
+            right,
+        }))
+    }
+
+    // whether the shape separates an inside from an outside, which csg relies on, meshes may
+    // have holes
+    pub fn is_closed(&self) -> bool {
+        !matches!(
+            self,
+            Shape::Triangle(_)
+                | Shape::Disk(_)
+                | Shape::Quad(_)
+                | Shape::Mesh(_)
+                | Shape::Curves(_)
+        )
+    }
+
+    // corners with the smallest and largest coordinates, None for unbounded shapes
+    pub fn bounding_box(&self) -> Option<(Vector3, Vector3)> {
+        let points = |points: &[Vector3]| {
+            points
+                .iter()
+                .skip(1)
+                .fold((points[0], points[0]), |(min, max), p| {
+                    (min.min(*p), max.max(*p))
+                })
+        };
+        let union = |(min1, max1): (Vector3, Vector3), (min2, max2): (Vector3, Vector3)| {
+            (min1.min(min2), max1.max(max2))
+        };
+        Some(match self {
+            Shape::Plane(_) => return None,
+            Shape::Sphere(Sphere { origin, radius }) => {
+                let extent = Vector3::new(*radius, *radius, *radius);
+                (origin - extent, origin + extent)
+            }
+            Shape::Triangle(t) => points(&[t.vertex1, t.vertex2, t.vertex3]),
+            Shape::Pyramid(p) => points(&[p.vertex1, p.vertex2, p.vertex3, p.vertex4]),
+            Shape::Cuboid(c) => {
+                let extent = c
+                    .axes
+                    .iter()
+                    .zip(c.half_size.to_array())
+                    .fold(Vector3::default(), |e, (axis, h)| {
+                        e + Vector3::new(axis.x.abs(), axis.y.abs(), axis.z.abs()) * h
+                    });
+                (c.center - extent, c.center + extent)
+            }
+            Shape::Cylinder(c) => union(
+                disk_bounds(c.base, c.axis, c.radius),
+                disk_bounds(c.base + c.axis * c.height, c.axis, c.radius),
+            ),
+            Shape::Cone(c) => union(
+                disk_bounds(c.base, c.axis, c.radius),
+                points(&[c.base + c.axis * c.height]),
+            ),
+            Shape::Disk(d) => disk_bounds(d.center, d.normal, d.radius),
+            Shape::Quad(q) => points(&[
+                q.corner,
+                q.corner + q.edge1,
+                q.corner + q.edge2,
+                q.corner + q.edge1 + q.edge2,
+            ]),
+            Shape::Torus(t) => {
+                let (min, max) = disk_bounds(t.center, t.axis, t.major_radius);
+                let tube = Vector3::new(t.minor_radius, t.minor_radius, t.minor_radius);
+                (min - tube, max + tube)
+            }
+            Shape::Csg(csg) => {
+                let (left, right) = (csg.left.bounding_box(), csg.right.bounding_box());
+                match csg.operation {
+                    CsgOperation::Union => union(left?, right?),
+                    CsgOperation::Intersection => match (left, right) {
+                        (Some((min1, max1)), Some((min2, max2))) => {
+                            (min1.max(min2), max1.min(max2))
+                        }
+                        (bounds, None) | (None, bounds) => bounds?,
+                    },
+                    CsgOperation::Difference => left?,
+                }
+            }
+            Shape::Sdf(sdf) => sdf.bounds(),
+            Shape::Mesh(mesh) => mesh.bounds()?,
+            Shape::Curves(curves) => curves.bounds()?,
+        })
+    }
+
+    // None for unbounded shapes
+    pub fn bounding_sphere(&self) -> Option<(Vector3, f32)> {
+        let vertices = match self {
+            Shape::Plane(_) => return None,
+            Shape::Sphere(Sphere { origin, radius }) => return Some((*origin, *radius)),
+            Shape::Triangle(t) => vec![t.vertex1, t.vertex2, t.vertex3],
+            Shape::Pyramid(p) => vec![p.vertex1, p.vertex2, p.vertex3, p.vertex4],
+            _ => {
+                let (min, max) = self.bounding_box()?;
+                return Some(((min + max) / 2.0, (max - min).length_squared().sqrt() / 2.0));
+            }
+        };
+        let center =
+            vertices.iter().fold(Vector3::default(), |a, b| a + b) / (vertices.len() as f32);
+        let radius = vertices
+            .iter()
+            .map(|v| (v - center).length_squared())
+            .fold(0.0, f32::max)
+            .sqrt();
+        Some((center, radius))
+    }
+}
+
+// bounds of a disk, which reaches radius * sin of the angle between the normal and an axis along it
+fn disk_bounds(center: Vector3, normal: Vector3, radius: f32) -> (Vector3, Vector3) {
+    let extent = Vector3::new(
+        radius * (1.0 - normal.x * normal.x).max(0.0).sqrt(),
+        radius * (1.0 - normal.y * normal.y).max(0.0).sqrt(),
+        radius * (1.0 - normal.z * normal.z).max(0.0).sqrt(),
+    );
+    (center - extent, center + extent)
 }
@@ -0,0 +1,208 @@
+use crate::photon_map::PhotonMap;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+
+const MAGIC: &[u8; 8] = b"PHOTONS\0";
+const VERSION: u32 = 1;
+
+// FNV-1a, unlike the std hashers its output is stable across runs and compiler versions,
+// which is required for keys that are written to disk
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum PhotonMapKind {
+    Global,
+    Caustic,
+    Volume,
+}
+
+impl PhotonMapKind {
+    fn tag(&self) -> u8 {
+        match self {
+            PhotonMapKind::Global => 0,
+            PhotonMapKind::Caustic => 1,
+            PhotonMapKind::Volume => 2,
+        }
+    }
+
+    fn from_tag(tag: u8) -> std::io::Result<Self> {
+        match tag {
+            0 => Ok(PhotonMapKind::Global),
+            1 => Ok(PhotonMapKind::Caustic),
+            2 => Ok(PhotonMapKind::Volume),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                "unknown photon map kind",
+            )),
+        }
+    }
+}
+
+// Photon maps together with the key of the scene they were traced in. A cache is only valid
+// for a scene with the same key, see Scene's Hash implementation.
+pub struct PhotonCache {
+    pub key: u64,
+    pub maps: Vec<(PhotonMapKind, PhotonMap)>,
+}
+
+impl PhotonCache {
+    pub fn new(key: u64) -> Self {
+        Self { key, maps: vec![] }
+    }
+
+    pub fn take(&mut self, kind: PhotonMapKind) -> Option<PhotonMap> {
+        let index = self.maps.iter().position(|(k, _)| *k == kind)?;
+        Some(self.maps.remove(index).1)
+    }
+
+    pub fn write_file(&self, file_name: &str) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn read_file(file_name: &str) -> std::io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(file_name)?))
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let maps = self
+            .maps
+            .iter()
+            .map(|(kind, map)| (*kind, map))
+            .collect::<Vec<_>>();
+        write_photon_maps(writer, self.key, &maps)
+    }
+
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "not a photon map file"));
+        }
+        let mut word = [0; 4];
+        reader.read_exact(&mut word)?;
+        let version = u32::from_le_bytes(word);
+        if version != VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported photon map file version {}", version),
+            ));
+        }
+        let mut key = [0; 8];
+        reader.read_exact(&mut key)?;
+        reader.read_exact(&mut word)?;
+        let count = u32::from_le_bytes(word);
+
+        let mut cache = Self::new(u64::from_le_bytes(key));
+        for _ in 0..count {
+            let mut tag = [0; 1];
+            reader.read_exact(&mut tag)?;
+            let kind = PhotonMapKind::from_tag(tag[0])?;
+            cache.maps.push((kind, PhotonMap::read_from(reader)?));
+        }
+        Ok(cache)
+    }
+}
+
+// writes borrowed maps in the format of PhotonCache::write_to
+pub fn write_photon_maps<W: Write>(
+    writer: &mut W,
+    key: u64,
+    maps: &[(PhotonMapKind, &PhotonMap)],
+) -> std::io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&VERSION.to_le_bytes())?;
+    writer.write_all(&key.to_le_bytes())?;
+    writer.write_all(&(maps.len() as u32).to_le_bytes())?;
+    for (kind, map) in maps {
+        writer.write_all(&[kind.tag()])?;
+        map.write_to(writer)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::material::Color;
+    use crate::photon_map::Photon;
+    use crate::vector3::Vector3;
+
+    fn photon_map(count: usize) -> PhotonMap {
+        let mut map = PhotonMap::new();
+        for i in 0..count {
+            let x = i as f32;
+            map.store(Photon::new(
+                Vector3::new(x, 2.0 * x, -x),
+                Vector3::new(0.0, -1.0, 0.0),
+                Vector3::new(1.0, 0.0, 0.0),
+                Color::new(x, 0.5, 0.25),
+            ));
+        }
+        map.balance();
+        map
+    }
+
+    #[test]
+    fn reads_back_what_was_written() {
+        let mut cache = PhotonCache::new(0x1234_5678_9abc_def0);
+        cache.maps.push((PhotonMapKind::Global, photon_map(100)));
+        cache.maps.push((PhotonMapKind::Caustic, photon_map(0)));
+        let mut bytes = vec![];
+        cache.write_to(&mut bytes).unwrap();
+
+        let read = PhotonCache::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(read.key, cache.key);
+        assert_eq!(read.maps.len(), cache.maps.len());
+        for ((kind, map), (read_kind, read_map)) in cache.maps.iter().zip(&read.maps) {
+            assert_eq!(kind, read_kind);
+            assert_eq!(map.len(), read_map.len());
+            for i in 0..map.len() {
+                let (photon, read_photon) = (map.photon(i), read_map.photon(i));
+                assert_eq!(photon.position.to_array(), read_photon.position.to_array());
+                assert_eq!(photon.power.to_array(), read_photon.power.to_array());
+                assert_eq!(
+                    photon.direction().to_array(),
+                    read_photon.direction().to_array()
+                );
+                assert_eq!(photon.normal().to_array(), read_photon.normal().to_array());
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        let mut bytes = vec![];
+        PhotonCache::new(1).write_to(&mut bytes).unwrap();
+        bytes[0] = b'X';
+        assert!(PhotonCache::read_from(&mut bytes.as_slice()).is_err());
+
+        let mut bytes = vec![];
+        let mut cache = PhotonCache::new(1);
+        cache.maps.push((PhotonMapKind::Global, photon_map(10)));
+        cache.write_to(&mut bytes).unwrap();
+        bytes.truncate(bytes.len() - 1);
+        assert!(PhotonCache::read_from(&mut bytes.as_slice()).is_err());
+    }
+}
@@ -0,0 +1,645 @@
+use crate::material::Color;
+use crate::vector3::Vector3;
+use core::f32::consts::PI;
+use std::cell::RefCell;
+use std::io::{Read, Write};
+use std::sync::OnceLock;
+
+const N_PHOTON_RADIANCE: usize = 400;
+
+// incoming directions and surface normals are stored as two bytes of spherical coordinates
+struct DirectionTable {
+    cos_theta: [f32; 256],
+    sin_theta: [f32; 256],
+    cos_phi: [f32; 256],
+    sin_phi: [f32; 256],
+}
+
+fn direction_table() -> &'static DirectionTable {
+    static TABLE: OnceLock<DirectionTable> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = DirectionTable {
+            cos_theta: [0.0; 256],
+            sin_theta: [0.0; 256],
+            cos_phi: [0.0; 256],
+            sin_phi: [0.0; 256],
+        };
+        for i in 0..256 {
+            let angle = (i as f32) * (1.0 / 256.0) * PI;
+            table.cos_theta[i] = angle.cos();
+            table.sin_theta[i] = angle.sin();
+            table.cos_phi[i] = (2.0 * angle).cos();
+            table.sin_phi[i] = (2.0 * angle).sin();
+        }
+        table
+    })
+}
+
+fn encode_direction(direction: Vector3) -> (u8, u8) {
+    let theta = (direction.z.clamp(-1.0, 1.0).acos() * (256.0 / PI)) as i32;
+    let phi = (direction.y.atan2(direction.x) * (256.0 / (2.0 * PI))) as i32;
+    let phi = if phi < 0 { phi + 256 } else { phi };
+    (theta.clamp(0, 255) as u8, phi.clamp(0, 255) as u8)
+}
+
+fn decode_direction(theta: u8, phi: u8) -> Vector3 {
+    let table = direction_table();
+    let (theta, phi) = (theta as usize, phi as usize);
+    Vector3::new(
+        table.sin_theta[theta] * table.cos_phi[phi],
+        table.sin_theta[theta] * table.sin_phi[phi],
+        table.cos_theta[theta],
+    )
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Photon {
+    pub position: Vector3,
+    pub power: Color,
+    theta: u8,
+    phi: u8,
+    normal_theta: u8,
+    normal_phi: u8,
+    plane: u8,
+}
+
+impl Photon {
+    pub fn new(position: Vector3, direction: Vector3, normal: Vector3, power: Color) -> Self {
+        let (theta, phi) = encode_direction(direction);
+        let (normal_theta, normal_phi) = encode_direction(normal);
+        Self {
+            position,
+            power,
+            theta,
+            phi,
+            normal_theta,
+            normal_phi,
+            plane: 0,
+        }
+    }
+
+    pub fn direction(&self) -> Vector3 {
+        decode_direction(self.theta, self.phi)
+    }
+
+    pub fn normal(&self) -> Vector3 {
+        decode_direction(self.normal_theta, self.normal_phi)
+    }
+}
+
+// Fixed capacity max-heap on distance, reused between queries so lookups do not allocate
+#[derive(Default)]
+pub struct NearestPhotons {
+    found: Vec<(f32, usize)>,
+    capacity: usize,
+    max_distance_squared: f32,
+}
+
+impl NearestPhotons {
+    fn reset(&mut self, capacity: usize, max_distance_squared: f32) {
+        self.found.clear();
+        self.found.reserve(capacity);
+        self.capacity = capacity;
+        self.max_distance_squared = max_distance_squared;
+    }
+
+    fn insert(&mut self, distance_squared: f32, index: usize) {
+        if self.found.len() < self.capacity {
+            self.found.push((distance_squared, index));
+            let mut child = self.found.len() - 1;
+            while child > 0 {
+                let parent = (child - 1) / 2;
+                if self.found[parent].0 >= self.found[child].0 {
+                    break;
+                }
+                self.found.swap(parent, child);
+                child = parent;
+            }
+        } else {
+            self.found[0] = (distance_squared, index);
+            let mut parent = 0;
+            loop {
+                let mut largest = parent;
+                for child in [2 * parent + 1, 2 * parent + 2] {
+                    if child < self.found.len() && self.found[child].0 > self.found[largest].0 {
+                        largest = child;
+                    }
+                }
+                if largest == parent {
+                    break;
+                }
+                self.found.swap(parent, largest);
+                parent = largest;
+            }
+        }
+        // once full the search radius shrinks to the furthest photon found so far
+        if self.found.len() == self.capacity {
+            self.max_distance_squared = self.found[0].0;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.found.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.found.is_empty()
+    }
+
+    pub fn max_distance_squared(&self) -> f32 {
+        self.found.iter().map(|(d, _)| *d).fold(0.0, f32::max)
+    }
+}
+
+// Left-balanced kd-tree stored as an implicit heap: the children of node i are 2i and 2i+1
+// (one-based), so no pointers are stored and the tree is a single flat array.
+pub struct PhotonMap {
+    photons: Vec<Photon>,
+    bbox_min: Vector3,
+    bbox_max: Vector3,
+    // whether the photons are in the order of the tree, which lookups rely on
+    balanced: bool,
+}
+
+impl Default for PhotonMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PhotonMap {
+    pub fn new() -> Self {
+        Self {
+            photons: vec![],
+            bbox_min: Vector3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            bbox_max: Vector3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+            balanced: true,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.photons.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.photons.is_empty()
+    }
+
+    pub fn store(&mut self, photon: Photon) {
+        self.bbox_min = self.bbox_min.min(photon.position);
+        self.bbox_max = self.bbox_max.max(photon.position);
+        self.photons.push(photon);
+        self.balanced = false;
+    }
+
+    // must be called after the last photon is stored and before any lookup
+    pub fn balance(&mut self) {
+        let n = self.photons.len();
+        self.balanced = true;
+        if n <= 1 {
+            return;
+        }
+        let mut original: Vec<usize> = (0..n).collect();
+        let mut balanced = vec![(0, 0); n];
+        balance_segment(
+            &self.photons,
+            &mut balanced,
+            &mut original,
+            1,
+            (0, n),
+            (self.bbox_min, self.bbox_max),
+        );
+        self.photons = balanced
+            .into_iter()
+            .map(|(photon_index, plane)| Photon {
+                plane,
+                ..self.photons[photon_index]
+            })
+            .collect();
+    }
+
+    // gathers the photon_count nearest photons within max_distance_squared according to
+    // distance, which returns None to reject a photon and may never be smaller than the
+    // squared euclidean distance
+    pub fn nearest<F>(
+        &self,
+        position: Vector3,
+        photon_count: usize,
+        max_distance_squared: f32,
+        distance: &F,
+        found: &mut NearestPhotons,
+    ) where
+        F: Fn(&Photon) -> Option<f32>,
+    {
+        debug_assert!(self.balanced, "photon map looked up before it was balanced");
+        found.reset(photon_count, max_distance_squared);
+        if !self.photons.is_empty() && photon_count > 0 {
+            self.locate(1, position, distance, found);
+        }
+    }
+
+    pub fn photon(&self, index: usize) -> &Photon {
+        &self.photons[index]
+    }
+
+    // calls visit for every photon within the radius, without any allocation
+    pub fn for_each_within<F>(&self, position: Vector3, radius_squared: f32, visit: &mut F)
+    where
+        F: FnMut(&Photon, f32),
+    {
+        debug_assert!(self.balanced, "photon map looked up before it was balanced");
+        if !self.photons.is_empty() {
+            self.visit_within(1, position, radius_squared, visit);
+        }
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&(self.photons.len() as u64).to_le_bytes())?;
+        for v in [self.bbox_min, self.bbox_max] {
+            write_floats(writer, &v.to_array())?;
+        }
+        for photon in &self.photons {
+            write_floats(writer, &photon.position.to_array())?;
+            write_floats(writer, &photon.power.to_array())?;
+            writer.write_all(&[
+                photon.theta,
+                photon.phi,
+                photon.normal_theta,
+                photon.normal_phi,
+                photon.plane,
+            ])?;
+        }
+        Ok(())
+    }
+
+    // reads a map written by write_to, which is already balanced
+    pub fn read_from<R: Read>(reader: &mut R) -> std::io::Result<Self> {
+        let mut count = [0; 8];
+        reader.read_exact(&mut count)?;
+        let count = u64::from_le_bytes(count) as usize;
+        let [x, y, z] = read_floats(reader)?;
+        let bbox_min = Vector3::new(x, y, z);
+        let [x, y, z] = read_floats(reader)?;
+        let bbox_max = Vector3::new(x, y, z);
+        // the count is not trusted with an allocation, a broken file runs out of data instead
+        let mut photons = vec![];
+        for _ in 0..count {
+            let [x, y, z] = read_floats(reader)?;
+            let [r, g, b] = read_floats(reader)?;
+            let mut bytes = [0; 5];
+            reader.read_exact(&mut bytes)?;
+            photons.push(Photon {
+                position: Vector3::new(x, y, z),
+                power: Color::new(r, g, b),
+                theta: bytes[0],
+                phi: bytes[1],
+                normal_theta: bytes[2],
+                normal_phi: bytes[3],
+                plane: bytes[4],
+            });
+        }
+        Ok(Self {
+            photons,
+            bbox_min,
+            bbox_max,
+            balanced: true,
+        })
+    }
+
+    fn locate<F>(&self, index: usize, position: Vector3, distance: &F, found: &mut NearestPhotons)
+    where
+        F: Fn(&Photon) -> Option<f32>,
+    {
+        let photon = &self.photons[index - 1];
+        let n = self.photons.len();
+        if 2 * index <= n {
+            let plane = photon.plane as usize;
+            let offset = position.coord(plane) - photon.position.coord(plane);
+            let (near, far) = if offset > 0.0 {
+                (2 * index + 1, 2 * index)
+            } else {
+                (2 * index, 2 * index + 1)
+            };
+            if near <= n {
+                self.locate(near, position, distance, found);
+            }
+            if far <= n && offset * offset < found.max_distance_squared {
+                self.locate(far, position, distance, found);
+            }
+        }
+        if let Some(distance_squared) = distance(photon) {
+            if distance_squared < found.max_distance_squared {
+                found.insert(distance_squared, index - 1);
+            }
+        }
+    }
+
+    fn visit_within<F>(&self, index: usize, position: Vector3, radius_squared: f32, visit: &mut F)
+    where
+        F: FnMut(&Photon, f32),
+    {
+        let photon = &self.photons[index - 1];
+        let n = self.photons.len();
+        if 2 * index <= n {
+            let plane = photon.plane as usize;
+            let offset = position.coord(plane) - photon.position.coord(plane);
+            if 2 * index < n && (offset > 0.0 || offset * offset < radius_squared) {
+                self.visit_within(2 * index + 1, position, radius_squared, visit);
+            }
+            if offset < 0.0 || offset * offset < radius_squared {
+                self.visit_within(2 * index, position, radius_squared, visit);
+            }
+        }
+        let distance_squared = (photon.position - position).length_squared();
+        if distance_squared < radius_squared {
+            visit(photon, distance_squared);
+        }
+    }
+}
+
+pub(crate) fn write_floats<W: Write>(writer: &mut W, values: &[f32; 3]) -> std::io::Result<()> {
+    for value in values {
+        writer.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+pub(crate) fn read_floats<R: Read>(reader: &mut R) -> std::io::Result<[f32; 3]> {
+    let mut bytes = [0; 12];
+    reader.read_exact(&mut bytes)?;
+    let float = |i: usize| f32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]]);
+    Ok([float(0), float(4), float(8)])
+}
+
+// Recursively places the median of original[start..end] at the given heap index. The median is
+// chosen such that the resulting tree is left-balanced, which keeps the heap array compact.
+fn balance_segment(
+    photons: &[Photon],
+    balanced: &mut [(usize, u8)],
+    original: &mut [usize],
+    index: usize,
+    (start, end): (usize, usize),
+    (bbox_min, bbox_max): (Vector3, Vector3),
+) {
+    let count = end - start;
+    let mut median = 1;
+    while 4 * median <= count {
+        median += median;
+    }
+    let median = if 3 * median <= count {
+        start + 2 * median - 1
+    } else {
+        end - median
+    };
+
+    let extent = bbox_max - bbox_min;
+    let axis = if extent.x > extent.y && extent.x > extent.z {
+        0
+    } else if extent.y > extent.z {
+        1
+    } else {
+        2
+    };
+
+    original[start..end].select_nth_unstable_by(median - start, |a, b| {
+        photons[*a]
+            .position
+            .coord(axis)
+            .total_cmp(&photons[*b].position.coord(axis))
+    });
+    balanced[index - 1] = (original[median], axis as u8);
+
+    let split = photons[original[median]].position.coord(axis);
+    if median > start {
+        balance_segment(
+            photons,
+            balanced,
+            original,
+            2 * index,
+            (start, median),
+            (bbox_min, bbox_max.replace_coord(axis, split)),
+        );
+    }
+    if median + 1 < end {
+        balance_segment(
+            photons,
+            balanced,
+            original,
+            2 * index + 1,
+            (median + 1, end),
+            (bbox_min.replace_coord(axis, split), bbox_max),
+        );
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum Filter {
+    Flat,
+    // Jensen's cone filter, the argument is the filter constant k >= 1
+    Cone(f32),
+    Gaussian,
+    Epanechnikov,
+}
+
+// constants of the normalized gaussian filter by Pavicic
+const GAUSSIAN_ALPHA: f32 = 0.918;
+const GAUSSIAN_BETA: f32 = 1.953;
+
+impl Filter {
+    fn weight(&self, distance_squared: f32, radius_squared: f32) -> f32 {
+        let x = distance_squared / radius_squared;
+        match *self {
+            Filter::Flat => 1.0,
+            Filter::Cone(k) => (1.0 - x.sqrt() / k).max(0.0),
+            Filter::Gaussian => {
+                GAUSSIAN_ALPHA
+                    * (1.0
+                        - (1.0 - (-GAUSSIAN_BETA * x / 2.0).exp()) / (1.0 - (-GAUSSIAN_BETA).exp()))
+            }
+            Filter::Epanechnikov => (1.0 - x).max(0.0),
+        }
+    }
+
+    // integral of the weight over the unit disc divided by its area
+    fn normalization(&self) -> f32 {
+        match *self {
+            Filter::Flat => 1.0,
+            Filter::Cone(k) => 1.0 - 2.0 / (3.0 * k),
+            Filter::Gaussian => {
+                let integral = 1.0 - 2.0 / GAUSSIAN_BETA * (1.0 - (-GAUSSIAN_BETA / 2.0).exp());
+                GAUSSIAN_ALPHA * (1.0 - integral / (1.0 - (-GAUSSIAN_BETA).exp()))
+            }
+            Filter::Epanechnikov => 0.5,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct RadianceEstimate {
+    pub photon_count: usize,
+    pub max_distance: f32,
+    pub filter: Filter,
+    // photons whose surface normal has a smaller cosine with the query normal are rejected
+    pub normal_tolerance: f32,
+    // distances along the normal are scaled by this factor of at least 1, turning the search
+    // sphere into a disc
+    pub disc_flattening: f32,
+}
+
+impl Default for RadianceEstimate {
+    fn default() -> Self {
+        Self {
+            photon_count: N_PHOTON_RADIANCE,
+            max_distance: f32::INFINITY,
+            filter: Filter::Flat,
+            normal_tolerance: -1.0,
+            disc_flattening: 1.0,
+        }
+    }
+}
+
+thread_local! {
+    static NEAREST_PHOTONS: RefCell<NearestPhotons> = RefCell::new(NearestPhotons::default());
+}
+
+impl RadianceEstimate {
+    pub fn irradiance(&self, photon_map: &PhotonMap, position: Vector3, normal: Vector3) -> Color {
+        let stretch = self.disc_flattening * self.disc_flattening - 1.0;
+        let distance = |photon: &Photon| {
+            if photon.normal().inner_product(normal) < self.normal_tolerance {
+                return None;
+            }
+            let offset = photon.position - position;
+            let along_normal = offset.inner_product(normal);
+            Some(offset.length_squared() + stretch * along_normal * along_normal)
+        };
+
+        NEAREST_PHOTONS.with(|nearest| {
+            let mut nearest = nearest.borrow_mut();
+            photon_map.nearest(
+                position,
+                self.photon_count,
+                self.max_distance * self.max_distance,
+                &distance,
+                &mut nearest,
+            );
+
+            let radius_squared = nearest.max_distance_squared();
+            if nearest.is_empty() || radius_squared <= 0.0 {
+                return Color::black();
+            }
+
+            let mut result = Color::black();
+            for &(distance_squared, index) in &nearest.found {
+                let photon = photon_map.photon(index);
+                result += photon.power
+                    * normal.inner_product(photon.direction()).max(0.0)
+                    * self.filter.weight(distance_squared, radius_squared);
+            }
+
+            result * (1.0 / (self.filter.normalization() * radius_squared * PI))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // xorshift points in a 10 unit cube, so the tests need no random number generator
+    fn points(seed: u32, count: usize) -> Vec<Vector3> {
+        let mut state = seed;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            10.0 * state as f32 / u32::MAX as f32
+        };
+        (0..count)
+            .map(|_| Vector3::new(next(), next(), next()))
+            .collect()
+    }
+
+    fn balanced_map(positions: &[Vector3]) -> PhotonMap {
+        let mut map = PhotonMap::new();
+        for position in positions {
+            let up = Vector3::new(0.0, 1.0, 0.0);
+            map.store(Photon::new(*position, up, up, Color::white()));
+        }
+        map.balance();
+        map
+    }
+
+    fn sorted(mut distances: Vec<f32>) -> Vec<f32> {
+        distances.sort_by(f32::total_cmp);
+        distances
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let positions = points(0x2545_f491, 2000);
+        let map = balanced_map(&positions);
+        let mut found = NearestPhotons::default();
+        for query in points(0x9e37_79b9, 50) {
+            let distance = |p: &Photon| Some((p.position - query).length_squared());
+            for &(count, max_distance_squared) in
+                &[(1, f32::INFINITY), (20, f32::INFINITY), (50, 1.0)]
+            {
+                map.nearest(query, count, max_distance_squared, &distance, &mut found);
+                for &(distance_squared, index) in &found.found {
+                    assert_eq!(distance(map.photon(index)), Some(distance_squared));
+                }
+                let expected = sorted(
+                    positions
+                        .iter()
+                        .map(|p| (p - query).length_squared())
+                        .filter(|d| *d < max_distance_squared)
+                        .collect(),
+                );
+                let expected = expected[..count.min(expected.len())].to_vec();
+                assert_eq!(
+                    sorted(found.found.iter().map(|(d, _)| *d).collect()),
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn nearest_skips_rejected_photons() {
+        let positions = points(0x2545_f491, 500);
+        let map = balanced_map(&positions);
+        let mut found = NearestPhotons::default();
+        let query = Vector3::new(5.0, 5.0, 5.0);
+        let distance = |p: &Photon| {
+            Some((p.position - query).length_squared()).filter(|_| p.position.coord(0) > 5.0)
+        };
+        map.nearest(query, 10, f32::INFINITY, &distance, &mut found);
+        let expected = sorted(
+            positions
+                .iter()
+                .filter_map(|p| Some((p - query).length_squared()).filter(|_| p.coord(0) > 5.0))
+                .collect(),
+        );
+        assert_eq!(
+            sorted(found.found.iter().map(|(d, _)| *d).collect()),
+            expected[..10]
+        );
+    }
+
+    #[test]
+    fn for_each_within_matches_brute_force() {
+        let positions = points(0x2545_f491, 2000);
+        let map = balanced_map(&positions);
+        for query in points(0x9e37_79b9, 50) {
+            let mut visited = vec![];
+            map.for_each_within(query, 2.0, &mut |_, d| visited.push(d));
+            let expected = positions
+                .iter()
+                .map(|p| (p - query).length_squared())
+                .filter(|d| *d < 2.0)
+                .collect();
+            assert_eq!(sorted(visited), sorted(expected));
+        }
+    }
+}
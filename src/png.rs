@@ -0,0 +1,112 @@
+use crate::material::Color;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+// largest block of data a stored deflate block can hold
+const MAX_STORED_BLOCK: usize = 65535;
+
+// 8 bit RGB PNG image. The image data is stored without compression, which keeps the writer
+// small at the cost of files the size of a ppm image.
+pub struct PNG {
+    file_name: String,
+    height: usize,
+    width: usize,
+    data: Vec<u8>,
+}
+
+impl PNG {
+    pub fn new(file_name: &str, width: usize, height: usize) -> Self {
+        PNG {
+            file_name: file_name.to_string(),
+            height,
+            width,
+            data: vec![0; 3 * height * width],
+        }
+    }
+
+    pub fn add_pixel(&mut self, x: usize, y: usize, color: Color) {
+        let location = 3 * (y * self.width + x);
+        if location < 3 * self.height * self.width {
+            self.data[location] = color.r_byte();
+            self.data[location + 1] = color.g_byte();
+            self.data[location + 2] = color.b_byte();
+        }
+    }
+
+    pub fn write_file(&self) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(&self.file_name)?);
+        self.write_to(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&SIGNATURE)?;
+
+        let mut header = vec![];
+        header.extend_from_slice(&(self.width as u32).to_be_bytes());
+        header.extend_from_slice(&(self.height as u32).to_be_bytes());
+        // bit depth 8, truecolor, deflate compression, adaptive filtering, no interlacing
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(writer, b"IHDR", &header)?;
+
+        // every scanline starts with its filter type, 0 leaves it unfiltered
+        let mut scanlines = Vec::with_capacity(self.height * (3 * self.width + 1));
+        for row in self.data.chunks(3 * self.width) {
+            scanlines.push(0);
+            scanlines.extend_from_slice(row);
+        }
+        write_chunk(writer, b"IDAT", &zlib_stored(&scanlines))?;
+        write_chunk(writer, b"IEND", &[])
+    }
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> std::io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(crc32(0xffff_ffff, kind), data) ^ 0xffff_ffff;
+    writer.write_all(&crc.to_be_bytes())
+}
+
+// zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let blocks = data.chunks(MAX_STORED_BLOCK).collect::<Vec<_>>();
+    if blocks.is_empty() {
+        stream.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    for (i, block) in blocks.iter().enumerate() {
+        let last = i + 1 == blocks.len();
+        stream.push(last as u8);
+        let length = block.len() as u16;
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                0xedb8_8320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -0,0 +1,230 @@
+// Real roots of polynomials, in no particular order. The cubic and quartic follow the closed
+// form solutions of Schwarze in Graphics Gems, which are computed in double precision since the
+// quartic loses many digits.
+
+const EPSILON: f64 = 1e-12;
+
+// roots of a * x^2 + b * x + c, falling back to the linear equation when a is zero
+pub fn solve_quadratic(a: f32, b: f32, c: f32) -> Vec<f32> {
+    if a == 0.0 {
+        return if b == 0.0 { vec![] } else { vec![-c / b] };
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return vec![];
+    }
+    // avoids the cancellation of subtracting nearly equal values
+    let q = -0.5 * (b + b.signum() * discriminant.sqrt());
+    if q == 0.0 {
+        return vec![0.0];
+    }
+    vec![q / a, c / q]
+}
+
+// roots of x^2 + p * x + q
+fn solve_normal_quadratic(p: f64, q: f64) -> Vec<f64> {
+    let half = p / 2.0;
+    let discriminant = half * half - q;
+    if is_zero(discriminant) {
+        vec![-half]
+    } else if discriminant < 0.0 {
+        vec![]
+    } else {
+        let root = discriminant.sqrt();
+        vec![root - half, -root - half]
+    }
+}
+
+// roots of x^3 + a * x^2 + b * x + c
+pub fn solve_cubic(a: f64, b: f64, c: f64) -> Vec<f64> {
+    // substituting x = y - a / 3 leaves y^3 + 3 * p * y + 2 * q
+    let a2 = a * a;
+    let p = (-a2 / 3.0 + b) / 3.0;
+    let q = (2.0 / 27.0 * a * a2 - a * b / 3.0 + c) / 2.0;
+    let p3 = p * p * p;
+    let discriminant = q * q + p3;
+
+    let roots = if is_zero(discriminant) {
+        if is_zero(q) {
+            vec![0.0]
+        } else {
+            let u = (-q).cbrt();
+            vec![2.0 * u, -u]
+        }
+    } else if discriminant < 0.0 {
+        // three real roots
+        let phi = (-q / (-p3).sqrt()).clamp(-1.0, 1.0).acos() / 3.0;
+        let t = 2.0 * (-p).sqrt();
+        let third = std::f64::consts::PI / 3.0;
+        vec![
+            t * phi.cos(),
+            -t * (phi + third).cos(),
+            -t * (phi - third).cos(),
+        ]
+    } else {
+        let root = discriminant.sqrt();
+        vec![(root - q).cbrt() - (root + q).cbrt()]
+    };
+    roots.into_iter().map(|y| y - a / 3.0).collect()
+}
+
+// roots of x^4 + a * x^3 + b * x^2 + c * x + d, each polished by Newton's method
+pub fn solve_quartic(a: f64, b: f64, c: f64, d: f64) -> Vec<f64> {
+    // substituting x = y - a / 4 leaves y^4 + p * y^2 + q * y + r
+    let a2 = a * a;
+    let p = -3.0 / 8.0 * a2 + b;
+    let q = a2 * a / 8.0 - a * b / 2.0 + c;
+    let r = -3.0 / 256.0 * a2 * a2 + a2 * b / 16.0 - a * c / 4.0 + d;
+
+    let roots = if is_zero(r) {
+        let mut roots = solve_cubic(0.0, p, q);
+        roots.push(0.0);
+        roots
+    } else {
+        // any real root of the resolvent cubic splits the quartic into two quadratics
+        let z = solve_cubic(-p / 2.0, -r, r * p / 2.0 - q * q / 8.0)[0];
+        let u = z * z - r;
+        let v = 2.0 * z - p;
+        let u = if is_zero(u) {
+            0.0
+        } else if u > 0.0 {
+            u.sqrt()
+        } else {
+            return vec![];
+        };
+        let v = if is_zero(v) {
+            0.0
+        } else if v > 0.0 {
+            v.sqrt()
+        } else {
+            return vec![];
+        };
+        let v = if q < 0.0 { -v } else { v };
+        let mut roots = solve_normal_quadratic(v, z - u);
+        roots.extend(solve_normal_quadratic(-v, z + u));
+        roots
+    };
+
+    let polynomial = |x: f64| (((x + a) * x + b) * x + c) * x + d;
+    let derivative = |x: f64| ((4.0 * x + 3.0 * a) * x + 2.0 * b) * x + c;
+    roots
+        .into_iter()
+        .map(|y| {
+            let mut x = y - a / 4.0;
+            for _ in 0..2 {
+                let slope = derivative(x);
+                if slope != 0.0 {
+                    x -= polynomial(x) / slope;
+                }
+            }
+            x
+        })
+        .collect()
+}
+
+fn is_zero(x: f64) -> bool {
+    x.abs() < EPSILON
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_roots(mut roots: Vec<f64>, expected: &[f64]) {
+        roots.sort_by(f64::total_cmp);
+        assert_eq!(roots.len(), expected.len(), "roots {:?}", roots);
+        for (root, expected) in roots.iter().zip(expected) {
+            assert!((root - expected).abs() < 1e-9, "roots {:?}", roots);
+        }
+    }
+
+    // distances along a ray to a torus around the y axis through the origin, with the
+    // coefficients Ray::torus_hits uses
+    fn torus_roots(origin: [f64; 3], direction: [f64; 3], major: f64, minor: f64) -> Vec<f64> {
+        let [ox, oy, oz] = origin;
+        let length = direction.iter().map(|d| d * d).sum::<f64>().sqrt();
+        let [dx, dy, dz] = direction.map(|d| d / length);
+        let (r2, s2) = (major * major, minor * minor);
+        let f = ox * dx + oy * dy + oz * dz;
+        let e = ox * ox + oy * oy + oz * oz + r2 - s2;
+        solve_quartic(
+            4.0 * f,
+            2.0 * e + 4.0 * f * f - 4.0 * r2 * (dx * dx + dz * dz),
+            4.0 * f * e - 8.0 * r2 * (ox * dx + oz * dz),
+            e * e - 4.0 * r2 * (ox * ox + oz * oz),
+        )
+    }
+
+    #[test]
+    fn quadratic_roots() {
+        let mut roots = solve_quadratic(1.0, -3.0, 2.0);
+        roots.sort_by(f32::total_cmp);
+        assert_eq!(roots, vec![1.0, 2.0]);
+        assert_eq!(solve_quadratic(0.0, 2.0, -1.0), vec![0.5]);
+        assert!(solve_quadratic(1.0, 0.0, 1.0).is_empty());
+    }
+
+    #[test]
+    fn cubic_roots() {
+        // (x - 1)(x - 2)(x - 3)
+        assert_roots(solve_cubic(-6.0, 11.0, -6.0), &[1.0, 2.0, 3.0]);
+        // (x - 1)^2 (x + 2)
+        assert_roots(solve_cubic(0.0, -3.0, 2.0), &[-2.0, 1.0]);
+        // (x - 2)(x^2 + 1)
+        assert_roots(solve_cubic(-2.0, 1.0, -2.0), &[2.0]);
+    }
+
+    #[test]
+    fn rays_through_the_tube_hit_a_torus_four_times() {
+        assert_roots(
+            torus_roots([-5.0, 0.0, 0.0], [1.0, 0.0, 0.0], 2.0, 0.5),
+            &[2.5, 3.5, 6.5, 7.5],
+        );
+        // off center the tube is 0.8 wide instead of 1
+        assert_roots(
+            torus_roots([-5.0, 0.3, 0.0], [2.0, 0.0, 0.0], 2.0, 0.5),
+            &[2.6, 3.4, 6.6, 7.4],
+        );
+        assert_roots(
+            torus_roots([0.0, 0.0, -5.0], [0.0, 0.0, 1.0], 1.0, 0.25),
+            &[3.75, 4.25, 5.75, 6.25],
+        );
+        let diagonal = 50f64.sqrt();
+        assert_roots(
+            torus_roots([-5.0, 0.0, -5.0], [1.0, 0.0, 1.0], 2.0, 0.5),
+            &[
+                diagonal - 2.5,
+                diagonal - 1.5,
+                diagonal + 1.5,
+                diagonal + 2.5,
+            ],
+        );
+    }
+
+    #[test]
+    fn rays_across_the_tube_hit_a_torus_twice() {
+        assert_roots(
+            torus_roots([2.0, 5.0, 0.0], [0.0, -1.0, 0.0], 2.0, 0.5),
+            &[4.5, 5.5],
+        );
+        // through the outer rim only
+        assert_roots(
+            torus_roots([-5.0, 0.0, 2.3], [1.0, 0.0, 0.0], 2.0, 0.5),
+            &[5.0 - 0.96f64.sqrt(), 5.0 + 0.96f64.sqrt()],
+        );
+    }
+
+    #[test]
+    fn rays_missing_a_torus_have_no_roots() {
+        // down the hole in the middle
+        assert_roots(
+            torus_roots([0.0, 5.0, 0.0], [0.0, -1.0, 0.0], 2.0, 0.5),
+            &[],
+        );
+        // above it
+        assert_roots(
+            torus_roots([-5.0, 1.0, 0.0], [1.0, 0.0, 0.0], 2.0, 0.5),
+            &[],
+        );
+    }
+}
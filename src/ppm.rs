@@ -1,5 +1,5 @@
 use crate::material::Color;
-use std::io::Write;
+use std::io::{Error, ErrorKind, Read, Write};
 
 pub struct PPM {
     file_name: String,
@@ -18,7 +18,7 @@ impl PPM {
         }
     }
 
-    pub fn add_pixel(&mut self, x: usize, y: usize, color: Color) -> () {
+    pub fn add_pixel(&mut self, x: usize, y: usize, color: Color) {
         let location = 3 * (y * self.width + x);
         if location < 3 * self.height * self.width {
             self.data[location] = color.r_byte();
@@ -27,8 +27,72 @@ impl PPM {
         }
     }
 
+    // reads a binary PPM image with 8 bit channels
+    pub fn read_file(file_name: &str) -> std::io::Result<Self> {
+        let mut bytes = vec![];
+        std::fs::File::open(file_name)?.read_to_end(&mut bytes)?;
+        let invalid = || Error::new(ErrorKind::InvalidData, "not a binary 8 bit ppm file");
+
+        // the header holds four tokens separated by whitespace and comments, followed by a
+        // single whitespace character before the pixel data
+        let mut tokens = vec![];
+        let mut position = 0;
+        while tokens.len() < 4 {
+            match bytes.get(position) {
+                Some(b'#') => {
+                    while position < bytes.len() && bytes[position] != b'\n' {
+                        position += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => position += 1,
+                Some(_) => {
+                    let start = position;
+                    while position < bytes.len() && !bytes[position].is_ascii_whitespace() {
+                        position += 1;
+                    }
+                    tokens.push(String::from_utf8_lossy(&bytes[start..position]).to_string());
+                }
+                None => return Err(invalid()),
+            }
+        }
+        position += 1;
+        let number = |token: &str| token.parse::<usize>().map_err(|_| invalid());
+        let width = number(&tokens[1])?;
+        let height = number(&tokens[2])?;
+        if tokens[0] != "P6" || number(&tokens[3])? != 255 {
+            return Err(invalid());
+        }
+        let data = bytes
+            .get(position..position + 3 * width * height)
+            .ok_or_else(invalid)?
+            .to_vec();
+        Ok(PPM {
+            file_name: file_name.to_string(),
+            height,
+            width,
+            data,
+        })
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        let location = 3 * (y * self.width + x);
+        Color::new(
+            self.data[location] as f32 / 255.0,
+            self.data[location + 1] as f32 / 255.0,
+            self.data[location + 2] as f32 / 255.0,
+        )
+    }
+
     pub fn write_file(&self) -> std::io::Result<()> {
-        std::fs::File::create(self.file_name.to_string())
+        std::fs::File::create(&self.file_name)
             .and_then(|mut f| {
                 f.write_all(format!("P6\n{} {}\n255\n", self.width, self.height).as_bytes())
                     .map(|_| f)
@@ -0,0 +1,98 @@
+use crate::vector3::Vector3;
+use std::f32::consts::PI;
+
+// Marks which directions, as seen from a light, point towards one of a set of bounding
+// spheres. The sphere of directions is divided in cells of equal solid angle by splitting
+// cos(theta) and phi uniformly, so emitting uniformly over the marked cells only requires
+// scaling the photon power by the covered fraction of the sphere.
+pub struct ProjectionMap {
+    resolution_z: usize,
+    resolution_phi: usize,
+    cells: Vec<usize>,
+}
+
+impl ProjectionMap {
+    pub fn new(origin: Vector3, targets: &[(Vector3, f32)], resolution: usize) -> Self {
+        let resolution_z = resolution.max(1);
+        let resolution_phi = 2 * resolution_z;
+        let targets = targets
+            .iter()
+            .map(|(center, radius)| {
+                let offset = center - origin;
+                let distance = offset.length_squared().sqrt();
+                if distance <= *radius {
+                    // the light is inside the bounding sphere, every direction hits it
+                    (Vector3::new(0.0, 0.0, 1.0), PI)
+                } else {
+                    (offset / distance, (radius / distance).asin())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut cells = vec![];
+        for i in 0..resolution_z {
+            for j in 0..resolution_phi {
+                let center = cell_direction(i, j, resolution_z, resolution_phi, 0.5, 0.5);
+                // the corners and edge midpoints bound the angular size of the cell
+                let cell_radius = [0.0, 0.5, 1.0]
+                    .iter()
+                    .flat_map(|u| [0.0, 0.5, 1.0].iter().map(move |v| (*u, *v)))
+                    .map(|(u, v)| {
+                        let corner = cell_direction(i, j, resolution_z, resolution_phi, u, v);
+                        center.inner_product(corner).clamp(-1.0, 1.0).acos()
+                    })
+                    .fold(0.0, f32::max);
+                let covered = targets.iter().any(|(direction, angular_radius)| {
+                    center.inner_product(*direction).clamp(-1.0, 1.0).acos()
+                        <= angular_radius + cell_radius
+                });
+                if covered {
+                    cells.push(i * resolution_phi + j);
+                }
+            }
+        }
+
+        Self {
+            resolution_z,
+            resolution_phi,
+            cells,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cells.is_empty()
+    }
+
+    // fraction of the sphere of directions that is covered by the marked cells
+    pub fn coverage(&self) -> f32 {
+        (self.cells.len() as f32) / ((self.resolution_z * self.resolution_phi) as f32)
+    }
+
+    // picks a marked cell with the first sample and a direction inside it with the other two
+    pub fn random_direction(&self, cell_sample: f32, (u, v): (f32, f32)) -> Vector3 {
+        let index = ((cell_sample * self.cells.len() as f32) as usize).min(self.cells.len() - 1);
+        let cell = self.cells[index];
+        cell_direction(
+            cell / self.resolution_phi,
+            cell % self.resolution_phi,
+            self.resolution_z,
+            self.resolution_phi,
+            u,
+            v,
+        )
+    }
+}
+
+fn cell_direction(
+    i: usize,
+    j: usize,
+    resolution_z: usize,
+    resolution_phi: usize,
+    u: f32,
+    v: f32,
+) -> Vector3 {
+    let z = -1.0 + 2.0 * ((i as f32) + u) / (resolution_z as f32);
+    let phi = 2.0 * PI * ((j as f32) + v) / (resolution_phi as f32);
+    let sin_theta = (1.0 - z * z).max(0.0).sqrt();
+    Vector3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), z)
+}
@@ -1,71 +1,348 @@
+use crate::bvh::Bvh;
+use crate::curves::Curves;
 use crate::material::Material;
-use crate::objects::{Object, Plane, Pyramid, Shape, Sphere, Triangle};
+use crate::mesh::Mesh;
+use crate::motion::Shutter;
+use crate::objects::{
+    Cone, Csg, CsgOperation, Cuboid, Cylinder, Disk, Object, Plane, Pyramid, Quad, Shape, Sphere,
+    Torus, Triangle,
+};
+use crate::polynomial;
+use crate::sdf::SdfShape;
+use crate::stats::{self, Counter};
 use crate::vector3::Vector3;
 use std::cmp::Ordering;
+use std::f32::consts::PI;
 
 const BIAS: f32 = 0.0001;
+// limits of sphere tracing, the smallest step keeps rays grazing a surface moving
+const MAX_MARCH_STEPS: usize = 1024;
+const MIN_MARCH_STEP: f32 = 0.0001;
+const BISECTION_STEPS: usize = 24;
 
 #[derive(Default, Copy, Clone, Debug)]
 pub struct Ray {
     pub origin: Vector3,
     pub direction: Vector3,
+    // in frames, moving objects are intersected where they are at this time
+    pub time: f32,
 }
 
-pub struct Intersection {
+pub struct Intersection<'a> {
     pub t: f32,
     pub hit_point: Vector3,
     pub hit_normal: Vector3,
-    pub material: Material,
+    // surface coordinates, within [0, 1] for bounded shapes
+    pub uv: (f32, f32),
+    // direction along the surface that shading follows, for curves
+    pub tangent: Option<Vector3>,
+    pub material: &'a Material,
+}
+
+// Bounding volume hierarchy over objects wherever they are while the shutter is open. Unbounded
+// objects like planes are left out of it and tested by every ray.
+pub struct ObjectBvh {
+    bvh: Bvh,
+    // index of the object of every primitive of the hierarchy
+    bounded: Vec<usize>,
+    unbounded: Vec<usize>,
+}
+
+impl ObjectBvh {
+    pub fn new(objects: &[Object], shutter: &Shutter) -> Self {
+        let mut bounded = vec![];
+        let mut unbounded = vec![];
+        for (index, object) in objects.iter().enumerate() {
+            match object.bounding_box(shutter) {
+                Some(bounds) => bounded.push((index, bounds)),
+                None => unbounded.push(index),
+            }
+        }
+        let bounds = bounded.iter().map(|(_, b)| *b).collect::<Vec<_>>();
+        Self {
+            bvh: Bvh::new(&bounds),
+            bounded: bounded.into_iter().map(|(index, _)| index).collect(),
+            unbounded,
+        }
+    }
+}
+
+// hit in the space of a local frame: t, normal and surface coordinates
+type LocalHit = (f32, Vector3, (f32, f32));
+
+// Every crossing of the surface of a closed shape along the whole line of the ray, behind its
+// origin too, in order and with normals pointing out of the solid. Half-spaces are unbounded so
+// the line can start inside.
+struct Crossings<'a> {
+    inside_before: bool,
+    hits: Vec<Intersection<'a>>,
+}
+
+impl<'a> Crossings<'a> {
+    // A ray along an edge or past a rim can hit a face without the one next to it, the
+    // crossings are kept alternating between entries and exits so the solid stays closed.
+    fn bounded(mut hits: Vec<Intersection<'a>>, direction: Vector3) -> Self {
+        hits.sort_by(|a, b| a.t.total_cmp(&b.t));
+        let mut inside = false;
+        hits.retain(|hit| {
+            let entering = direction.inner_product(hit.hit_normal) < 0.0;
+            if entering == inside {
+                return false;
+            }
+            inside = entering;
+            true
+        });
+        if inside {
+            hits.pop();
+        }
+        Self {
+            inside_before: false,
+            hits,
+        }
+    }
+
+    // Walks the crossings of both solids together, keeping those where the combined solid
+    // changes between inside and outside. The cut away solid of a difference is seen from
+    // inside, so its normals turn around.
+    fn combine(
+        operation: CsgOperation,
+        left: Crossings<'a>,
+        right: Crossings<'a>,
+        direction: Vector3,
+    ) -> Self {
+        let inside = |left: bool, right: bool| match operation {
+            CsgOperation::Union => left || right,
+            CsgOperation::Intersection => left && right,
+            CsgOperation::Difference => left && !right,
+        };
+        let (mut in_left, mut in_right) = (left.inside_before, right.inside_before);
+        let inside_before = inside(in_left, in_right);
+        let mut state = inside_before;
+        let mut hits = vec![];
+        let mut left = left.hits.into_iter().peekable();
+        let mut right = right.hits.into_iter().peekable();
+        loop {
+            let from_left = match (left.peek(), right.peek()) {
+                (Some(a), Some(b)) => a.t <= b.t,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => break,
+            };
+            let mut hit = match if from_left { left.next() } else { right.next() } {
+                Some(hit) => hit,
+                None => break,
+            };
+            // the normal tells whether the ray enters, which stays right at grazing hits
+            let entering = direction.inner_product(hit.hit_normal) < 0.0;
+            if from_left {
+                in_left = entering;
+            } else {
+                in_right = entering;
+            }
+            let now = inside(in_left, in_right);
+            if now != state {
+                if !from_left && operation == CsgOperation::Difference {
+                    hit.hit_normal *= -1.0;
+                }
+                hits.push(hit);
+                state = now;
+            }
+        }
+        Self {
+            inside_before,
+            hits,
+        }
+    }
+}
+
+// Orthonormal frame in which a shape is intersected, the shapes around an axis use it as the
+// y axis
+struct LocalFrame {
+    origin: Vector3,
+    axes: [Vector3; 3],
+}
+
+impl LocalFrame {
+    fn around(origin: Vector3, axis: Vector3) -> Self {
+        let (y, x, z) = axis.create_coord_system();
+        Self {
+            origin,
+            axes: [x, y, z],
+        }
+    }
+
+    fn point(&self, point: Vector3) -> Vector3 {
+        self.vector(point - self.origin)
+    }
+
+    fn vector(&self, vector: Vector3) -> Vector3 {
+        let [x, y, z] = self.axes;
+        Vector3::new(
+            vector.inner_product(x),
+            vector.inner_product(y),
+            vector.inner_product(z),
+        )
+    }
+
+    fn to_world_vector(&self, vector: Vector3) -> Vector3 {
+        let [x, y, z] = self.axes;
+        x * vector.x + y * vector.y + z * vector.z
+    }
+}
+
+// Direction along the surface that anisotropic reflection follows, like the grooves of brushed
+// metal: around the axis of round shapes, where u runs too, and along the first edge of flat
+// ones. None for shapes without one.
+fn surface_tangent(shape: &Shape, point: Vector3) -> Option<Vector3> {
+    let around = |axis: Vector3, center: Vector3| {
+        let tangent = axis.outer_product(point - center);
+        if tangent.length_squared() > 0.0 {
+            Some(tangent.normalized())
+        } else {
+            None
+        }
+    };
+    match shape {
+        Shape::Plane(plane) => Some(plane.normal.create_coord_system().1),
+        Shape::Sphere(sphere) => around(Vector3::new(0.0, 1.0, 0.0), sphere.origin),
+        Shape::Cylinder(cylinder) => around(cylinder.axis, cylinder.base),
+        Shape::Cone(cone) => around(cone.axis, cone.base),
+        Shape::Disk(disk) => around(disk.normal, disk.center),
+        Shape::Torus(torus) => around(torus.axis, torus.center),
+        Shape::Quad(quad) => Some(quad.edge1.normalized()),
+        _ => None,
+    }
+}
+
+// The span of t in which a line is inside the box around the origin with the given half size,
+// between the largest entry into and the smallest exit from the slabs between opposite faces
+fn slabs(origin: Vector3, direction: Vector3, half_size: Vector3) -> Option<(f32, f32)> {
+    let (mut near, mut far) = (f32::NEG_INFINITY, f32::INFINITY);
+    for i in 0..3 {
+        let (o, d, h) = (origin.coord(i), direction.coord(i), half_size.coord(i));
+        if d == 0.0 {
+            if o.abs() > h {
+                return None;
+            }
+            continue;
+        }
+        let (t1, t2) = ((-h - o) / d, (h - o) / d);
+        near = near.max(t1.min(t2));
+        far = far.min(t1.max(t2));
+    }
+    if near > far {
+        return None;
+    }
+    Some((near, far))
+}
+
+// angle around the y axis, as a fraction of a turn
+fn azimuth(point: Vector3) -> f32 {
+    point.z.atan2(point.x) / (2.0 * PI) + 0.5
+}
+
+// disk of the given radius perpendicular to the y axis at height y, side is the sign of the
+// y coordinate of its normal
+fn intersect_cap(
+    origin: Vector3,
+    direction: Vector3,
+    y: f32,
+    radius: f32,
+    side: f32,
+) -> Option<LocalHit> {
+    if direction.y == 0.0 {
+        return None;
+    }
+    let t = (y - origin.y) / direction.y;
+    let p = origin + direction * t;
+    let distance = (p.x * p.x + p.z * p.z).sqrt();
+    if distance > radius {
+        return None;
+    }
+    Some((
+        t,
+        Vector3::new(0.0, side, 0.0),
+        (azimuth(p), distance / radius),
+    ))
 }
 
 impl Ray {
-    pub fn new(origin: Vector3, direction: Vector3) -> Self {
+    pub fn new(origin: Vector3, direction: Vector3, time: f32) -> Self {
         Self {
             origin: origin + direction * BIAS,
             direction,
+            time,
         }
     }
 
-    pub fn intersect_any(&self, objects: &Vec<Object>) -> Option<Intersection> {
-        let mut intersections = objects
-            .into_iter()
-            .filter_map(|object| self.intersect(&object))
-            .collect::<Vec<_>>();
-
-        intersections.sort_by(|a, b| a.t.partial_cmp(&b.t).unwrap_or(Ordering::Equal));
+    pub fn intersect_any<'a>(
+        &self,
+        objects: &'a [Object],
+        bvh: &ObjectBvh,
+    ) -> Option<Intersection<'a>> {
+        self.intersect_closest(objects, bvh)
+            .map(|(_, intersection)| intersection)
+    }
 
-        intersections.first().map(|i| Intersection {
-            t: i.t,
-            hit_point: i.hit_point,
-            hit_normal: i.hit_normal,
-            material: i.material,
-        })
+    // the closest intersection together with the index of the object that was hit, objects
+    // are found through the hierarchy built over them
+    pub fn intersect_closest<'a>(
+        &self,
+        objects: &'a [Object],
+        bvh: &ObjectBvh,
+    ) -> Option<(usize, Intersection<'a>)> {
+        let mut tests = 0;
+        let mut intersect = |index: usize| {
+            tests += 1;
+            self.intersect(&objects[index]).map(|i| (i.t, (index, i)))
+        };
+        let bounded = bvh
+            .bvh
+            .closest(self.origin, self.direction, |i| intersect(bvh.bounded[i]));
+        let closest = bvh
+            .unbounded
+            .iter()
+            .filter_map(|index| intersect(*index))
+            .chain(bounded)
+            .min_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        stats::add(Counter::IntersectionTests, tests);
+        closest.map(|(_, hit)| hit)
     }
 
     pub fn reflect(&self, point: Vector3, normal: Vector3) -> Self {
         let direction = self.direction.reflect(normal).normalized();
-        Self::new(point, direction)
+        Self::new(point, direction, self.time)
     }
 
-    pub fn random_ray(origin: Vector3) -> Self {
+    pub fn random_ray(origin: Vector3, sample: (f32, f32), time: f32) -> Self {
         Self {
             origin,
-            direction: Vector3::random_in_sphere(),
+            direction: Vector3::in_sphere(sample),
+            time,
         }
     }
 
-    pub fn random_ray_in_hemisphere(origin: Vector3, normal: Vector3) -> Self {
-        let random_vector = Vector3::random_in_hemisphere();
+    pub fn random_ray_in_hemisphere(
+        origin: Vector3,
+        normal: Vector3,
+        sample: (f32, f32),
+        time: f32,
+    ) -> Self {
+        let random_vector = Vector3::in_hemisphere(sample);
         let (nx, ny, nz) = normal.create_coord_system();
         let adjusted_vector = Vector3 {
             x: random_vector.x * nz.x + random_vector.y * nx.x + random_vector.z * ny.x,
             y: random_vector.x * nz.y + random_vector.y * nx.y + random_vector.z * ny.y,
             z: random_vector.x * nz.z + random_vector.y * nx.z + random_vector.z * ny.z,
         };
-        Self::new(origin, adjusted_vector)
+        Self::new(origin, adjusted_vector, time)
     }
 
-    fn intersect_plane(&self, plane: &Plane, material: &Material) -> Option<Intersection> {
+    fn intersect_plane<'a>(
+        &self,
+        plane: &Plane,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
         let Plane { position, normal } = *plane;
 
         let denominator = normal.inner_product(self.direction);
@@ -88,21 +365,34 @@ impl Ray {
         } else {
             normal * -1.0
         };
+        // distances along two directions in the plane, the plane repeats any texture
+        let (_, tangent, bitangent) = normal.create_coord_system();
+        let offset = hit_point - position;
         Some(Intersection {
             t,
             hit_point,
             hit_normal,
-            material: *material,
+            uv: (
+                offset.inner_product(tangent),
+                offset.inner_product(bitangent),
+            ),
+            tangent: None,
+            material,
         })
     }
 
-    fn intersect_sphere(&self, sphere: &Sphere, material: &Material) -> Option<Intersection> {
+    fn intersect_sphere<'a>(
+        &self,
+        sphere: &Sphere,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
         let Sphere { origin, radius } = sphere;
 
         let v = origin - self.origin;
 
         let tca = v.inner_product(self.direction);
-        if tca < 0.0 {
+        // pointing away from the center only hits from inside
+        if tca < 0.0 && v.length_squared() > radius * radius {
             return None;
         }
 
@@ -123,11 +413,27 @@ impl Ray {
             t,
             hit_point,
             hit_normal: normal,
-            material: *material,
+            uv: (azimuth(normal), normal.y.clamp(-1.0, 1.0).asin() / PI + 0.5),
+            tangent: None,
+            material,
         })
     }
 
-    fn intersect_triangle(&self, triangle: &Triangle, material: &Material) -> Option<Intersection> {
+    fn intersect_triangle<'a>(
+        &self,
+        triangle: &Triangle,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        self.triangle_line_hit(triangle, material)
+            .filter(|intersection| intersection.t >= 0.0)
+    }
+
+    // hit anywhere along the line of the ray
+    fn triangle_line_hit<'a>(
+        &self,
+        triangle: &Triangle,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
         let Triangle {
             vertex1,
             vertex2,
@@ -147,7 +453,7 @@ impl Ray {
 
         let t_vec = self.origin - vertex1;
         let u = t_vec.inner_product(p_vec) * inverse_determinant;
-        if u < 0.0 || u > 1.0 {
+        if !(0.0..=1.0).contains(&u) {
             return None;
         }
 
@@ -158,20 +464,22 @@ impl Ray {
         }
 
         let t = n2.inner_product(q_vec) * inverse_determinant;
-        if t < 0.0 {
-            return None;
-        }
-
         let hit_point = self.origin + self.direction * t;
         Some(Intersection {
             t,
             hit_point,
             hit_normal: n1.outer_product(n2).normalized(),
-            material: *material,
+            uv: (u, v),
+            tangent: None,
+            material,
         })
     }
 
-    fn intersect_pyramid(&self, pyramid: &Pyramid, material: &Material) -> Option<Intersection> {
+    fn intersect_pyramid<'a>(
+        &self,
+        pyramid: &Pyramid,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
         let Pyramid {
             vertex1,
             vertex2,
@@ -182,15 +490,15 @@ impl Ray {
             (
                 vertex4,
                 Triangle {
-                    vertex1: vertex1,
-                    vertex2: vertex2,
-                    vertex3: vertex3,
+                    vertex1,
+                    vertex2,
+                    vertex3,
                 },
             ),
             (
                 vertex2,
                 Triangle {
-                    vertex1: vertex1,
+                    vertex1,
                     vertex2: vertex3,
                     vertex3: vertex4,
                 },
@@ -200,14 +508,14 @@ impl Ray {
                 Triangle {
                     vertex1: vertex2,
                     vertex2: vertex4,
-                    vertex3: vertex3,
+                    vertex3,
                 },
             ),
             (
                 vertex3,
                 Triangle {
-                    vertex1: vertex1,
-                    vertex2: vertex2,
+                    vertex1,
+                    vertex2,
                     vertex3: vertex4,
                 },
             ),
@@ -226,6 +534,8 @@ impl Ray {
                 t,
                 hit_point,
                 hit_normal,
+                uv,
+                tangent: _,
                 material: _,
             } = intersection;
             let inner_dir = p - hit_point;
@@ -240,17 +550,552 @@ impl Ray {
                 t: *t,
                 hit_point: *hit_point,
                 hit_normal: normal,
-                material: *material,
+                uv: *uv,
+                tangent: None,
+                material,
+            }
+        })
+    }
+
+    // the closest hit in front of the ray, with the normal taken back out of the frame
+    fn closest_hit<'a>(
+        &self,
+        hits: impl IntoIterator<Item = LocalHit>,
+        frame: &LocalFrame,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        hits.into_iter()
+            .filter(|(t, _, _)| *t > 0.0)
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|hit| self.local_intersection(hit, frame, material))
+    }
+
+    fn local_intersection<'a>(
+        &self,
+        (t, normal, uv): LocalHit,
+        frame: &LocalFrame,
+        material: &'a Material,
+    ) -> Intersection<'a> {
+        Intersection {
+            t,
+            hit_point: self.origin + self.direction * t,
+            hit_normal: frame.to_world_vector(normal).normalized(),
+            uv,
+            tangent: None,
+            material,
+        }
+    }
+
+    // slabs between the faces of each axis, the ray is inside the box between the largest
+    // entry and the smallest exit
+    fn intersect_cuboid<'a>(
+        &self,
+        cuboid: &Cuboid,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        let (frame, hits) = self.cuboid_hits(cuboid);
+        self.closest_hit(hits, &frame, material)
+    }
+
+    fn cuboid_hits(&self, cuboid: &Cuboid) -> (LocalFrame, Vec<LocalHit>) {
+        let frame = LocalFrame {
+            origin: cuboid.center,
+            axes: cuboid.axes,
+        };
+        let (origin, direction) = (frame.point(self.origin), frame.vector(self.direction));
+        let size = cuboid.half_size;
+
+        let (near, far) = match slabs(origin, direction, size) {
+            Some(span) => span,
+            None => return (frame, vec![]),
+        };
+
+        let face = |t: f32| {
+            let p = origin + direction * t;
+            // the face is on the axis along which the point is relatively furthest out
+            let i = (0..3)
+                .max_by(|a, b| {
+                    let a = (p.coord(*a) / size.coord(*a)).abs();
+                    let b = (p.coord(*b) / size.coord(*b)).abs();
+                    a.total_cmp(&b)
+                })
+                .unwrap_or(0);
+            let (j, k) = ((i + 1) % 3, (i + 2) % 3);
+            let normal = Vector3::default().replace_coord(i, p.coord(i).signum());
+            let uv = (
+                (p.coord(j) / size.coord(j) + 1.0) / 2.0,
+                (p.coord(k) / size.coord(k) + 1.0) / 2.0,
+            );
+            (t, normal, uv)
+        };
+        let hits = vec![face(near), face(far)];
+        (frame, hits)
+    }
+
+    fn intersect_cylinder<'a>(
+        &self,
+        cylinder: &Cylinder,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        let (frame, hits) = self.cylinder_hits(cylinder);
+        self.closest_hit(hits, &frame, material)
+    }
+
+    fn cylinder_hits(&self, cylinder: &Cylinder) -> (LocalFrame, Vec<LocalHit>) {
+        let Cylinder {
+            base,
+            axis,
+            radius,
+            height,
+        } = *cylinder;
+        let frame = LocalFrame::around(base, axis);
+        let (o, d) = (frame.point(self.origin), frame.vector(self.direction));
+
+        let a = d.x * d.x + d.z * d.z;
+        let b = 2.0 * (o.x * d.x + o.z * d.z);
+        let c = o.x * o.x + o.z * o.z - radius * radius;
+        let mut hits = polynomial::solve_quadratic(a, b, c)
+            .into_iter()
+            .filter_map(|t| {
+                let p = o + d * t;
+                if !(0.0..=height).contains(&p.y) {
+                    return None;
+                }
+                let normal = Vector3::new(p.x, 0.0, p.z);
+                Some((t, normal, (azimuth(p), p.y / height)))
+            })
+            .collect::<Vec<_>>();
+        hits.extend(intersect_cap(o, d, 0.0, radius, -1.0));
+        hits.extend(intersect_cap(o, d, height, radius, 1.0));
+        (frame, hits)
+    }
+
+    // the side solves x^2 + z^2 = (k * (height - y))^2 with k the slope of the radius, which
+    // also describes the mirrored cone above the apex
+    fn intersect_cone<'a>(&self, cone: &Cone, material: &'a Material) -> Option<Intersection<'a>> {
+        let (frame, hits) = self.cone_hits(cone);
+        self.closest_hit(hits, &frame, material)
+    }
+
+    fn cone_hits(&self, cone: &Cone) -> (LocalFrame, Vec<LocalHit>) {
+        let Cone {
+            base,
+            axis,
+            radius,
+            height,
+        } = *cone;
+        let frame = LocalFrame::around(base, axis);
+        let (o, d) = (frame.point(self.origin), frame.vector(self.direction));
+
+        let k2 = (radius / height) * (radius / height);
+        let above = height - o.y;
+        let a = d.x * d.x + d.z * d.z - k2 * d.y * d.y;
+        let b = 2.0 * (o.x * d.x + o.z * d.z + k2 * above * d.y);
+        let c = o.x * o.x + o.z * o.z - k2 * above * above;
+        let mut hits = polynomial::solve_quadratic(a, b, c)
+            .into_iter()
+            .filter_map(|t| {
+                let p = o + d * t;
+                if !(0.0..=height).contains(&p.y) {
+                    return None;
+                }
+                let normal = Vector3::new(p.x, k2 * (height - p.y), p.z);
+                Some((t, normal, (azimuth(p), p.y / height)))
+            })
+            .collect::<Vec<_>>();
+        hits.extend(intersect_cap(o, d, 0.0, radius, -1.0));
+        (frame, hits)
+    }
+
+    // like the plane, the normal faces the ray
+    fn intersect_disk<'a>(&self, disk: &Disk, material: &'a Material) -> Option<Intersection<'a>> {
+        let frame = LocalFrame::around(disk.center, disk.normal);
+        let (o, d) = (frame.point(self.origin), frame.vector(self.direction));
+        let side = -d.y.signum();
+        self.closest_hit(
+            intersect_cap(o, d, 0.0, disk.radius, side),
+            &frame,
+            material,
+        )
+    }
+
+    // like the plane, the normal faces the ray
+    fn intersect_quad<'a>(&self, quad: &Quad, material: &'a Material) -> Option<Intersection<'a>> {
+        let Quad {
+            corner,
+            edge1,
+            edge2,
+        } = *quad;
+        let normal = edge1.outer_product(edge2);
+        let denominator = normal.inner_product(self.direction);
+        if denominator == 0.0 {
+            return None;
+        }
+        let t = (corner - self.origin).inner_product(normal) / denominator;
+        if t <= 0.0 {
+            return None;
+        }
+
+        // coordinates of the hit along the edges
+        let hit_point = self.origin + self.direction * t;
+        let offset = hit_point - corner;
+        let area = normal.length_squared();
+        let u = offset.outer_product(edge2).inner_product(normal) / area;
+        let v = edge1.outer_product(offset).inner_product(normal) / area;
+        if !(0.0..=1.0).contains(&u) || !(0.0..=1.0).contains(&v) {
+            return None;
+        }
+
+        let normal = normal.normalized();
+        Some(Intersection {
+            t,
+            hit_point,
+            hit_normal: if denominator < 0.0 {
+                normal
+            } else {
+                normal * -1.0
+            },
+            uv: (u, v),
+            tangent: None,
+            material,
+        })
+    }
+
+    // Solves (|p|^2 + R^2 - r^2)^2 = 4 * R^2 * (x^2 + z^2) along the ray. The quartic is solved
+    // from where the line of the ray enters the bounding sphere with a unit direction, which keeps the
+    // coefficients small.
+    fn intersect_torus<'a>(
+        &self,
+        torus: &Torus,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        let (frame, hits) = self.torus_hits(torus);
+        self.closest_hit(hits, &frame, material)
+    }
+
+    fn torus_hits(&self, torus: &Torus) -> (LocalFrame, Vec<LocalHit>) {
+        let Torus {
+            center,
+            axis,
+            major_radius,
+            minor_radius,
+        } = *torus;
+        let frame = LocalFrame::around(center, axis);
+        let (o, d) = (frame.point(self.origin), frame.vector(self.direction));
+        let length = d.length_squared().sqrt();
+        let d = d / length;
+
+        let bound = major_radius + minor_radius;
+        let b = o.inner_product(d);
+        let discriminant = b * b - (o.length_squared() - bound * bound);
+        if discriminant < 0.0 {
+            return (frame, vec![]);
+        }
+        let shift = -b - discriminant.sqrt();
+        let o = o + d * shift;
+
+        let [ox, oy, oz] = o.to_array().map(f64::from);
+        let [dx, dy, dz] = d.to_array().map(f64::from);
+        let (r2, s2) = (
+            f64::from(major_radius * major_radius),
+            f64::from(minor_radius * minor_radius),
+        );
+        let f = ox * dx + oy * dy + oz * dz;
+        let e = ox * ox + oy * oy + oz * oz + r2 - s2;
+        let roots = polynomial::solve_quartic(
+            4.0 * f,
+            2.0 * e + 4.0 * f * f - 4.0 * r2 * (dx * dx + dz * dz),
+            4.0 * f * e - 8.0 * r2 * (ox * dx + oz * dz),
+            e * e - 4.0 * r2 * (ox * ox + oz * oz),
+        );
+
+        let hits = roots
+            .into_iter()
+            .map(|t| {
+                let t = t as f32;
+                let p = o + d * t;
+                let ring = (p.x * p.x + p.z * p.z).sqrt();
+                // away from the closest point on the circle through the middle of the tube
+                let normal = Vector3::new(p.x, 0.0, p.z) * (1.0 - major_radius / ring)
+                    + Vector3::new(0.0, p.y, 0.0);
+                let v = p.y.atan2(ring - major_radius) / (2.0 * PI) + 0.5;
+                ((shift + t) / length, normal, (azimuth(p), v))
+            })
+            .collect();
+        (frame, hits)
+    }
+
+    fn intersect_shape<'a>(
+        &self,
+        shape: &Shape,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        let intersection = match shape {
+            Shape::Plane(plane) => self.intersect_plane(plane, material),
+            Shape::Sphere(sphere) => self.intersect_sphere(sphere, material),
+            Shape::Triangle(triangle) => self.intersect_triangle(triangle, material),
+            Shape::Pyramid(pyramid) => self.intersect_pyramid(pyramid, material),
+            Shape::Cuboid(cuboid) => self.intersect_cuboid(cuboid, material),
+            Shape::Cylinder(cylinder) => self.intersect_cylinder(cylinder, material),
+            Shape::Cone(cone) => self.intersect_cone(cone, material),
+            Shape::Disk(disk) => self.intersect_disk(disk, material),
+            Shape::Quad(quad) => self.intersect_quad(quad, material),
+            Shape::Torus(torus) => self.intersect_torus(torus, material),
+            Shape::Csg(csg) => self
+                .csg_crossings(csg, material)
+                .hits
+                .into_iter()
+                .find(|intersection| intersection.t > 0.0),
+            Shape::Sdf(sdf) => self.sdf_hits(sdf, material, true).into_iter().next(),
+            Shape::Mesh(mesh) => self.intersect_mesh(mesh, material),
+            Shape::Curves(curves) => self.intersect_curves(curves, material),
+        };
+        intersection.map(|mut intersection| {
+            if intersection.tangent.is_none() {
+                intersection.tangent = surface_tangent(shape, intersection.hit_point);
+            }
+            intersection
+        })
+    }
+
+    fn csg_crossings<'a>(&self, csg: &Csg, material: &'a Material) -> Crossings<'a> {
+        Crossings::combine(
+            csg.operation,
+            self.crossings(&csg.left, material),
+            self.crossings(&csg.right, material),
+            self.direction,
+        )
+    }
+
+    fn crossings<'a>(&self, shape: &Shape, material: &'a Material) -> Crossings<'a> {
+        let local = |(frame, hits): (LocalFrame, Vec<LocalHit>)| {
+            hits.into_iter()
+                .map(|hit| self.local_intersection(hit, &frame, material))
+                .collect::<Vec<_>>()
+        };
+        let hits = match shape {
+            Shape::Plane(plane) => return self.plane_crossings(plane, material),
+            Shape::Csg(csg) => return self.csg_crossings(csg, material),
+            Shape::Sphere(sphere) => self.sphere_hits(sphere, material),
+            Shape::Pyramid(pyramid) => self.pyramid_hits(pyramid, material),
+            Shape::Cuboid(cuboid) => local(self.cuboid_hits(cuboid)),
+            Shape::Cylinder(cylinder) => local(self.cylinder_hits(cylinder)),
+            Shape::Cone(cone) => local(self.cone_hits(cone)),
+            Shape::Torus(torus) => local(self.torus_hits(torus)),
+            Shape::Sdf(sdf) => self.sdf_hits(sdf, material, false),
+            Shape::Triangle(_)
+            | Shape::Disk(_)
+            | Shape::Quad(_)
+            | Shape::Mesh(_)
+            | Shape::Curves(_) => {
+                unreachable!("csg is only built from closed shapes")
             }
+        };
+        Crossings::bounded(hits, self.direction)
+    }
+
+    fn intersect_mesh<'a>(&self, mesh: &Mesh, material: &'a Material) -> Option<Intersection<'a>> {
+        let (t, normal, uv) = mesh.intersect(self.origin, self.direction)?;
+        Some(Intersection {
+            t,
+            hit_point: self.origin + self.direction * t,
+            hit_normal: normal,
+            uv,
+            tangent: None,
+            material,
+        })
+    }
+
+    fn intersect_curves<'a>(
+        &self,
+        curves: &Curves,
+        material: &'a Material,
+    ) -> Option<Intersection<'a>> {
+        let (t, normal, tangent, uv) = curves.intersect(self.origin, self.direction)?;
+        Some(Intersection {
+            t,
+            hit_point: self.origin + self.direction * t,
+            hit_normal: normal,
+            uv,
+            tangent: Some(tangent),
+            material,
         })
     }
 
-    fn intersect(&self, object: &Object) -> Option<Intersection> {
-        match &object.shape {
-            Shape::Plane(plane) => self.intersect_plane(&plane, &object.material),
-            Shape::Sphere(sphere) => self.intersect_sphere(&sphere, &object.material),
-            Shape::Triangle(triangle) => self.intersect_triangle(&triangle, &object.material),
-            Shape::Pyramid(pyramid) => self.intersect_pyramid(&pyramid, &object.material),
+    // Sphere tracing through the bounds, from the origin on for the closest hit and along the
+    // whole line otherwise. Steps of the distance divided by the Lipschitz bound cannot pass the
+    // surface, a change of sign between two steps is narrowed down by bisection.
+    fn sdf_hits<'a>(
+        &self,
+        sdf: &SdfShape,
+        material: &'a Material,
+        closest: bool,
+    ) -> Vec<Intersection<'a>> {
+        // padded so marching does not start on a surface touching the bounds
+        let (min, max) = sdf.bounds();
+        let center = (min + max) / 2.0;
+        let pad = Vector3::new(MIN_MARCH_STEP, MIN_MARCH_STEP, MIN_MARCH_STEP);
+        let half_size = (max - min) / 2.0 + pad;
+        let (near, far) = match slabs(self.origin - center, self.direction, half_size) {
+            Some(span) => span,
+            None => return vec![],
+        };
+        let near = if closest { near.max(0.0) } else { near };
+        if near > far {
+            return vec![];
+        }
+
+        let length = self.direction.length_squared().sqrt();
+        let distance = |t: f32| sdf.distance(self.origin + self.direction * t);
+        let mut hits = vec![];
+        let (mut t, mut d) = (near, distance(near));
+        for _ in 0..MAX_MARCH_STEPS {
+            if t >= far {
+                break;
+            }
+            let step = (d.abs() / sdf.lipschitz()).max(MIN_MARCH_STEP) / length;
+            let next = (t + step).min(far);
+            let next_d = distance(next);
+            if (d < 0.0) != (next_d < 0.0) {
+                let (mut before, mut after) = (t, next);
+                for _ in 0..BISECTION_STEPS {
+                    let middle = (before + after) / 2.0;
+                    if (distance(middle) < 0.0) == (d < 0.0) {
+                        before = middle;
+                    } else {
+                        after = middle;
+                    }
+                }
+                let t = (before + after) / 2.0;
+                let hit_point = self.origin + self.direction * t;
+                let normal = sdf.normal(hit_point);
+                hits.push(Intersection {
+                    t,
+                    hit_point,
+                    hit_normal: normal,
+                    uv: (azimuth(normal), normal.y.clamp(-1.0, 1.0).asin() / PI + 0.5),
+                    tangent: None,
+                    material,
+                });
+                if closest {
+                    break;
+                }
+            }
+            t = next;
+            d = next_d;
+        }
+        hits
+    }
+
+    // the half-space behind the normal of the plane
+    fn plane_crossings<'a>(&self, plane: &Plane, material: &'a Material) -> Crossings<'a> {
+        let Plane { position, normal } = *plane;
+        let denominator = normal.inner_product(self.direction);
+        if denominator == 0.0 {
+            return Crossings {
+                inside_before: (self.origin - position).inner_product(normal) < 0.0,
+                hits: vec![],
+            };
+        }
+        let t = (position - self.origin).inner_product(normal) / denominator;
+        let hit_point = self.origin + self.direction * t;
+        let (_, tangent, bitangent) = normal.create_coord_system();
+        let offset = hit_point - position;
+        Crossings {
+            inside_before: denominator > 0.0,
+            hits: vec![Intersection {
+                t,
+                hit_point,
+                hit_normal: normal.normalized(),
+                uv: (
+                    offset.inner_product(tangent),
+                    offset.inner_product(bitangent),
+                ),
+                tangent: None,
+                material,
+            }],
         }
     }
+
+    fn sphere_hits<'a>(&self, sphere: &Sphere, material: &'a Material) -> Vec<Intersection<'a>> {
+        let Sphere { origin, radius } = *sphere;
+        let v = self.origin - origin;
+        let a = self.direction.length_squared();
+        let b = 2.0 * v.inner_product(self.direction);
+        let c = v.length_squared() - radius * radius;
+        polynomial::solve_quadratic(a, b, c)
+            .into_iter()
+            .map(|t| {
+                let hit_point = self.origin + self.direction * t;
+                let normal = (hit_point - origin).normalized();
+                Intersection {
+                    t,
+                    hit_point,
+                    hit_normal: normal,
+                    uv: (azimuth(normal), normal.y.clamp(-1.0, 1.0).asin() / PI + 0.5),
+                    tangent: None,
+                    material,
+                }
+            })
+            .collect()
+    }
+
+    // the faces with their normals turned away from the vertex opposite to them
+    fn pyramid_hits<'a>(&self, pyramid: &Pyramid, material: &'a Material) -> Vec<Intersection<'a>> {
+        let Pyramid {
+            vertex1,
+            vertex2,
+            vertex3,
+            vertex4,
+        } = *pyramid;
+        [
+            (vertex1, vertex2, vertex3, vertex4),
+            (vertex1, vertex3, vertex4, vertex2),
+            (vertex2, vertex4, vertex3, vertex1),
+            (vertex1, vertex2, vertex4, vertex3),
+        ]
+        .iter()
+        .filter_map(|&(vertex1, vertex2, vertex3, opposite)| {
+            let triangle = Triangle {
+                vertex1,
+                vertex2,
+                vertex3,
+            };
+            let mut hit = self.triangle_line_hit(&triangle, material)?;
+            if hit.hit_normal.inner_product(opposite - vertex1) > 0.0 {
+                hit.hit_normal *= -1.0;
+            }
+            Some(hit)
+        })
+        .collect()
+    }
+
+    // a moving object is intersected by taking the ray into the space of its shape at the time
+    // of the ray, the direction keeps its length there so t is scaled along
+    pub fn intersect<'a>(&self, object: &'a Object) -> Option<Intersection<'a>> {
+        let transform = match object.motion.transform(self.time) {
+            Some(transform) => transform,
+            None => return self.intersect_shape(&object.shape, &object.material),
+        };
+        let local = Ray {
+            origin: transform.inverse_point(self.origin),
+            direction: transform.inverse_vector(self.direction),
+            time: self.time,
+        };
+        local
+            .intersect_shape(&object.shape, &object.material)
+            .map(|intersection| {
+                let t = intersection.t * transform.scale;
+                Intersection {
+                    t,
+                    hit_point: self.origin + self.direction * t,
+                    hit_normal: transform.apply_vector(intersection.hit_normal),
+                    uv: intersection.uv,
+                    tangent: intersection
+                        .tangent
+                        .map(|tangent| transform.apply_vector(tangent).normalized()),
+                    material: intersection.material,
+                }
+            })
+    }
 }
@@ -0,0 +1,379 @@
+use crate::adaptive::{AdaptiveSampling, PixelStatistics};
+use crate::aov::{AovBuffers, AovTile, Radiance};
+use crate::camera::Camera;
+use crate::checkpoint::{write_checkpoint, write_checkpoint_photons};
+use crate::film::{Film, FilmTile, PixelFilter};
+use crate::photon_cache::PhotonMapKind;
+use crate::photon_map::PhotonMap;
+use crate::ppm::PPM;
+use crate::sampler::SamplerKind;
+use crate::scene::Scene;
+use crate::stats::{self, Counter};
+use crate::tiles::{create_tiles, Tile, TileOrder};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct RenderSettings {
+    pub width: usize,
+    pub height: usize,
+    // average number of samples per pixel, the adaptive sampler spends this budget where it is needed
+    pub samples_per_pixel: usize,
+    pub adaptive_sampling: AdaptiveSampling,
+    pub sampler: SamplerKind,
+    pub seed: u32,
+    pub pixel_filter: PixelFilter,
+    pub filter_radius: f32,
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    // also render the radiance components and surface passes, see AovBuffers
+    pub aovs: bool,
+    // the partially rendered image is written here at the end of every pass and whenever
+    // preview_interval has passed since the last write
+    pub preview_file: Option<String>,
+    pub preview_interval: Duration,
+    // the render progress is written here every checkpoint_interval, see Checkpoint
+    pub checkpoint_file: Option<String>,
+    pub checkpoint_interval: Duration,
+}
+
+// covers everything that influences the rendered image, output files and intervals are left out
+impl Hash for RenderSettings {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.width.hash(state);
+        self.height.hash(state);
+        self.samples_per_pixel.hash(state);
+        self.adaptive_sampling.hash(state);
+        self.sampler.hash(state);
+        self.seed.hash(state);
+        self.pixel_filter.hash(state);
+        self.filter_radius.to_bits().hash(state);
+        self.tile_size.hash(state);
+        self.tile_order.hash(state);
+        self.aovs.hash(state);
+    }
+}
+
+// Accumulated state of a render. The tiles of a round are merged into the film in tile order,
+// whichever thread finishes first, so the progress is fully described by the current round and
+// the number of merged tiles. Samplers are deterministic given the pixel and sample index, the
+// sample counts in statistics take the place of random number generator state.
+#[derive(Clone)]
+pub struct RenderProgress {
+    pub round: usize,
+    pub budget: usize,
+    // samples per pixel of the current round, empty between rounds
+    pub allocation: Vec<u32>,
+    pub tiles_done: usize,
+    pub film: Film,
+    pub statistics: Vec<PixelStatistics>,
+    pub aovs: Option<AovBuffers>,
+}
+
+impl RenderProgress {
+    pub fn new(settings: &RenderSettings) -> Self {
+        let RenderSettings { width, height, .. } = *settings;
+        Self {
+            round: 0,
+            budget: settings.samples_per_pixel * width * height,
+            allocation: vec![],
+            tiles_done: 0,
+            film: Film::new(width, height, settings.pixel_filter, settings.filter_radius),
+            statistics: vec![PixelStatistics::default(); width * height],
+            aovs: if settings.aovs {
+                Some(AovBuffers::new(
+                    width,
+                    height,
+                    settings.pixel_filter,
+                    settings.filter_radius,
+                ))
+            } else {
+                None
+            },
+        }
+    }
+}
+
+pub struct RenderResult {
+    pub film: Film,
+    pub statistics: Vec<PixelStatistics>,
+    pub aovs: Option<AovBuffers>,
+}
+
+struct TileResult {
+    film: FilmTile,
+    statistics: Vec<PixelStatistics>,
+    aovs: Option<AovTile>,
+}
+
+struct RenderState {
+    progress: RenderProgress,
+    // tiles that finished before all tiles in front of them
+    pending: BTreeMap<usize, TileResult>,
+    last_preview: Instant,
+    last_checkpoint: Instant,
+    // a thread is writing a checkpoint, the others do not start another one
+    writing_checkpoint: bool,
+    // the photon maps next to the checkpoint are current
+    photons_written: bool,
+}
+
+// Renders or continues to render progress. Checkpoints are written under checkpoint_key, which
+// should identify the scene, camera, photon maps and settings.
+pub fn render(
+    settings: &RenderSettings,
+    camera: &Camera,
+    scene: &Scene,
+    photon_map_global: &PhotonMap,
+    photon_map_caustic: &PhotonMap,
+    progress: RenderProgress,
+    checkpoint_key: u64,
+) -> RenderResult {
+    let tiles = create_tiles(
+        settings.width,
+        settings.height,
+        settings.tile_size,
+        settings.tile_order,
+    );
+    let state = Mutex::new(RenderState {
+        progress,
+        pending: BTreeMap::new(),
+        last_preview: Instant::now(),
+        last_checkpoint: Instant::now(),
+        writing_checkpoint: false,
+        photons_written: false,
+    });
+
+    loop {
+        let first_tile = {
+            let mut state = state.lock().unwrap();
+            let progress = &mut state.progress;
+            if progress.allocation.is_empty() {
+                let allocation = settings.adaptive_sampling.allocate(
+                    &progress.statistics,
+                    progress.budget,
+                    progress.round,
+                );
+                let round_samples: usize = allocation.iter().map(|a| *a as usize).sum();
+                if round_samples == 0 {
+                    break;
+                }
+                progress.budget = progress.budget.saturating_sub(round_samples);
+                progress.allocation = allocation;
+                progress.tiles_done = 0;
+            }
+            let round_samples: usize = progress.allocation.iter().map(|a| *a as usize).sum();
+            println!("Round {}: {} samples", progress.round + 1, round_samples);
+            progress.tiles_done
+        };
+
+        // tiles are handed out in order from a shared counter, so the tile order is respected
+        let next_tile = AtomicUsize::new(first_tile);
+        let completed = AtomicUsize::new(first_tile);
+        (0..rayon::current_num_threads())
+            .into_par_iter()
+            .for_each(|_| loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                if index >= tiles.len() {
+                    break;
+                }
+                render_tile(
+                    settings,
+                    camera,
+                    scene,
+                    (photon_map_global, photon_map_caustic),
+                    &tiles,
+                    index,
+                    &state,
+                    checkpoint_key,
+                );
+                let done = completed.fetch_add(1, Ordering::Relaxed) + 1;
+                println!("{:.2}%", 100.0 * (done as f32) / (tiles.len() as f32));
+            });
+
+        let preview = {
+            let mut state = state.lock().unwrap();
+            state.progress.round += 1;
+            state.progress.allocation.clear();
+            take_preview(settings, &mut state)
+        };
+        write_preview(settings, preview);
+    }
+
+    let progress = state.into_inner().unwrap().progress;
+    RenderResult {
+        film: progress.film,
+        statistics: progress.statistics,
+        aovs: progress.aovs,
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn render_tile(
+    settings: &RenderSettings,
+    camera: &Camera,
+    scene: &Scene,
+    (photon_map_global, photon_map_caustic): (&PhotonMap, &PhotonMap),
+    tiles: &[Tile],
+    index: usize,
+    state: &Mutex<RenderState>,
+    checkpoint_key: u64,
+) {
+    let RenderSettings { width, height, .. } = *settings;
+    let tile = &tiles[index];
+    let tile_pixels = |y: usize| y * width + tile.x0..y * width + tile.x1;
+    let (mut film_tile, mut statistics, mut aov_tile, allocation) = {
+        let state = state.lock().unwrap();
+        let progress = &state.progress;
+        let statistics = (tile.y0..tile.y1)
+            .flat_map(|y| progress.statistics[tile_pixels(y)].to_vec())
+            .collect::<Vec<_>>();
+        let allocation = (tile.y0..tile.y1)
+            .flat_map(|y| progress.allocation[tile_pixels(y)].to_vec())
+            .collect::<Vec<_>>();
+        let aov_tile = progress
+            .aovs
+            .as_ref()
+            .map(|aovs| aovs.tile((tile.x0, tile.y0), (tile.x1, tile.y1)));
+        (
+            progress.film.tile((tile.x0, tile.y0), (tile.x1, tile.y1)),
+            statistics,
+            aov_tile,
+            allocation,
+        )
+    };
+
+    let mut sampler = settings
+        .sampler
+        .create(settings.adaptive_sampling.min_samples, settings.seed);
+    let tile_width = tile.x1 - tile.x0;
+    for (i, pixel) in statistics.iter_mut().enumerate() {
+        let x = tile.x0 + i % tile_width;
+        let y = tile.y0 + i / tile_width;
+        for _ in 0..allocation[i] {
+            sampler.start_sample((x as u32, y as u32), pixel.count());
+            let (ra, rb) = if settings.adaptive_sampling.max_samples == 1 {
+                sampler.next_2d();
+                (0.0, 0.0)
+            } else {
+                let (u, v) = sampler.next_2d();
+                (u - 0.5, v - 0.5)
+            };
+            let a = (x as f32 + ra) / (width as f32);
+            let b = (y as f32 + rb) / (height as f32);
+            // positions the camera does not see, like outside of a fisheye circle, stay black
+            let lens_sample = sampler.next_2d();
+            let ray = camera.create_ray(true, a, b, lens_sample, sampler.next_1d());
+            let (radiance, surface) = match &ray {
+                Some((ray, weight)) => {
+                    stats::increment(Counter::CameraRays);
+                    let (radiance, surface) = scene.trace_surface(
+                        photon_map_global,
+                        photon_map_caustic,
+                        &mut *sampler,
+                        ray,
+                        0,
+                    );
+                    (radiance * *weight, surface)
+                }
+                None => (Radiance::default(), None),
+            };
+            let color = radiance.total();
+            let position = (x as f32 + 0.5 + ra, y as f32 + 0.5 + rb);
+            pixel.add(color);
+            film_tile.add_sample(position, color);
+            if let Some(aov_tile) = &mut aov_tile {
+                aov_tile.add_sample(i, position, &radiance, surface);
+            }
+        }
+    }
+
+    stats::flush();
+
+    let (preview, checkpoint) = {
+        let mut guard = state.lock().unwrap();
+        let state = &mut *guard;
+        state.pending.insert(
+            index,
+            TileResult {
+                film: film_tile,
+                statistics,
+                aovs: aov_tile,
+            },
+        );
+        // merging in tile order keeps the floating point sums of overlapping tiles independent
+        // of thread timing
+        while let Some(result) = state.pending.remove(&state.progress.tiles_done) {
+            let progress = &mut state.progress;
+            let tile = &tiles[progress.tiles_done];
+            let tile_width = tile.x1 - tile.x0;
+            for (i, pixel) in result.statistics.into_iter().enumerate() {
+                let y = tile.y0 + i / tile_width;
+                progress.statistics[y * width + tile.x0 + i % tile_width] = pixel;
+            }
+            progress.film.merge_tile(&result.film);
+            if let (Some(aovs), Some(aov_tile)) = (&mut progress.aovs, &result.aovs) {
+                aovs.merge_tile(aov_tile);
+            }
+            progress.tiles_done += 1;
+        }
+
+        let preview = if state.last_preview.elapsed() >= settings.preview_interval {
+            take_preview(settings, state)
+        } else {
+            None
+        };
+        let checkpoint = match &settings.checkpoint_file {
+            Some(file_name)
+                if !state.writing_checkpoint
+                    && state.last_checkpoint.elapsed() >= settings.checkpoint_interval =>
+            {
+                state.writing_checkpoint = true;
+                Some((file_name, state.progress.clone(), !state.photons_written))
+            }
+            _ => None,
+        };
+        (preview, checkpoint)
+    };
+    // the lock is released, the other threads go on merging tiles while the files are written
+    write_preview(settings, preview);
+
+    if let Some((file_name, progress, write_photons)) = checkpoint {
+        println!("Writing checkpoint to {}", file_name);
+        let photon_maps = [
+            (PhotonMapKind::Global, photon_map_global),
+            (PhotonMapKind::Caustic, photon_map_caustic),
+        ];
+        let mut result = Ok(());
+        if write_photons {
+            result = write_checkpoint_photons(file_name, checkpoint_key, &photon_maps);
+        }
+        let photons_written = result.is_ok();
+        let result = result.and_then(|_| write_checkpoint(file_name, checkpoint_key, &progress));
+        if let Err(e) = result {
+            println!("Could not write checkpoint to {}: {}", file_name, e);
+        }
+        let mut state = state.lock().unwrap();
+        state.writing_checkpoint = false;
+        state.photons_written = photons_written;
+        state.last_checkpoint = Instant::now();
+    }
+}
+
+// the preview image of the current progress, taken under the lock and written after it
+fn take_preview(settings: &RenderSettings, state: &mut RenderState) -> Option<PPM> {
+    state.last_preview = Instant::now();
+    let file_name = settings.preview_file.as_ref()?;
+    Some(state.progress.film.to_ppm(file_name, 0.0))
+}
+
+fn write_preview(settings: &RenderSettings, preview: Option<PPM>) {
+    if let (Some(file_name), Some(ppm)) = (&settings.preview_file, preview) {
+        if let Err(e) = ppm.write_file() {
+            println!("Could not write preview to {}: {}", file_name, e);
+        }
+    }
+}
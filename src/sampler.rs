@@ -0,0 +1,338 @@
+// Samplers hand out the random numbers of a single sample one dimension at a time. Every
+// sample starts with start_sample, after which the renderer always consumes the dimensions
+// in the same order (pixel jitter, lens position, light and BRDF sampling) so that
+// low-discrepancy samplers stratify each of them. All samplers are deterministic given
+// their seed, the pixel and the sample index.
+pub trait Sampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32);
+    fn next_1d(&mut self) -> f32;
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let u = self.next_1d();
+        (u, self.next_1d())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum SamplerKind {
+    Independent,
+    Stratified,
+    Halton,
+    Sobol,
+}
+
+impl SamplerKind {
+    // samples_per_pixel is only used by the stratified sampler to size its strata
+    pub fn create(&self, samples_per_pixel: u32, seed: u32) -> Box<dyn Sampler + Send> {
+        match self {
+            SamplerKind::Independent => Box::new(IndependentSampler::new(seed)),
+            SamplerKind::Stratified => Box::new(StratifiedSampler::new(samples_per_pixel, seed)),
+            SamplerKind::Halton => Box::new(HaltonSampler::new(seed)),
+            SamplerKind::Sobol => Box::new(SobolSampler::new(seed)),
+        }
+    }
+}
+
+const ONE_MINUS_EPSILON: f32 = 1.0 - f32::EPSILON / 2.0;
+
+fn mix(mut x: u32) -> u32 {
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x7feb352d);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x846ca68b);
+    x ^= x >> 16;
+    x
+}
+
+fn hash(values: &[u32]) -> u32 {
+    values
+        .iter()
+        .fold(0x9e3779b9, |h, v| mix(h ^ mix(v.wrapping_add(0x632be5ab))))
+}
+
+fn to_float(x: u32) -> f32 {
+    ((x >> 8) as f32) * (1.0 / 16777216.0)
+}
+
+// random permutation of 0..length without any storage (Kensler, "Correlated Multi-Jittered
+// Sampling")
+fn permutation_element(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            break;
+        }
+    }
+    (i.wrapping_add(seed)) % length
+}
+
+struct SampleState {
+    seed: u32,
+    pixel_seed: u32,
+    index: u32,
+    dimension: u32,
+}
+
+impl SampleState {
+    fn new(seed: u32) -> Self {
+        Self {
+            seed,
+            pixel_seed: seed,
+            index: 0,
+            dimension: 0,
+        }
+    }
+
+    fn start(&mut self, pixel: (u32, u32), index: u32) {
+        self.pixel_seed = hash(&[self.seed, pixel.0, pixel.1]);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    fn next_dimension(&mut self) -> u32 {
+        self.dimension += 1;
+        self.dimension - 1
+    }
+
+    fn random(&self, dimension: u32) -> f32 {
+        to_float(hash(&[self.pixel_seed, self.index, dimension]))
+    }
+}
+
+pub struct IndependentSampler {
+    state: SampleState,
+}
+
+impl IndependentSampler {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            state: SampleState::new(seed),
+        }
+    }
+}
+
+impl Sampler for IndependentSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        self.state.random(dimension)
+    }
+}
+
+// Jittered strata per dimension, shuffled independently per dimension. Sample indices past
+// samples_per_pixel start a new, differently shuffled, set of strata.
+pub struct StratifiedSampler {
+    state: SampleState,
+    samples_per_pixel: u32,
+}
+
+impl StratifiedSampler {
+    pub fn new(samples_per_pixel: u32, seed: u32) -> Self {
+        Self {
+            state: SampleState::new(seed),
+            samples_per_pixel: samples_per_pixel.max(1),
+        }
+    }
+
+    fn stratum(&self, dimension: u32, count: u32) -> u32 {
+        let n = self.samples_per_pixel;
+        let seed = hash(&[self.state.pixel_seed, dimension, self.state.index / n]);
+        permutation_element(self.state.index % n, count, seed)
+    }
+}
+
+impl Sampler for StratifiedSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        let stratum = self.stratum(dimension, self.samples_per_pixel);
+        ((stratum as f32 + self.state.random(dimension)) / (self.samples_per_pixel as f32))
+            .min(ONE_MINUS_EPSILON)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let dimension = self.state.next_dimension();
+        self.state.next_dimension();
+        let columns = (self.samples_per_pixel as f32).sqrt().ceil() as u32;
+        let rows = self.samples_per_pixel.div_ceil(columns);
+        let stratum = self.stratum(dimension, columns * rows);
+        let u = (((stratum % columns) as f32 + self.state.random(dimension)) / columns as f32)
+            .min(ONE_MINUS_EPSILON);
+        let v = (((stratum / columns) as f32 + self.state.random(dimension + 1)) / rows as f32)
+            .min(ONE_MINUS_EPSILON);
+        (u, v)
+    }
+}
+
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53, 59, 61, 67, 71, 73, 79, 83, 89, 97,
+    101, 103, 107, 109, 113, 127, 131,
+];
+
+// Halton sequence with per pixel random digit scrambling, every digit is shifted by an
+// amount that depends on the digits before it. Dimensions beyond the prime table fall back
+// to independent random numbers.
+pub struct HaltonSampler {
+    state: SampleState,
+}
+
+impl HaltonSampler {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            state: SampleState::new(seed),
+        }
+    }
+
+    fn scrambled_radical_inverse(&self, dimension: u32) -> f32 {
+        let base = PRIMES[dimension as usize];
+        let inverse_base = 1.0 / (base as f64);
+        let mut index = self.state.index;
+        let mut weight = inverse_base;
+        let mut prefix = hash(&[self.state.pixel_seed, dimension]);
+        let mut result = 0.0;
+        // keep scrambling after the index runs out of digits, down to float precision
+        while weight > 1e-8 {
+            let digit = index % base;
+            index /= base;
+            let scrambled = (digit + prefix % base) % base;
+            result += (scrambled as f64) * weight;
+            weight *= inverse_base;
+            prefix = mix(prefix ^ digit.wrapping_add(0x9e3779b9));
+        }
+        (result as f32).min(ONE_MINUS_EPSILON)
+    }
+}
+
+impl Sampler for HaltonSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        if (dimension as usize) < PRIMES.len() {
+            self.scrambled_radical_inverse(dimension)
+        } else {
+            self.state.random(dimension)
+        }
+    }
+}
+
+// direction numbers of the first four Sobol dimensions (Joe and Kuo), the primitive
+// polynomial of each dimension is given by its degree s and coefficients a and m
+const fn sobol_directions() -> [[u32; 32]; 4] {
+    let polynomials: [(usize, u32, [u32; 3]); 4] = [
+        (0, 0, [0, 0, 0]),
+        (1, 0, [1, 0, 0]),
+        (2, 1, [1, 3, 0]),
+        (3, 1, [1, 3, 1]),
+    ];
+    let mut directions = [[0; 32]; 4];
+    let mut dimension = 0;
+    while dimension < 4 {
+        let (s, a, m) = polynomials[dimension];
+        let mut i = 0;
+        while i < 32 {
+            directions[dimension][i] = if s == 0 {
+                1 << (31 - i)
+            } else if i < s {
+                m[i] << (31 - i)
+            } else {
+                let mut value = directions[dimension][i - s] ^ (directions[dimension][i - s] >> s);
+                let mut k = 1;
+                while k < s {
+                    if (a >> (s - 1 - k)) & 1 == 1 {
+                        value ^= directions[dimension][i - k];
+                    }
+                    k += 1;
+                }
+                value
+            };
+            i += 1;
+        }
+        dimension += 1;
+    }
+    directions
+}
+
+const SOBOL_DIRECTIONS: [[u32; 32]; 4] = sobol_directions();
+
+fn sobol(index: u32, dimension: usize) -> u32 {
+    let mut result = 0;
+    for (bit, direction) in SOBOL_DIRECTIONS[dimension].iter().enumerate() {
+        if (index >> bit) & 1 == 1 {
+            result ^= direction;
+        }
+    }
+    result
+}
+
+fn nested_uniform_scramble(x: u32, seed: u32) -> u32 {
+    // Laine-Karras style permutation on the reversed bits
+    let mut x = x.reverse_bits();
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x.reverse_bits()
+}
+
+// Owen-scrambled Sobol sequence with hash based scrambling (Burley, "Practical Hash-based
+// Owen Scrambling"). Dimensions are padded in groups of four, every group shuffles the
+// sample index with its own seed so the groups are decorrelated.
+pub struct SobolSampler {
+    state: SampleState,
+}
+
+impl SobolSampler {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            state: SampleState::new(seed),
+        }
+    }
+}
+
+impl Sampler for SobolSampler {
+    fn start_sample(&mut self, pixel: (u32, u32), index: u32) {
+        self.state.start(pixel, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.next_dimension();
+        let group_seed = hash(&[self.state.pixel_seed, dimension / 4]);
+        let index = nested_uniform_scramble(self.state.index, group_seed);
+        let within_group = (dimension % 4) as usize;
+        let seed = hash(&[group_seed, within_group as u32]);
+        to_float(nested_uniform_scramble(sobol(index, within_group), seed))
+    }
+}
@@ -1,49 +1,129 @@
-use crate::material::{Color, Material};
+use crate::aov::{Radiance, SurfaceSample};
+use crate::brdf::{self, Microfacet};
+use crate::hair::HairScattering;
+use crate::material::{Coat, Color, Material, Shading, Surface};
+use crate::medium::Exit;
+use crate::motion::{Motion, Shutter};
 use crate::objects::{Light, Object};
-use crate::ray::{Intersection, Ray};
+use crate::photon_map::{Filter, Photon, PhotonMap, RadianceEstimate};
+use crate::projection_map::ProjectionMap;
+use crate::ray::{Intersection, ObjectBvh, Ray};
+use crate::sampler::Sampler;
+use crate::stats::{self, Counter};
 use crate::vector3::Vector3;
-use rand::distributions::WeightedIndex;
-use rand::prelude::*;
-use kdtree::KdTree;
-use kdtree::distance::squared_euclidean;
-use core::f32::consts::PI;
+use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
 
 const MAX_DEPTH: u8 = 6;
-const N_PHOTON_RADIANCE: usize = 400;
 
 pub struct Scene {
     objects: Vec<Object>,
+    bvh: ObjectBvh,
     lights: Vec<Light>,
+    global_estimate: RadianceEstimate,
+    caustic_estimate: RadianceEstimate,
+    // per object, objects with equal materials share an id
+    material_ids: Vec<u32>,
 }
 
-#[derive(PartialEq)]
+// only the inputs of the photon pass are hashed, so cached photon maps survive changes to the
+// radiance estimates
+impl Hash for Scene {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.objects.hash(state);
+        self.lights.hash(state);
+    }
+}
+
+// The global pass stores photons whose path has had a diffuse bounce, also when specular bounces
+// followed it, the caustic pass stores photons that arrive after one or more specular bounces
+// straight from the light.
+#[derive(Copy, Clone, PartialEq)]
+pub enum PhotonPass {
+    Global,
+    Caustic,
+}
+
+#[derive(Copy, Clone, PartialEq)]
 pub enum BounceType {
     NONE,
     DIFFUSE,
     SPECULAR,
 }
 
-#[derive(Debug)]
-pub struct Photon {
-    position: Vector3,
-    direction: Vector3,
-    power: Color,
-}
-
 impl Scene {
-    pub fn new(objects: Vec<Object>, lights: Vec<Light>) -> Self {
-        Self { objects, lights }
+    // the objects are placed wherever they move while the shutter is open, rays have to be
+    // within it
+    pub fn new(objects: Vec<Object>, lights: Vec<Light>, shutter: &Shutter) -> Self {
+        assert!(
+            objects
+                .iter()
+                .all(|o| o.shape.is_closed() || !o.material.has_medium()),
+            "a medium needs a shape that encloses a solid"
+        );
+        let mut materials: Vec<Material> = vec![];
+        let material_ids = objects
+            .iter()
+            .map(|object| {
+                let index = match materials.iter().position(|m| *m == object.material) {
+                    Some(index) => index,
+                    None => {
+                        materials.push(object.material.clone());
+                        materials.len() - 1
+                    }
+                };
+                index as u32 + 1
+            })
+            .collect();
+        Self {
+            material_ids,
+            bvh: ObjectBvh::new(&objects, shutter),
+            objects,
+            lights,
+            global_estimate: RadianceEstimate::default(),
+            caustic_estimate: RadianceEstimate::default(),
+        }
+    }
+
+    pub fn with_radiance_estimates(
+        self,
+        global_estimate: RadianceEstimate,
+        caustic_estimate: RadianceEstimate,
+    ) -> Self {
+        // a flattening below 1 would stretch the search along the normal instead
+        assert!(global_estimate.disc_flattening >= 1.0);
+        assert!(caustic_estimate.disc_flattening >= 1.0);
+        // a cone constant below 1 cuts the cone off inside the search radius, and at 2/3 or
+        // below its normalization is no longer positive
+        assert!(!matches!(global_estimate.filter, Filter::Cone(k) if k < 1.0));
+        assert!(!matches!(caustic_estimate.filter, Filter::Cone(k) if k < 1.0));
+        Self {
+            global_estimate,
+            caustic_estimate,
+            ..self
+        }
+    }
+
+    // whether anything moves while a shutter is open
+    pub fn has_motion(&self) -> bool {
+        self.objects
+            .iter()
+            .any(|o| !matches!(o.motion, Motion::Static))
     }
 
     fn direct_illumination(
         &self,
         ray: &Ray,
         intersection: &Intersection,
-        material: &Material,
+        shading: &Shading,
     ) -> (Color, Color) {
         // ambient light
         let mut total_diffuse_color = Color::black();
         let mut total_specular_color = Color::black();
+        let material = &shading.surface;
+        let hair = hair_scattering(ray, intersection, material);
+        let lobe = glossy_lobe(ray, intersection, material);
+        let normal = facing_normal(ray, intersection);
 
         for Light {
             position,
@@ -52,19 +132,43 @@ impl Scene {
         } in &self.lights
         {
             let light_dir = (position - intersection.hit_point).normalized();
-            let r = Ray::new(intersection.hit_point, light_dir);
-            let int = r.intersect_any(&self.objects);
-            if let Some(i) = int {
-                if (i.hit_point - intersection.hit_point).length_squared()
-                    < (position - intersection.hit_point).length_squared()
-                {
-                    continue;
-                }
+            if !self.unshadowed(intersection.hit_point, *position, ray.time) {
+                continue;
+            }
+            if let Some((scattering, axes)) = &hair {
+                let wo = to_frame(axes, ray.direction * -1.0).normalized();
+                // lights are scaled so a white diffuse surface facing one reflects its color
+                total_diffuse_color +=
+                    color * scattering.evaluate(wo, to_frame(axes, light_dir)) * PI;
+                continue;
             }
+            let flattening = shading.subsurface.map_or(1.0, |subsurface| {
+                let ratio = brdf::flattening(
+                    subsurface.roughness,
+                    normal,
+                    ray.direction * -1.0,
+                    light_dir,
+                );
+                1.0 + (ratio - 1.0) * subsurface.amount
+            });
             // do something with attenuation
             total_diffuse_color += color
                 * material.diffuse_color
-                * intersection.hit_normal.inner_product(light_dir).max(0.0);
+                * intersection.hit_normal.inner_product(light_dir).max(0.0)
+                * flattening;
+            if let Some(sheen) = &shading.sheen {
+                total_specular_color += color
+                    * sheen.color
+                    * brdf::sheen(sheen.roughness, normal, ray.direction * -1.0, light_dir)
+                    * PI;
+            }
+            if let Some(lobe) = &lobe {
+                total_specular_color += color
+                    * material.reflect_color
+                    * lobe.evaluate(ray.direction * -1.0, light_dir)
+                    * PI;
+                continue;
+            }
             total_specular_color += color
                 * (-(light_dir * -1.0)
                     .reflect(intersection.hit_normal)
@@ -73,314 +177,818 @@ impl Scene {
                 .powf(material.specular_exponent);
         }
 
-        return (total_diffuse_color, total_specular_color);
+        (total_diffuse_color, total_specular_color)
     }
 
-    fn global_illumination(
-        &self,
-        photon_map: &KdTree<f32, Photon, [f32; 3]>,
-        hit_point: Vector3,
-        hit_normal: Vector3,
-    ) -> Color {
-        let mut result = Color::black();
-        let res = photon_map.nearest(&hit_point.to_array(), N_PHOTON_RADIANCE, &squared_euclidean).unwrap();
-
-        if res.len() == 0 {
-            return result;
+    // whether nothing lies between the point and the light
+    fn unshadowed(&self, point: Vector3, light: Vector3, time: f32) -> bool {
+        let r = Ray::new(point, (light - point).normalized(), time);
+        stats::increment(Counter::ShadowRays);
+        match r.intersect_any(&self.objects, &self.bvh) {
+            Some(i) => (i.hit_point - point).length_squared() >= (light - point).length_squared(),
+            None => true,
         }
-        let mut max_distance_squared = 0.0;
-        for i in 0..res.len() {
-            let photon = res[i].1;
-            result += photon.power * hit_normal.inner_product(photon.direction).max(0.0);
-            if res[i].0 > max_distance_squared {
-                max_distance_squared = res[i].0;
+    }
+
+    // The reflection off a coat towards the viewer, and the color that the layers below are
+    // seen in through it. Both follow the Fresnel reflectance towards the viewer.
+    #[allow(clippy::too_many_arguments)]
+    fn coat_radiance(
+        &self,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        sampler: &mut dyn Sampler,
+        ray: &Ray,
+        intersection: &Intersection,
+        coat: &Coat,
+        depth: u8,
+    ) -> (Radiance, Color) {
+        let normal = facing_normal(ray, intersection);
+        let wo = ray.direction * -1.0;
+        let reflectance = brdf::schlick(coat.refractive_index, wo.inner_product(normal));
+
+        let mut specular = Color::black();
+        if coat.roughness > 0.0 {
+            let lobe = Microfacet::new((coat.roughness, coat.roughness), normal, None);
+            for light in &self.lights {
+                if self.unshadowed(intersection.hit_point, light.position, ray.time) {
+                    let light_dir = (light.position - intersection.hit_point).normalized();
+                    specular += light.color * lobe.evaluate(wo, light_dir) * PI;
+                }
             }
         }
-
-        return result * (1.0 / (max_distance_squared * PI));
-        // heap.reset();
-        // return photon_map.irradiance_estimate(heap, hit_point, hit_normal);
+        let reflected = match coat_reflection(ray, intersection, coat, sampler.next_2d()) {
+            Some((reflect_ray, weight)) => {
+                stats::increment(Counter::ReflectedRays);
+                self.trace_ray(
+                    photon_map_global,
+                    photon_map_caustic,
+                    sampler,
+                    &reflect_ray,
+                    depth + 1,
+                ) * weight
+            }
+            None => Color::black(),
+        };
+        let reflection = Radiance {
+            specular: specular * reflectance,
+            reflected: reflected * reflectance,
+            ..Radiance::default()
+        };
+        (reflection, coat.color * (1.0 - reflectance))
     }
 
     pub fn trace_ray(
         &self,
-        photon_map_global: &KdTree<f32, Photon, [f32; 3]>,
-        photon_map_caustic: &KdTree<f32, Photon, [f32; 3]>,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        sampler: &mut dyn Sampler,
         ray: &Ray,
         depth: u8,
     ) -> Color {
+        self.trace_radiance(photon_map_global, photon_map_caustic, sampler, ray, depth)
+            .total()
+    }
+
+    // like trace_ray, with the terms of the radiance kept apart
+    pub fn trace_radiance(
+        &self,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        sampler: &mut dyn Sampler,
+        ray: &Ray,
+        depth: u8,
+    ) -> Radiance {
+        self.trace_surface(photon_map_global, photon_map_caustic, sampler, ray, depth)
+            .0
+    }
+
+    // like trace_radiance, together with the surface the ray hits for the depth, normal,
+    // albedo and id passes
+    pub fn trace_surface(
+        &self,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        sampler: &mut dyn Sampler,
+        ray: &Ray,
+        depth: u8,
+    ) -> (Radiance, Option<SurfaceSample>) {
         if depth >= MAX_DEPTH {
-            return Color::black();
+            return (Radiance::default(), None);
         }
 
-        let intersection = ray.intersect_any(&self.objects);
-
-        match intersection {
-            Some(int) => {
-                let Material {
-                    refractive_index,
-                    diffuse_color,
-                    reflect_color,
-                    specular_exponent: _,
-                } = int.material;
-
-                if refractive_index == 0.0 {
-                    let (direct_color, specular_color) =
-                        self.direct_illumination(&ray, &int, &int.material);
-                    let global_color = self.global_illumination(
-                        photon_map_global,
-                        int.hit_point,
-                        int.hit_normal,
-                    );
-                    let caustic_color = self.global_illumination(
-                        photon_map_caustic,
-                        int.hit_point,
-                        int.hit_normal,
-                    );
-                    let reflected_color = if reflect_color.max() > 0.0 {
-                        let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+        let (index, int) = match ray.intersect_closest(&self.objects, &self.bvh) {
+            Some(intersection) => intersection,
+            None => return (Radiance::default(), None),
+        };
+        let shading = int
+            .material
+            .shading(int.uv, int.hit_point, sampler.next_1d());
+        let radiance = self.surface_radiance(
+            photon_map_global,
+            photon_map_caustic,
+            sampler,
+            ray,
+            &self.objects[index],
+            &int,
+            &shading,
+            depth,
+        );
+        let radiance = match &shading.coat {
+            Some(coat) => {
+                let (reflection, transmittance) = self.coat_radiance(
+                    photon_map_global,
+                    photon_map_caustic,
+                    sampler,
+                    ray,
+                    &int,
+                    coat,
+                    depth,
+                );
+                reflection + radiance * transmittance
+            }
+            None => radiance,
+        };
+        (
+            radiance,
+            Some(self.surface_sample(index, &int, &shading.surface)),
+        )
+    }
+
+    // the radiance from the surface under any coat
+    #[allow(clippy::too_many_arguments)]
+    fn surface_radiance(
+        &self,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        sampler: &mut dyn Sampler,
+        ray: &Ray,
+        object: &Object,
+        int: &Intersection,
+        shading: &Shading,
+        depth: u8,
+    ) -> Radiance {
+        let Surface {
+            refractive_index,
+            diffuse_color,
+            reflect_color,
+            specular_exponent: _,
+            roughness: _,
+            hair: _,
+            medium,
+        } = shading.surface;
+
+        if let Some(medium) = &medium {
+            // rays from inside pass the boundary unchanged
+            if ray.direction.inner_product(int.hit_normal) > 0.0 {
+                let through_ray = Ray::new(int.hit_point, ray.direction, ray.time);
+                return self.trace_radiance(
+                    photon_map_global,
+                    photon_map_caustic,
+                    sampler,
+                    &through_ray,
+                    depth + 1,
+                );
+            }
+            let nt = refractive_index.max(1.0);
+            let direction = ray.direction.normalized();
+            let r = brdf::schlick(nt, -direction.inner_product(int.hit_normal));
+            let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+            stats::increment(Counter::ReflectedRays);
+            let reflected = self.trace_radiance(
+                photon_map_global,
+                photon_map_caustic,
+                sampler,
+                &reflect_ray,
+                depth + 1,
+            );
+            // one walk through the medium per sample
+            stats::increment(Counter::RefractedRays);
+            let inwards = refract(direction, int.hit_normal, 1.0, nt).unwrap_or(direction);
+            let transmitted =
+                match medium.walk(nt, object, int.hit_point, inwards, ray.time, sampler) {
+                    Some(exit) => {
+                        self.exit_radiance(photon_map_global, photon_map_caustic, &exit, ray.time)
+                    }
+                    None => Radiance::default(),
+                };
+            reflected * r + transmitted * (1.0 - r)
+        } else if refractive_index == 0.0 {
+            let (direct_color, specular_color) = self.direct_illumination(ray, int, shading);
+            stats::increment(Counter::GlobalLookups);
+            let global_color = stats::timed(Counter::GlobalLookupNanoseconds, || {
+                self.global_estimate
+                    .irradiance(photon_map_global, int.hit_point, int.hit_normal)
+            });
+            stats::increment(Counter::CausticLookups);
+            let caustic_color = stats::timed(Counter::CausticLookupNanoseconds, || {
+                self.caustic_estimate
+                    .irradiance(photon_map_caustic, int.hit_point, int.hit_normal)
+            });
+            let reflected_color = if reflect_color.max() <= 0.0 {
+                Color::black()
+            } else if let Some(lobe) = glossy_lobe(ray, int, &shading.surface) {
+                // one direction of the glossy lobe per sample
+                match lobe.sample(ray.direction * -1.0, sampler.next_2d()) {
+                    Some((direction, weight)) => {
+                        let reflect_ray = Ray::new(int.hit_point, direction, ray.time);
+                        stats::increment(Counter::ReflectedRays);
                         reflect_color
+                            * weight
                             * self.trace_ray(
                                 photon_map_global,
                                 photon_map_caustic,
+                                sampler,
                                 &reflect_ray,
                                 depth + 1,
                             )
-                    } else {
-                        Color::black()
-                    };
-                    return direct_color
-                        + reflected_color
-                        + specular_color
-                        + diffuse_color * (global_color + caustic_color);
+                    }
+                    None => Color::black(),
+                }
+            } else {
+                let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+                stats::increment(Counter::ReflectedRays);
+                reflect_color
+                    * self.trace_ray(
+                        photon_map_global,
+                        photon_map_caustic,
+                        sampler,
+                        &reflect_ray,
+                        depth + 1,
+                    )
+            };
+            Radiance {
+                direct: direct_color,
+                specular: specular_color,
+                reflected: reflected_color,
+                global: diffuse_color * global_color,
+                caustic: diffuse_color * caustic_color,
+            }
+        } else {
+            let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+
+            let nt: f32;
+            let c: f32;
+            let mut t = Vector3::new(0.0, 0.0, 0.0);
+            if ray.direction.inner_product(int.hit_normal) < 0.0 {
+                let n = 1.0;
+                nt = refractive_index;
+                if let Some(v) = refract(ray.direction, int.hit_normal, n, nt) {
+                    t = v;
+                }
+                c = -ray.direction.inner_product(int.hit_normal);
+            } else {
+                let n = refractive_index;
+                nt = 1.0;
+                if let Some(v) = refract(ray.direction, int.hit_normal * -1.0, n, nt) {
+                    c = v.normalized().inner_product(int.hit_normal);
+                    t = v;
                 } else {
-                    let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+                    stats::increment(Counter::ReflectedRays);
+                    return self.trace_radiance(
+                        photon_map_global,
+                        photon_map_caustic,
+                        sampler,
+                        &reflect_ray,
+                        depth + 1,
+                    );
+                }
+            }
 
-                    let nt: f32;
-                    let c: f32;
-                    let mut t = Vector3::new(0.0, 0.0, 0.0);
-                    if ray.direction.inner_product(int.hit_normal) < 0.0 {
-                        let n = 1.0;
-                        nt = refractive_index;
-                        if let Some(v) = refract(ray.direction, int.hit_normal, n, nt) {
-                            t = v;
-                        }
-                        c = -1.0 * ray.direction.inner_product(int.hit_normal);
-                    } else {
-                        let n = refractive_index;
-                        nt = 1.0;
-                        if let Some(v) = refract(ray.direction, int.hit_normal * -1.0, n, nt) {
-                            c = v.normalized().inner_product(int.hit_normal);
-                            t = v;
-                        } else {
-                            return Color::white()
-                                * self.trace_ray(
-                                    photon_map_global,
-                                    photon_map_caustic,
-                                    &reflect_ray,
-                                    depth + 1,
-                                );
-                        }
-                    }
+            let r0 = ((nt - 1.0) / (nt + 1.0)).powf(2.0);
+            let r = r0 + (1.0 - r0) * (1.0 - c).powf(5.0);
+            let refract_ray = Ray {
+                origin: int.hit_point + 0.0001 * t.normalized(),
+                direction: t.normalized(),
+                time: ray.time,
+            };
+
+            stats::increment(Counter::ReflectedRays);
+            stats::increment(Counter::RefractedRays);
+            self.trace_radiance(
+                photon_map_global,
+                photon_map_caustic,
+                sampler,
+                &reflect_ray,
+                depth + 1,
+            ) * r
+                + self.trace_radiance(
+                    photon_map_global,
+                    photon_map_caustic,
+                    sampler,
+                    &refract_ray,
+                    depth + 1,
+                ) * (1.0 - r)
+        }
+    }
 
-                    let r0 = ((nt - 1.0) / (nt + 1.0)).powf(2.0);
-                    let r = r0 + (1.0 - r0) * (1.0 - c).powf(5.0);
-                    let refract_ray = Ray {
-                        origin: &int.hit_point + 0.0001 * t.normalized(),
-                        direction: t.normalized(),
-                    };
-
-                    return Color::white()
-                        * (r * self.trace_ray(
-                            photon_map_global,
-                            photon_map_caustic,
-                            &reflect_ray,
-                            depth + 1,
-                        ) + (1.0 - r)
-                            * self.trace_ray(
-                                photon_map_global,
-                                photon_map_caustic,
-                                &refract_ray,
-                                depth + 1,
-                            ));
-                }
+    // Light that comes in where a walk leaves a medium, taken in like by a diffuse surface and
+    // weighted by what is left after the walk
+    fn exit_radiance(
+        &self,
+        photon_map_global: &PhotonMap,
+        photon_map_caustic: &PhotonMap,
+        exit: &Exit,
+        time: f32,
+    ) -> Radiance {
+        let mut direct = Color::black();
+        for light in &self.lights {
+            let light_dir = (light.position - exit.point).normalized();
+            let cos = exit.normal.inner_product(light_dir);
+            if cos > 0.0 && self.unshadowed(exit.point, light.position, time) {
+                direct += light.color * cos;
             }
-            None => Color::black(),
+        }
+        stats::increment(Counter::GlobalLookups);
+        let global = stats::timed(Counter::GlobalLookupNanoseconds, || {
+            self.global_estimate
+                .irradiance(photon_map_global, exit.point, exit.normal)
+        });
+        stats::increment(Counter::CausticLookups);
+        let caustic = stats::timed(Counter::CausticLookupNanoseconds, || {
+            self.caustic_estimate
+                .irradiance(photon_map_caustic, exit.point, exit.normal)
+        });
+        Radiance {
+            direct: exit.throughput * direct,
+            global: exit.throughput * global,
+            caustic: exit.throughput * caustic,
+            ..Radiance::default()
         }
     }
 
-    pub fn random_photon_ray(&self, n_photons: usize) -> (Ray, Color) {
-        let weights: Vec<f32> = self.lights.iter().map(|l| l.intensity).collect();
-        let dist = WeightedIndex::new(&weights).unwrap();
+    fn surface_sample(&self, index: usize, int: &Intersection, surface: &Surface) -> SurfaceSample {
+        let albedo = if let Some(medium) = surface.medium {
+            medium.albedo
+        } else if surface.refractive_index == 0.0 {
+            surface.diffuse_color
+        } else {
+            Color::white()
+        };
+        SurfaceSample {
+            depth: int.t,
+            normal: int.hit_normal,
+            albedo,
+            object_id: index as u32 + 1,
+            material_id: self.material_ids[index],
+        }
+    }
 
-        let mut rng = rand::thread_rng();
-        let index = dist.sample(&mut rng);
+    // photons leave at a time within the shutter interval, so moving objects leave their
+    // illumination along their path
+    pub fn random_photon_ray(
+        &self,
+        sampler: &mut dyn Sampler,
+        shutter: &Shutter,
+        n_photons: usize,
+    ) -> (Ray, Color) {
+        let weights: Vec<f32> = self.lights.iter().map(|l| l.intensity).collect();
+        let index = choose_weighted(&weights, sampler.next_1d());
         let light = &self.lights[index];
+        let time = shutter.time(sampler.next_1d());
 
         (
-            Ray::random_ray(light.position),
+            Ray::random_ray(light.position, sampler.next_2d(), time),
             light.intensity * light.color / (n_photons as f32),
         )
     }
 
+    // one projection map per light, covering the specular objects that produce caustics
+    // wherever they move while the shutter is open
+    pub fn caustic_projection_maps(
+        &self,
+        resolution: usize,
+        shutter: &Shutter,
+    ) -> Vec<ProjectionMap> {
+        let targets = self
+            .objects
+            .iter()
+            .filter(|o| o.material.is_specular())
+            .filter_map(|o| o.bounding_sphere(shutter))
+            .collect::<Vec<_>>();
+        self.lights
+            .iter()
+            .map(|l| ProjectionMap::new(l.position, &targets, resolution))
+            .collect()
+    }
+
+    // Emits a photon only towards the cells of the projection maps. Lights are chosen in
+    // proportion to the power they send towards specular objects, so every photon carries
+    // an equal share of that power. None when no light sees a specular object.
+    pub fn random_caustic_photon_ray(
+        &self,
+        sampler: &mut dyn Sampler,
+        projection_maps: &[ProjectionMap],
+        shutter: &Shutter,
+        n_photons: usize,
+    ) -> Option<(Ray, Color)> {
+        let weights: Vec<f32> = self
+            .lights
+            .iter()
+            .zip(projection_maps)
+            .map(|(l, m)| l.intensity * m.coverage())
+            .collect();
+        let total: f32 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let index = choose_weighted(&weights, sampler.next_1d());
+        let light = &self.lights[index];
+        let cell_sample = sampler.next_1d();
+        let time = shutter.time(sampler.next_1d());
+
+        Some((
+            Ray {
+                origin: light.position,
+                direction: projection_maps[index].random_direction(cell_sample, sampler.next_2d()),
+                time,
+            },
+            total * light.color / (n_photons as f32),
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
     pub fn trace_photon(
         &self,
-        photon_map_global: &mut KdTree<f32, Photon, [f32; 3]>,
-        photon_map_caustic: &mut KdTree<f32, Photon, [f32; 3]>,
+        photon_map: &mut PhotonMap,
+        pass: PhotonPass,
+        sampler: &mut dyn Sampler,
         ray: &Ray,
         color: Color,
         depth: u8,
         bounce_type: BounceType,
-    ) -> () {
+        diffuse_path: bool,
+    ) {
         if depth >= MAX_DEPTH {
             return;
         }
-
-        let intersection = ray.intersect_any(&self.objects);
-
-        match intersection {
-            Some(int) => {
-                let Material {
-                    refractive_index,
-                    diffuse_color,
-                    reflect_color,
-                    specular_exponent: _,
-                } = int.material;
-
-                let mut bounce = BounceType::NONE;
-                let mut rng = rand::thread_rng();
-                if refractive_index == 0.0 {
-                    let p_reflect = (diffuse_color + reflect_color).max();
-                    let p_diffuse = diffuse_color.sum()
-                        / (diffuse_color.sum() + reflect_color.sum())
-                        * p_reflect;
-                    let p_specular = reflect_color.sum()
-                        / (diffuse_color.sum() + reflect_color.sum())
-                        * p_reflect;
-
-                    let mut absorb = false;
-                    let mut reflected_photon_color = Color::black();
-                    let mut reflect_ray = Ray {
-                        origin: Vector3::new(0.0, 0.0, 0.0),
-                        direction: Vector3::new(0.0, 0.0, 0.0),
-                    };
-                    let r = rng.gen::<f32>();
-
-                    if r >= 0.0 && r < p_diffuse {
-                        reflect_ray = Ray::random_ray_in_hemisphere(int.hit_point, int.hit_normal);
-                        reflected_photon_color = color * diffuse_color / p_diffuse;
-                        bounce = BounceType::DIFFUSE;
-                    } else if r >= p_diffuse && r < (p_diffuse + p_specular) {
-                        reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
-                        reflected_photon_color = color * reflect_color / p_specular;
-                        bounce = BounceType::SPECULAR;
-                    }
-
-                    if r >= (p_specular + p_diffuse) {
-                        absorb = true;
-                    }
-
-                    if depth != 0 {
-                        if bounce_type == BounceType::DIFFUSE {
-                            let photon = Photon {
-                                position: int.hit_point,
-                                direction: (ray.direction * -1.0).normalized(),
-                                power: color,
-                            };
-                        } else if bounce_type == BounceType::SPECULAR {
-                            let photon = Photon {
-                                position: int.hit_point,
-                                direction: (ray.direction * -1.0).normalized(),
-                                power: color,
-                            };
-                            photon_map_caustic.add(int.hit_point.to_array(), photon).unwrap();
-                        }
+        stats::increment(Counter::PhotonRays);
+
+        let intersection = ray.intersect_closest(&self.objects, &self.bvh);
+
+        if let Some((index, int)) = intersection {
+            let shading = int
+                .material
+                .shading(int.uv, int.hit_point, sampler.next_1d());
+            let Surface {
+                refractive_index,
+                diffuse_color,
+                reflect_color,
+                specular_exponent: _,
+                roughness: _,
+                hair: _,
+                medium,
+            } = shading.surface;
+
+            // photons are stored where they arrive at an opaque or translucent surface, also
+            // under a coat
+            if (refractive_index == 0.0 || medium.is_some()) && depth != 0 {
+                let photon = Photon::new(
+                    int.hit_point,
+                    (-ray.direction).normalized(),
+                    int.hit_normal,
+                    color,
+                );
+                match pass {
+                    PhotonPass::Global if diffuse_path => photon_map.store(photon),
+                    PhotonPass::Caustic if bounce_type == BounceType::SPECULAR => {
+                        photon_map.store(photon)
                     }
+                    _ => (),
+                }
+            }
 
-                    if absorb {
-                        return;
-                    } else {
-                        return self.trace_photon(
-                            photon_map_global,
-                            photon_map_caustic,
+            // the coat reflects photons by its reflectance and tints the rest
+            let mut color = color;
+            if let Some(coat) = &shading.coat {
+                let cos = -ray
+                    .direction
+                    .normalized()
+                    .inner_product(facing_normal(ray, &int));
+                if sampler.next_1d() < brdf::schlick(coat.refractive_index, cos) {
+                    if let Some((reflect_ray, weight)) =
+                        coat_reflection(ray, &int, coat, sampler.next_2d())
+                    {
+                        self.trace_photon(
+                            photon_map,
+                            pass,
+                            sampler,
                             &reflect_ray,
-                            reflected_photon_color,
+                            color * weight,
                             depth + 1,
-                            bounce,
+                            BounceType::SPECULAR,
+                            diffuse_path,
                         );
                     }
-                } else {
+                    return;
+                }
+                color = color * coat.color;
+            }
+
+            // like camera rays, photons walk through a medium, and leave it diffusely
+            if let Some(medium) = &medium {
+                if ray.direction.inner_product(int.hit_normal) > 0.0 {
+                    let through_ray = Ray::new(int.hit_point, ray.direction, ray.time);
+                    return self.trace_photon(
+                        photon_map,
+                        pass,
+                        sampler,
+                        &through_ray,
+                        color,
+                        depth + 1,
+                        bounce_type,
+                        diffuse_path,
+                    );
+                }
+                let nt = refractive_index.max(1.0);
+                let direction = ray.direction.normalized();
+                if sampler.next_1d() < brdf::schlick(nt, -direction.inner_product(int.hit_normal)) {
                     let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+                    return self.trace_photon(
+                        photon_map,
+                        pass,
+                        sampler,
+                        &reflect_ray,
+                        color,
+                        depth + 1,
+                        BounceType::SPECULAR,
+                        diffuse_path,
+                    );
+                }
+                // caustic photons only follow specular paths
+                if pass == PhotonPass::Caustic {
+                    return;
+                }
+                let inwards = refract(direction, int.hit_normal, 1.0, nt).unwrap_or(direction);
+                let object = &self.objects[index];
+                if let Some(exit) =
+                    medium.walk(nt, object, int.hit_point, inwards, ray.time, sampler)
+                {
+                    let p_leave = exit.throughput.max().min(1.0);
+                    if sampler.next_1d() < p_leave {
+                        let leave_ray = Ray::random_ray_in_hemisphere(
+                            exit.point,
+                            exit.normal,
+                            sampler.next_2d(),
+                            ray.time,
+                        );
+                        self.trace_photon(
+                            photon_map,
+                            pass,
+                            sampler,
+                            &leave_ray,
+                            color * exit.throughput / p_leave,
+                            depth + 1,
+                            BounceType::DIFFUSE,
+                            true,
+                        );
+                    }
+                }
+                return;
+            }
 
-                    let n: f32;
-                    let nt: f32;
-                    let c: f32;
-                    let mut t = Vector3::new(0.0, 0.0, 0.0);
-                    if ray.direction.inner_product(int.hit_normal) < 0.0 {
-                        n = 1.0;
-                        nt = refractive_index;
-                        if let Some(v) = refract(ray.direction, int.hit_normal, n, nt) {
-                            t = v;
-                        }
-                        c = -1.0 * ray.direction.inner_product(int.hit_normal);
+            let mut bounce = BounceType::NONE;
+            if refractive_index == 0.0 {
+                let p_reflect = (diffuse_color + reflect_color).max();
+                let p_diffuse =
+                    diffuse_color.sum() / (diffuse_color.sum() + reflect_color.sum()) * p_reflect;
+                let p_specular =
+                    reflect_color.sum() / (diffuse_color.sum() + reflect_color.sum()) * p_reflect;
+
+                let mut absorb = false;
+                let mut reflected_photon_color = Color::black();
+                let mut reflect_ray = Ray {
+                    origin: Vector3::new(0.0, 0.0, 0.0),
+                    direction: Vector3::new(0.0, 0.0, 0.0),
+                    time: ray.time,
+                };
+                let r = sampler.next_1d();
+                let direction_sample = sampler.next_2d();
+
+                if let Some((scattering, axes)) = hair_scattering(ray, &int, &shading.surface) {
+                    // the fiber scatters like it shades, and keeps what it lets through
+                    let wo = to_frame(&axes, ray.direction * -1.0).normalized();
+                    let (wi, weight) = scattering.sample(wo, direction_sample, sampler.next_2d());
+                    let p_scatter = weight.max().min(1.0);
+                    if r < p_scatter {
+                        reflect_ray = Ray::new(int.hit_point, from_frame(&axes, wi), ray.time);
+                        reflected_photon_color = color * weight / p_scatter;
+                        bounce = BounceType::DIFFUSE;
                     } else {
-                        n = refractive_index;
-                        nt = 1.0;
-                        if let Some(v) = refract(ray.direction, int.hit_normal * -1.0, n, nt) {
-                            c = v.normalized().inner_product(int.hit_normal);
-                            t = v;
-                        } else {
-                            return self.trace_photon(
-                                photon_map_global,
-                                photon_map_caustic,
-                                &reflect_ray,
-                                color,
-                                depth + 1,
-                                BounceType::SPECULAR,
-                            );
+                        absorb = true;
+                    }
+                } else if r >= 0.0 && r < p_diffuse {
+                    reflect_ray = Ray::random_ray_in_hemisphere(
+                        int.hit_point,
+                        int.hit_normal,
+                        direction_sample,
+                        ray.time,
+                    );
+                    reflected_photon_color = color * diffuse_color / p_diffuse;
+                    bounce = BounceType::DIFFUSE;
+                } else if r >= p_diffuse && r < (p_diffuse + p_specular) {
+                    // glossy bounces follow the same lobe as camera rays, and like mirrors
+                    // leave the caustic pass what they focus
+                    match glossy_lobe(ray, &int, &shading.surface) {
+                        Some(lobe) => match lobe.sample(ray.direction * -1.0, direction_sample) {
+                            Some((direction, weight)) => {
+                                reflect_ray = Ray::new(int.hit_point, direction, ray.time);
+                                reflected_photon_color =
+                                    color * reflect_color * weight / p_specular;
+                            }
+                            None => absorb = true,
+                        },
+                        None => {
+                            reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+                            reflected_photon_color = color * reflect_color / p_specular;
                         }
                     }
+                    bounce = BounceType::SPECULAR;
+                } else {
+                    absorb = true;
+                }
 
-                    let r0 = ((nt - 1.0) / (nt + 1.0)).powf(2.0);
-                    let r = r0 + (1.0 - r0) * (1.0 - c).powf(5.0);
-                    let refract_ray = Ray {
-                        origin: &int.hit_point + 0.0001 * t.normalized(),
-                        direction: t.normalized(),
-                    };
+                // caustic photons only follow specular paths
+                if pass == PhotonPass::Caustic && bounce == BounceType::DIFFUSE {
+                    absorb = true;
+                }
 
-                    if rng.gen::<f32>() < r {
-                        return self.trace_photon(
-                            photon_map_global,
-                            photon_map_caustic,
-                            &reflect_ray,
-                            color,
-                            depth + 1,
-                            BounceType::SPECULAR,
-                        );
+                if !absorb {
+                    self.trace_photon(
+                        photon_map,
+                        pass,
+                        sampler,
+                        &reflect_ray,
+                        reflected_photon_color,
+                        depth + 1,
+                        bounce,
+                        diffuse_path || bounce == BounceType::DIFFUSE,
+                    )
+                }
+            } else {
+                let reflect_ray = ray.reflect(int.hit_point, int.hit_normal);
+
+                let n: f32;
+                let nt: f32;
+                let c: f32;
+                let mut t = Vector3::new(0.0, 0.0, 0.0);
+                if ray.direction.inner_product(int.hit_normal) < 0.0 {
+                    n = 1.0;
+                    nt = refractive_index;
+                    if let Some(v) = refract(ray.direction, int.hit_normal, n, nt) {
+                        t = v;
+                    }
+                    c = -ray.direction.inner_product(int.hit_normal);
+                } else {
+                    n = refractive_index;
+                    nt = 1.0;
+                    if let Some(v) = refract(ray.direction, int.hit_normal * -1.0, n, nt) {
+                        c = v.normalized().inner_product(int.hit_normal);
+                        t = v;
                     } else {
                         return self.trace_photon(
-                            photon_map_global,
-                            photon_map_caustic,
-                            &refract_ray,
+                            photon_map,
+                            pass,
+                            sampler,
+                            &reflect_ray,
                             color,
                             depth + 1,
                             BounceType::SPECULAR,
+                            diffuse_path,
                         );
                     }
                 }
+
+                let r0 = ((nt - 1.0) / (nt + 1.0)).powf(2.0);
+                let r = r0 + (1.0 - r0) * (1.0 - c).powf(5.0);
+                let refract_ray = Ray {
+                    origin: int.hit_point + 0.0001 * t.normalized(),
+                    direction: t.normalized(),
+                    time: ray.time,
+                };
+
+                if sampler.next_1d() < r {
+                    self.trace_photon(
+                        photon_map,
+                        pass,
+                        sampler,
+                        &reflect_ray,
+                        color,
+                        depth + 1,
+                        BounceType::SPECULAR,
+                        diffuse_path,
+                    )
+                } else {
+                    self.trace_photon(
+                        photon_map,
+                        pass,
+                        sampler,
+                        &refract_ray,
+                        color,
+                        depth + 1,
+                        BounceType::SPECULAR,
+                        diffuse_path,
+                    )
+                }
             }
-            None => return,
         }
     }
 }
 
+// The hair model at a hit on a curve, with the axes of its frame: along the fiber, across it
+// and towards the viewer. None for other materials and shapes.
+fn hair_scattering(
+    ray: &Ray,
+    intersection: &Intersection,
+    material: &Surface,
+) -> Option<(HairScattering, [Vector3; 3])> {
+    let hair = material.hair?;
+    let along = intersection.tangent?;
+    let facing = ray.direction * -1.0;
+    let towards = facing - along * facing.inner_product(along);
+    if towards.length_squared() == 0.0 {
+        return None;
+    }
+    let towards = towards.normalized();
+    let across = towards.outer_product(along);
+    // v runs across the curve
+    let h = intersection.uv.1 * 2.0 - 1.0;
+    Some((HairScattering::new(&hair, h), [along, across, towards]))
+}
+
+// the rough reflection of a glossy material, around the normal on the side of the viewer
+fn glossy_lobe(ray: &Ray, intersection: &Intersection, material: &Surface) -> Option<Microfacet> {
+    if !material.is_glossy() {
+        return None;
+    }
+    Some(Microfacet::new(
+        material.roughness,
+        facing_normal(ray, intersection),
+        intersection.tangent,
+    ))
+}
+
+// The reflection of the ray off a coat and its weight, the BRDF times the cosine divided by
+// the density of choosing the direction. None when a rough coat reflects below the surface.
+fn coat_reflection(
+    ray: &Ray,
+    intersection: &Intersection,
+    coat: &Coat,
+    sample: (f32, f32),
+) -> Option<(Ray, f32)> {
+    let normal = facing_normal(ray, intersection);
+    if coat.roughness <= 0.0 {
+        return Some((ray.reflect(intersection.hit_point, normal), 1.0));
+    }
+    let lobe = Microfacet::new((coat.roughness, coat.roughness), normal, None);
+    let (direction, weight) = lobe.sample(ray.direction * -1.0, sample)?;
+    Some((
+        Ray::new(intersection.hit_point, direction, ray.time),
+        weight,
+    ))
+}
+
+// the normal on the side of the viewer
+fn facing_normal(ray: &Ray, intersection: &Intersection) -> Vector3 {
+    if ray.direction.inner_product(intersection.hit_normal) > 0.0 {
+        intersection.hit_normal * -1.0
+    } else {
+        intersection.hit_normal
+    }
+}
+
+fn to_frame(axes: &[Vector3; 3], v: Vector3) -> Vector3 {
+    Vector3::new(
+        v.inner_product(axes[0]),
+        v.inner_product(axes[1]),
+        v.inner_product(axes[2]),
+    )
+}
+
+fn from_frame(axes: &[Vector3; 3], v: Vector3) -> Vector3 {
+    axes[0] * v.x + axes[1] * v.y + axes[2] * v.z
+}
+
+// index of the weight that the uniform sample u falls in
+fn choose_weighted(weights: &[f32], u: f32) -> usize {
+    let total: f32 = weights.iter().sum();
+    let mut remaining = u * total;
+    for (i, weight) in weights.iter().enumerate() {
+        if remaining < *weight {
+            return i;
+        }
+        remaining -= weight;
+    }
+    weights.len() - 1
+}
+
 fn refract(direction: Vector3, normal: Vector3, n: f32, nt: f32) -> Option<Vector3> {
     let dn = direction.inner_product(normal);
     let sq_rt = 1.0 - (n * n * (1.0 - (dn * dn))) / (nt * nt);
 
     if sq_rt < 0.0 {
-        return None;
+        None
     } else {
-        return Some((n * (direction - normal * dn)) / nt - normal * sq_rt.sqrt());
+        Some((n * (direction - normal * dn)) / nt - normal * sq_rt.sqrt())
     }
 }
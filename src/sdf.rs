@@ -0,0 +1,383 @@
+use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
+use std::mem;
+
+// distance to either side of a point at which the gradient is estimated
+const NORMAL_OFFSET: f32 = 1e-4;
+
+// Tree of signed distance functions, negative inside the solid. Primitives sit around the
+// origin with y as their axis, the other nodes place and combine them.
+#[derive(Debug)]
+pub enum Sdf {
+    Sphere {
+        radius: f32,
+    },
+    Cuboid {
+        half_size: Vector3,
+    },
+    // capped, from -half_height to half_height
+    Cylinder {
+        radius: f32,
+        half_height: f32,
+    },
+    Torus {
+        major_radius: f32,
+        minor_radius: f32,
+    },
+    Translate {
+        offset: Vector3,
+        child: Box<Sdf>,
+    },
+    // blends the surfaces where they are closer than smoothness, 0 gives the plain union
+    SmoothUnion {
+        left: Box<Sdf>,
+        right: Box<Sdf>,
+        smoothness: f32,
+    },
+    // turns the child around the y axis by rate radians per unit of height
+    Twist {
+        rate: f32,
+        child: Box<Sdf>,
+    },
+    // adds a sine pattern to the distance
+    Displace {
+        amplitude: f32,
+        frequency: f32,
+        child: Box<Sdf>,
+    },
+    // copies of the child every period, limit copies on each side along each axis
+    Repeat {
+        period: Vector3,
+        limit: [u32; 3],
+        child: Box<Sdf>,
+    },
+}
+
+impl Sdf {
+    pub fn sphere(radius: f32) -> Self {
+        Sdf::Sphere { radius }
+    }
+
+    pub fn cuboid(half_size: Vector3) -> Self {
+        Sdf::Cuboid { half_size }
+    }
+
+    pub fn cylinder(radius: f32, half_height: f32) -> Self {
+        Sdf::Cylinder {
+            radius,
+            half_height,
+        }
+    }
+
+    pub fn torus(major_radius: f32, minor_radius: f32) -> Self {
+        Sdf::Torus {
+            major_radius,
+            minor_radius,
+        }
+    }
+
+    pub fn translate(child: Sdf, offset: Vector3) -> Self {
+        Sdf::Translate {
+            offset,
+            child: Box::new(child),
+        }
+    }
+
+    pub fn smooth_union(left: Sdf, right: Sdf, smoothness: f32) -> Self {
+        Sdf::SmoothUnion {
+            left: Box::new(left),
+            right: Box::new(right),
+            smoothness,
+        }
+    }
+
+    pub fn twist(child: Sdf, rate: f32) -> Self {
+        Sdf::Twist {
+            rate,
+            child: Box::new(child),
+        }
+    }
+
+    pub fn displace(child: Sdf, amplitude: f32, frequency: f32) -> Self {
+        Sdf::Displace {
+            amplitude,
+            frequency,
+            child: Box::new(child),
+        }
+    }
+
+    pub fn repeat(child: Sdf, period: Vector3, limit: [u32; 3]) -> Self {
+        Sdf::Repeat {
+            period,
+            limit,
+            child: Box::new(child),
+        }
+    }
+
+    pub fn distance(&self, p: Vector3) -> f32 {
+        match self {
+            Sdf::Sphere { radius } => p.length_squared().sqrt() - radius,
+            Sdf::Cuboid { half_size } => {
+                let q = Vector3::new(p.x.abs(), p.y.abs(), p.z.abs()) - half_size;
+                let outside = q.max(Vector3::default()).length_squared().sqrt();
+                outside + q.x.max(q.y).max(q.z).min(0.0)
+            }
+            Sdf::Cylinder {
+                radius,
+                half_height,
+            } => {
+                let (dx, dy) = (p.x.hypot(p.z) - radius, p.y.abs() - half_height);
+                dx.max(dy).min(0.0) + dx.max(0.0).hypot(dy.max(0.0))
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => (p.x.hypot(p.z) - major_radius).hypot(p.y) - minor_radius,
+            Sdf::Translate { offset, child } => child.distance(p - offset),
+            Sdf::SmoothUnion {
+                left,
+                right,
+                smoothness,
+            } => {
+                let (a, b) = (left.distance(p), right.distance(p));
+                if *smoothness <= 0.0 {
+                    return a.min(b);
+                }
+                // polynomial smooth minimum, at most smoothness / 4 below the minimum
+                let h = (0.5 + 0.5 * (b - a) / smoothness).clamp(0.0, 1.0);
+                b + (a - b) * h - smoothness * h * (1.0 - h)
+            }
+            Sdf::Twist { rate, child } => {
+                let (sin, cos) = (rate * p.y).sin_cos();
+                child.distance(Vector3::new(
+                    cos * p.x - sin * p.z,
+                    p.y,
+                    sin * p.x + cos * p.z,
+                ))
+            }
+            Sdf::Displace {
+                amplitude,
+                frequency,
+                child,
+            } => {
+                let pattern =
+                    (frequency * p.x).sin() * (frequency * p.y).sin() * (frequency * p.z).sin();
+                child.distance(p) + amplitude * pattern
+            }
+            Sdf::Repeat {
+                period,
+                limit,
+                child,
+            } => {
+                let mut q = p;
+                for (i, limit) in limit.iter().enumerate() {
+                    let period = period.coord(i);
+                    if period > 0.0 {
+                        let limit = *limit as f32;
+                        let cell = (p.coord(i) / period).round().clamp(-limit, limit);
+                        q = q.replace_coord(i, p.coord(i) - period * cell);
+                    }
+                }
+                child.distance(q)
+            }
+        }
+    }
+
+    // box the surface stays within, corners with the smallest and largest coordinates
+    pub fn bounds(&self) -> (Vector3, Vector3) {
+        let symmetric = |extent: Vector3| (extent * -1.0, extent);
+        match self {
+            Sdf::Sphere { radius } => symmetric(Vector3::new(*radius, *radius, *radius)),
+            Sdf::Cuboid { half_size } => symmetric(*half_size),
+            Sdf::Cylinder {
+                radius,
+                half_height,
+            } => symmetric(Vector3::new(*radius, *half_height, *radius)),
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                let outer = major_radius + minor_radius;
+                symmetric(Vector3::new(outer, *minor_radius, outer))
+            }
+            Sdf::Translate { offset, child } => {
+                let (min, max) = child.bounds();
+                (min + offset, max + offset)
+            }
+            Sdf::SmoothUnion {
+                left,
+                right,
+                smoothness,
+            } => {
+                let ((min1, max1), (min2, max2)) = (left.bounds(), right.bounds());
+                let blend = smoothness.max(0.0) / 4.0;
+                let blend = Vector3::new(blend, blend, blend);
+                (min1.min(min2) - blend, max1.max(max2) + blend)
+            }
+            Sdf::Twist { child, .. } => {
+                let (min, max) = child.bounds();
+                let radius = radius_around_y(min, max);
+                (
+                    Vector3::new(-radius, min.y, -radius),
+                    Vector3::new(radius, max.y, radius),
+                )
+            }
+            Sdf::Displace {
+                amplitude, child, ..
+            } => {
+                let (min, max) = child.bounds();
+                let a = amplitude.abs();
+                let a = Vector3::new(a, a, a);
+                (min - a, max + a)
+            }
+            Sdf::Repeat {
+                period,
+                limit,
+                child,
+            } => {
+                let (min, max) = child.bounds();
+                let [x, y, z] = limit.map(|l| l as f32);
+                let reach = Vector3::new(period.x * x, period.y * y, period.z * z);
+                (min - reach, max + reach)
+            }
+        }
+    }
+
+    // Bound on how much faster than the distance to the surface the function can change,
+    // sphere tracing divides its steps by it. Twists and displacements stretch space.
+    pub fn lipschitz(&self) -> f32 {
+        match self {
+            Sdf::Sphere { .. } | Sdf::Cuboid { .. } | Sdf::Cylinder { .. } | Sdf::Torus { .. } => {
+                1.0
+            }
+            Sdf::Translate { child, .. } | Sdf::Repeat { child, .. } => child.lipschitz(),
+            Sdf::SmoothUnion { left, right, .. } => left.lipschitz().max(right.lipschitz()),
+            // the rotation changes by rate per unit of height, moving points at the edge of
+            // the bounds sideways by up to rate times their distance from the axis
+            Sdf::Twist { rate, child } => {
+                let (min, max) = self.bounds();
+                child.lipschitz() * (1.0 + rate.abs() * radius_around_y(min, max))
+            }
+            Sdf::Displace {
+                amplitude,
+                frequency,
+                child,
+            } => child.lipschitz() + (amplitude * frequency).abs() * 3.0_f32.sqrt(),
+        }
+    }
+}
+
+// distance from the y axis to the furthest corner of the box
+fn radius_around_y(min: Vector3, max: Vector3) -> f32 {
+    let x = min.x.abs().max(max.x.abs());
+    let z = min.z.abs().max(max.z.abs());
+    x.hypot(z)
+}
+
+impl Hash for Sdf {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        mem::discriminant(self).hash(state);
+        match self {
+            Sdf::Sphere { radius } => radius.to_bits().hash(state),
+            Sdf::Cuboid { half_size } => half_size.hash(state),
+            Sdf::Cylinder {
+                radius,
+                half_height,
+            } => {
+                radius.to_bits().hash(state);
+                half_height.to_bits().hash(state);
+            }
+            Sdf::Torus {
+                major_radius,
+                minor_radius,
+            } => {
+                major_radius.to_bits().hash(state);
+                minor_radius.to_bits().hash(state);
+            }
+            Sdf::Translate { offset, child } => {
+                offset.hash(state);
+                child.hash(state);
+            }
+            Sdf::SmoothUnion {
+                left,
+                right,
+                smoothness,
+            } => {
+                left.hash(state);
+                right.hash(state);
+                smoothness.to_bits().hash(state);
+            }
+            Sdf::Twist { rate, child } => {
+                rate.to_bits().hash(state);
+                child.hash(state);
+            }
+            Sdf::Displace {
+                amplitude,
+                frequency,
+                child,
+            } => {
+                amplitude.to_bits().hash(state);
+                frequency.to_bits().hash(state);
+                child.hash(state);
+            }
+            Sdf::Repeat {
+                period,
+                limit,
+                child,
+            } => {
+                period.hash(state);
+                limit.hash(state);
+                child.hash(state);
+            }
+        }
+    }
+}
+
+// Distance function placed as a shape, with its bounds and Lipschitz bound worked out once
+#[derive(Debug)]
+pub struct SdfShape {
+    sdf: Sdf,
+    bounds: (Vector3, Vector3),
+    lipschitz: f32,
+}
+
+impl SdfShape {
+    pub fn new(sdf: Sdf) -> Self {
+        Self {
+            bounds: sdf.bounds(),
+            lipschitz: sdf.lipschitz(),
+            sdf,
+        }
+    }
+
+    pub fn distance(&self, p: Vector3) -> f32 {
+        self.sdf.distance(p)
+    }
+
+    pub fn bounds(&self) -> (Vector3, Vector3) {
+        self.bounds
+    }
+
+    pub fn lipschitz(&self) -> f32 {
+        self.lipschitz
+    }
+
+    // gradient from central differences, pointing out of the solid
+    pub fn normal(&self, p: Vector3) -> Vector3 {
+        let h = NORMAL_OFFSET;
+        let difference = |axis: Vector3| self.distance(p + axis * h) - self.distance(p - axis * h);
+        Vector3::new(
+            difference(Vector3::new(1.0, 0.0, 0.0)),
+            difference(Vector3::new(0.0, 1.0, 0.0)),
+            difference(Vector3::new(0.0, 0.0, 1.0)),
+        )
+        .normalized()
+    }
+}
+
+// the bounds and Lipschitz bound follow from the tree
+impl Hash for SdfShape {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.sdf.hash(state);
+    }
+}
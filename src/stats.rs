@@ -0,0 +1,239 @@
+use std::cell::Cell;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+// Counters are accumulated per thread and added to the totals by flush, so counting in the
+// inner loops does not make the threads contend for shared cache lines. Rendering threads
+// flush after every tile, the report flushes the thread it is created on.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Counter {
+    CameraRays,
+    ShadowRays,
+    ReflectedRays,
+    RefractedRays,
+    PhotonRays,
+    IntersectionTests,
+    GlobalPhotons,
+    CausticPhotons,
+    GlobalLookups,
+    GlobalLookupNanoseconds,
+    CausticLookups,
+    CausticLookupNanoseconds,
+}
+
+const COUNTER_COUNT: usize = 12;
+
+const COUNTERS: [(Counter, &str); COUNTER_COUNT] = [
+    (Counter::CameraRays, "camera_rays"),
+    (Counter::ShadowRays, "shadow_rays"),
+    (Counter::ReflectedRays, "reflected_rays"),
+    (Counter::RefractedRays, "refracted_rays"),
+    (Counter::PhotonRays, "photon_rays"),
+    (Counter::IntersectionTests, "intersection_tests"),
+    (Counter::GlobalPhotons, "global_photons"),
+    (Counter::CausticPhotons, "caustic_photons"),
+    (Counter::GlobalLookups, "global_lookups"),
+    (
+        Counter::GlobalLookupNanoseconds,
+        "global_lookup_nanoseconds",
+    ),
+    (Counter::CausticLookups, "caustic_lookups"),
+    (
+        Counter::CausticLookupNanoseconds,
+        "caustic_lookup_nanoseconds",
+    ),
+];
+
+// wall time of the phases of a render, measured on the thread that runs them
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Phase {
+    PhotonCache,
+    PhotonTracing,
+    PhotonBalancing,
+    Rendering,
+    Denoising,
+    Output,
+}
+
+const PHASE_COUNT: usize = 6;
+
+const PHASES: [(Phase, &str); PHASE_COUNT] = [
+    (Phase::PhotonCache, "photon_cache"),
+    (Phase::PhotonTracing, "photon_tracing"),
+    (Phase::PhotonBalancing, "photon_balancing"),
+    (Phase::Rendering, "rendering"),
+    (Phase::Denoising, "denoising"),
+    (Phase::Output, "output"),
+];
+
+static TOTALS: [AtomicU64; COUNTER_COUNT] = [const { AtomicU64::new(0) }; COUNTER_COUNT];
+static PHASE_NANOSECONDS: [AtomicU64; PHASE_COUNT] = [const { AtomicU64::new(0) }; PHASE_COUNT];
+
+thread_local! {
+    static LOCAL: [Cell<u64>; COUNTER_COUNT] = const { [const { Cell::new(0) }; COUNTER_COUNT] };
+}
+
+pub fn add(counter: Counter, amount: u64) {
+    LOCAL.with(|local| {
+        let cell = &local[counter as usize];
+        cell.set(cell.get() + amount);
+    });
+}
+
+pub fn increment(counter: Counter) {
+    add(counter, 1);
+}
+
+// runs f and adds its duration in nanoseconds to counter
+pub fn timed<T, F: FnOnce() -> T>(counter: Counter, f: F) -> T {
+    let start = Instant::now();
+    let result = f();
+    add(counter, start.elapsed().as_nanos() as u64);
+    result
+}
+
+pub fn phase<T, F: FnOnce() -> T>(phase: Phase, f: F) -> T {
+    let start = Instant::now();
+    let result = f();
+    PHASE_NANOSECONDS[phase as usize]
+        .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    result
+}
+
+// adds the counts of the current thread to the totals
+pub fn flush() {
+    LOCAL.with(|local| {
+        for (total, cell) in TOTALS.iter().zip(local) {
+            total.fetch_add(cell.replace(0), Ordering::Relaxed);
+        }
+    });
+}
+
+pub struct Report {
+    counters: Vec<(&'static str, u64)>,
+    phases: Vec<(&'static str, Duration)>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        flush();
+        Self {
+            counters: COUNTERS
+                .iter()
+                .map(|(counter, name)| (*name, TOTALS[*counter as usize].load(Ordering::Relaxed)))
+                .collect(),
+            phases: PHASES
+                .iter()
+                .map(|(phase, name)| {
+                    let nanoseconds = PHASE_NANOSECONDS[*phase as usize].load(Ordering::Relaxed);
+                    (*name, Duration::from_nanos(nanoseconds))
+                })
+                .collect(),
+        }
+    }
+
+    pub fn counter(&self, counter: Counter) -> u64 {
+        self.counters[counter as usize].1
+    }
+
+    pub fn phase(&self, phase: Phase) -> Duration {
+        self.phases[phase as usize].1
+    }
+
+    // rays traced while rendering, photon rays are not included
+    pub fn render_rays(&self) -> u64 {
+        self.counter(Counter::CameraRays)
+            + self.counter(Counter::ShadowRays)
+            + self.counter(Counter::ReflectedRays)
+            + self.counter(Counter::RefractedRays)
+    }
+
+    // derived figures, as (name, value) in the order they are reported
+    fn rates(&self) -> Vec<(&'static str, f64)> {
+        let per_second = |count: u64, phase: Phase| {
+            let seconds = self.phase(phase).as_secs_f64();
+            if seconds > 0.0 {
+                count as f64 / seconds
+            } else {
+                0.0
+            }
+        };
+        let average_microseconds = |nanoseconds: Counter, count: Counter| {
+            let count = self.counter(count);
+            if count > 0 {
+                self.counter(nanoseconds) as f64 / count as f64 / 1000.0
+            } else {
+                0.0
+            }
+        };
+        vec![
+            (
+                "rays_per_second",
+                per_second(self.render_rays(), Phase::Rendering),
+            ),
+            (
+                "photon_rays_per_second",
+                per_second(self.counter(Counter::PhotonRays), Phase::PhotonTracing),
+            ),
+            (
+                "global_lookup_microseconds",
+                average_microseconds(Counter::GlobalLookupNanoseconds, Counter::GlobalLookups),
+            ),
+            (
+                "caustic_lookup_microseconds",
+                average_microseconds(Counter::CausticLookupNanoseconds, Counter::CausticLookups),
+            ),
+        ]
+    }
+
+    pub fn print(&self) {
+        println!("Statistics:");
+        for (name, value) in &self.counters {
+            println!("  {:<28} {:>16}", name, value);
+        }
+        for (name, duration) in &self.phases {
+            println!("  {:<28} {:>15.3}s", name, duration.as_secs_f64());
+        }
+        for (name, value) in self.rates() {
+            println!("  {:<28} {:>16.1}", name, value);
+        }
+    }
+
+    pub fn write_json(&self, file_name: &str) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(File::create(file_name)?);
+        writeln!(writer, "{{")?;
+        writeln!(writer, "  \"counters\": {{")?;
+        for (i, (name, value)) in self.counters.iter().enumerate() {
+            let separator = if i + 1 < self.counters.len() { "," } else { "" };
+            writeln!(writer, "    \"{}\": {}{}", name, value, separator)?;
+        }
+        writeln!(writer, "  }},")?;
+        writeln!(writer, "  \"phase_seconds\": {{")?;
+        for (i, (name, duration)) in self.phases.iter().enumerate() {
+            let separator = if i + 1 < self.phases.len() { "," } else { "" };
+            writeln!(
+                writer,
+                "    \"{}\": {}{}",
+                name,
+                duration.as_secs_f64(),
+                separator
+            )?;
+        }
+        writeln!(writer, "  }},")?;
+        let rates = self.rates();
+        for (i, (name, value)) in rates.iter().enumerate() {
+            let separator = if i + 1 < rates.len() { "," } else { "" };
+            writeln!(writer, "  \"{}\": {}{}", name, value, separator)?;
+        }
+        writeln!(writer, "}}")?;
+        writer.flush()
+    }
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
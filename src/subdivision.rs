@@ -0,0 +1,292 @@
+use crate::mesh::Mesh;
+use crate::vector3::Vector3;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum Subdivision {
+    // for meshes of any polygons, which turn into quads
+    CatmullClark,
+    // for meshes of triangles, other polygons are split into triangles first
+    Loop,
+}
+
+// Polygons with any number of sides sharing vertices by index, wound counter-clockwise seen
+// from outside
+#[derive(Debug, Clone)]
+pub struct PolygonMesh {
+    pub positions: Vec<Vector3>,
+    pub faces: Vec<Vec<usize>>,
+}
+
+// faces on either side of an edge and the index of its new vertex, edges with other than two
+// faces are treated as boundaries and stay sharp
+struct Edge {
+    faces: Vec<usize>,
+    vertex: usize,
+}
+
+impl PolygonMesh {
+    // The vertices and faces of a Wavefront obj file, faces refer to vertices from 1 on or
+    // backwards from the last one read with negative indices. Anything else is skipped.
+    pub fn read_obj(file_name: &str) -> std::io::Result<Self> {
+        let text = std::fs::read_to_string(file_name)?;
+        let invalid = |line: usize| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid obj data on line {}", line + 1),
+            )
+        };
+        let mut mesh = PolygonMesh {
+            positions: vec![],
+            faces: vec![],
+        };
+        for (line_number, line) in text.lines().enumerate() {
+            let mut tokens = line.split_whitespace();
+            match tokens.next() {
+                Some("v") => {
+                    let coordinates = tokens
+                        .take(3)
+                        .map(|t| t.parse::<f32>().map_err(|_| invalid(line_number)))
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if coordinates.len() != 3 {
+                        return Err(invalid(line_number));
+                    }
+                    let [x, y, z] = [coordinates[0], coordinates[1], coordinates[2]];
+                    mesh.positions.push(Vector3::new(x, y, z));
+                }
+                Some("f") => {
+                    let count = mesh.positions.len() as i64;
+                    let face = tokens
+                        .map(|t| {
+                            // texture and normal indices after slashes are not used
+                            let index = t.split('/').next().unwrap_or("");
+                            let index = index.parse::<i64>().map_err(|_| invalid(line_number))?;
+                            let index = if index < 0 { count + index } else { index - 1 };
+                            if index < 0 || index >= count {
+                                return Err(invalid(line_number));
+                            }
+                            Ok(index as usize)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    if face.len() < 3 {
+                        return Err(invalid(line_number));
+                    }
+                    mesh.faces.push(face);
+                }
+                _ => {}
+            }
+        }
+        Ok(mesh)
+    }
+
+    // the mesh subdivided levels times, in triangles with normals averaged at the vertices
+    pub fn subdivide(&self, scheme: Subdivision, levels: usize) -> Mesh {
+        let mut mesh = self.clone();
+        if scheme == Subdivision::Loop {
+            mesh.faces = mesh.triangles().iter().map(|t| t.to_vec()).collect();
+        }
+        for _ in 0..levels {
+            mesh = match scheme {
+                Subdivision::CatmullClark => mesh.catmull_clark(),
+                Subdivision::Loop => mesh.loop_subdivision(),
+            };
+        }
+        Mesh::smooth(mesh.positions.clone(), mesh.triangles())
+    }
+
+    // fans around the first vertex of each face
+    pub fn triangles(&self) -> Vec<[usize; 3]> {
+        self.faces
+            .iter()
+            .flat_map(|face| (1..face.len() - 1).map(move |i| [face[0], face[i], face[i + 1]]))
+            .collect()
+    }
+
+    // Edges by their vertices in increasing order, the new vertices at the edges are numbered
+    // from first on. The edges are kept sorted, so sums over the neighbours they give add up
+    // in the same order on every run.
+    fn edges(&self, first: usize) -> BTreeMap<(usize, usize), Edge> {
+        let mut edges: BTreeMap<(usize, usize), Edge> = BTreeMap::new();
+        for (index, face) in self.faces.iter().enumerate() {
+            for i in 0..face.len() {
+                let key = edge_key(face[i], face[(i + 1) % face.len()]);
+                let vertex = first + edges.len();
+                edges
+                    .entry(key)
+                    .or_insert(Edge {
+                        faces: vec![],
+                        vertex,
+                    })
+                    .faces
+                    .push(index);
+            }
+        }
+        edges
+    }
+
+    // Neighbours of every vertex across edges, and whether the vertex is on a boundary, where
+    // only its neighbours along the boundary count
+    fn neighbours(&self, edges: &BTreeMap<(usize, usize), Edge>) -> Vec<(Vec<usize>, bool)> {
+        let mut neighbours = vec![(vec![], false); self.positions.len()];
+        let mut boundary = vec![vec![]; self.positions.len()];
+        for (&(a, b), edge) in edges {
+            neighbours[a].0.push(b);
+            neighbours[b].0.push(a);
+            if edge.faces.len() != 2 {
+                boundary[a].push(b);
+                boundary[b].push(a);
+            }
+        }
+        for (vertex, boundary) in boundary.into_iter().enumerate() {
+            if !boundary.is_empty() {
+                neighbours[vertex] = (boundary, true);
+            }
+        }
+        neighbours
+    }
+
+    // Every face gets a point at its centroid, every edge one between its ends and the points
+    // of the faces next to it, and every vertex moves towards the average of both. Each face
+    // turns into a quad for each of its corners.
+    fn catmull_clark(&self) -> Self {
+        let positions = &self.positions;
+        let face_points = self
+            .faces
+            .iter()
+            .map(|face| average(face.iter().map(|v| positions[*v])))
+            .collect::<Vec<_>>();
+        let edge_start = positions.len();
+        let edges = self.edges(edge_start);
+        let face_start = edge_start + edges.len();
+
+        let mut new_positions = vec![Vector3::default(); face_start + face_points.len()];
+        for (&(a, b), edge) in &edges {
+            let midpoint = (positions[a] + positions[b]) / 2.0;
+            new_positions[edge.vertex] = if edge.faces.len() == 2 {
+                (midpoint + (face_points[edge.faces[0]] + face_points[edge.faces[1]]) / 2.0) / 2.0
+            } else {
+                midpoint
+            };
+        }
+        new_positions[face_start..].copy_from_slice(&face_points);
+
+        let mut vertex_faces = vec![vec![]; positions.len()];
+        for (index, face) in self.faces.iter().enumerate() {
+            for vertex in face {
+                vertex_faces[*vertex].push(index);
+            }
+        }
+        for (vertex, (neighbours, boundary)) in self.neighbours(&edges).into_iter().enumerate() {
+            let p = positions[vertex];
+            new_positions[vertex] = if boundary {
+                boundary_vertex(p, &neighbours, positions)
+            } else if neighbours.is_empty() {
+                p
+            } else {
+                let n = neighbours.len() as f32;
+                let faces = average(vertex_faces[vertex].iter().map(|f| face_points[*f]));
+                let midpoints = average(neighbours.iter().map(|v| (p + positions[*v]) / 2.0));
+                (faces + midpoints * 2.0 + p * (n - 3.0)) / n
+            };
+        }
+
+        let edge_vertex = |a: usize, b: usize| edges[&edge_key(a, b)].vertex;
+        let mut faces = vec![];
+        for (index, face) in self.faces.iter().enumerate() {
+            let k = face.len();
+            for i in 0..k {
+                let (previous, vertex, next) = (face[(i + k - 1) % k], face[i], face[(i + 1) % k]);
+                faces.push(vec![
+                    vertex,
+                    edge_vertex(vertex, next),
+                    face_start + index,
+                    edge_vertex(previous, vertex),
+                ]);
+            }
+        }
+        Self {
+            positions: new_positions,
+            faces,
+        }
+    }
+
+    // Every edge gets a point weighted towards its ends over the opposite corners of its two
+    // triangles, every vertex moves towards the average of its neighbours by the weights of
+    // Warren. Each triangle turns into four.
+    fn loop_subdivision(&self) -> Self {
+        let positions = &self.positions;
+        let edge_start = positions.len();
+        let edges = self.edges(edge_start);
+
+        let mut new_positions = vec![Vector3::default(); edge_start + edges.len()];
+        for (&(a, b), edge) in &edges {
+            let ends = positions[a] + positions[b];
+            new_positions[edge.vertex] = if edge.faces.len() == 2 {
+                let opposite = edge
+                    .faces
+                    .iter()
+                    .map(|f| {
+                        let corner = self.faces[*f].iter().find(|v| **v != a && **v != b);
+                        positions[*corner.unwrap_or(&a)]
+                    })
+                    .fold(Vector3::default(), |sum, p| sum + p);
+                ends * (3.0 / 8.0) + opposite / 8.0
+            } else {
+                ends / 2.0
+            };
+        }
+        for (vertex, (neighbours, boundary)) in self.neighbours(&edges).into_iter().enumerate() {
+            let p = positions[vertex];
+            new_positions[vertex] = if boundary {
+                boundary_vertex(p, &neighbours, positions)
+            } else if neighbours.is_empty() {
+                p
+            } else {
+                let n = neighbours.len();
+                let beta = if n == 3 {
+                    3.0 / 16.0
+                } else {
+                    3.0 / (8.0 * n as f32)
+                };
+                let sum = neighbours
+                    .iter()
+                    .fold(Vector3::default(), |s, v| s + positions[*v]);
+                p * (1.0 - n as f32 * beta) + sum * beta
+            };
+        }
+
+        let edge_vertex = |a: usize, b: usize| edges[&edge_key(a, b)].vertex;
+        let mut faces = vec![];
+        for face in &self.faces {
+            let (a, b, c) = (face[0], face[1], face[2]);
+            let (ab, bc, ca) = (edge_vertex(a, b), edge_vertex(b, c), edge_vertex(c, a));
+            faces.push(vec![a, ab, ca]);
+            faces.push(vec![b, bc, ab]);
+            faces.push(vec![c, ca, bc]);
+            faces.push(vec![ab, bc, ca]);
+        }
+        Self {
+            positions: new_positions,
+            faces,
+        }
+    }
+}
+
+fn edge_key(a: usize, b: usize) -> (usize, usize) {
+    (a.min(b), a.max(b))
+}
+
+fn average(points: impl Iterator<Item = Vector3>) -> Vector3 {
+    let (sum, count) = points.fold((Vector3::default(), 0), |(s, n), p| (s + p, n + 1));
+    sum / count.max(1) as f32
+}
+
+// A vertex on a boundary follows the curve through its two neighbours along it, corners where
+// more boundaries meet stay where they are
+fn boundary_vertex(p: Vector3, neighbours: &[usize], positions: &[Vector3]) -> Vector3 {
+    if neighbours.len() != 2 {
+        return p;
+    }
+    p * 0.75 + (positions[neighbours[0]] + positions[neighbours[1]]) / 8.0
+}
@@ -0,0 +1,134 @@
+use crate::ppm::PPM;
+use crate::vector3::Vector3;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+// Value at a point of a surface, looked up by its surface coordinates or its position
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScalarTexture {
+    Constant(f32),
+    // brightness of an image repeating over the surface coordinates, v runs from the bottom up
+    Image(Arc<ScalarImage>),
+    // Fractal value noise at the position within [0, 1], every octave at twice the frequency
+    // and half the amplitude of the one before
+    Noise { frequency: f32, octaves: u32 },
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ScalarImage {
+    width: usize,
+    height: usize,
+    values: Vec<f32>,
+}
+
+impl ScalarTexture {
+    // binary ppm file
+    pub fn image(file_name: &str) -> std::io::Result<Self> {
+        let image = PPM::read_file(file_name)?;
+        let (width, height) = (image.width(), image.height());
+        let values = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| image.pixel(x, y).luminance())
+            .collect();
+        Ok(ScalarTexture::Image(Arc::new(ScalarImage {
+            width,
+            height,
+            values,
+        })))
+    }
+
+    pub fn noise(frequency: f32, octaves: u32) -> Self {
+        ScalarTexture::Noise {
+            frequency,
+            octaves: octaves.max(1),
+        }
+    }
+
+    pub fn value(&self, uv: (f32, f32), position: Vector3) -> f32 {
+        match self {
+            ScalarTexture::Constant(value) => *value,
+            ScalarTexture::Image(image) => image.bilinear(uv),
+            ScalarTexture::Noise { frequency, octaves } => {
+                let (mut sum, mut total, mut amplitude, mut frequency) =
+                    (0.0, 0.0, 1.0, *frequency);
+                for _ in 0..*octaves {
+                    sum += amplitude * value_noise(position * frequency);
+                    total += amplitude;
+                    amplitude /= 2.0;
+                    frequency *= 2.0;
+                }
+                sum / total
+            }
+        }
+    }
+}
+
+impl ScalarImage {
+    // between the centres of the four nearest pixels, wrapping around the edges
+    fn bilinear(&self, (u, v): (f32, f32)) -> f32 {
+        if self.values.is_empty() {
+            return 0.0;
+        }
+        let x = u.rem_euclid(1.0) * self.width as f32 - 0.5;
+        let y = (1.0 - v.rem_euclid(1.0)) * self.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(self.width as i64) as usize;
+            let y = (y as i64).rem_euclid(self.height as i64) as usize;
+            self.values[y * self.width + x]
+        };
+        let top = pixel(x0, y0) * (1.0 - fx) + pixel(x0 + 1.0, y0) * fx;
+        let bottom = pixel(x0, y0 + 1.0) * (1.0 - fx) + pixel(x0 + 1.0, y0 + 1.0) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+impl Hash for ScalarTexture {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            ScalarTexture::Constant(value) => value.to_bits().hash(state),
+            ScalarTexture::Image(image) => {
+                image.width.hash(state);
+                image.height.hash(state);
+                for value in &image.values {
+                    value.to_bits().hash(state);
+                }
+            }
+            ScalarTexture::Noise { frequency, octaves } => {
+                frequency.to_bits().hash(state);
+                octaves.hash(state);
+            }
+        }
+    }
+}
+
+// random values at the integer lattice, blended smoothly between them
+fn value_noise(p: Vector3) -> f32 {
+    let (x0, y0, z0) = (p.x.floor(), p.y.floor(), p.z.floor());
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let (fx, fy, fz) = (smooth(p.x - x0), smooth(p.y - y0), smooth(p.z - z0));
+    let (x0, y0, z0) = (x0 as i32, y0 as i32, z0 as i32);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    let corner = |dx: i32, dy: i32, dz: i32| lattice_value(x0 + dx, y0 + dy, z0 + dz);
+    let face = |dz: i32| {
+        lerp(
+            lerp(corner(0, 0, dz), corner(1, 0, dz), fx),
+            lerp(corner(0, 1, dz), corner(1, 1, dz), fx),
+            fy,
+        )
+    };
+    lerp(face(0), face(1), fz)
+}
+
+// hash of the lattice point, within [0, 1)
+fn lattice_value(x: i32, y: i32, z: i32) -> f32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0x5bd1_e995);
+    h ^= h >> 15;
+    (h & 0x00ff_ffff) as f32 / 16_777_216.0
+}
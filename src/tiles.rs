@@ -0,0 +1,76 @@
+#[derive(Debug, Copy, Clone, PartialEq, Hash)]
+pub enum TileOrder {
+    Scanline,
+    // outwards from the center of the image, where the subject usually is
+    Spiral,
+    // along a Hilbert curve, neighbouring tiles are rendered close together in time
+    Hilbert,
+}
+
+// pixels [x0, x1) x [y0, y1)
+#[derive(Debug, Copy, Clone)]
+pub struct Tile {
+    pub x0: usize,
+    pub y0: usize,
+    pub x1: usize,
+    pub y1: usize,
+}
+
+pub fn create_tiles(width: usize, height: usize, tile_size: usize, order: TileOrder) -> Vec<Tile> {
+    let tile_size = tile_size.max(1);
+    let columns = width.div_ceil(tile_size);
+    let rows = height.div_ceil(tile_size);
+
+    let mut grid = (0..rows)
+        .flat_map(|row| (0..columns).map(move |column| (column, row)))
+        .collect::<Vec<_>>();
+
+    match order {
+        TileOrder::Scanline => (),
+        TileOrder::Spiral => {
+            let center_x = (columns as f32 - 1.0) / 2.0;
+            let center_y = (rows as f32 - 1.0) / 2.0;
+            grid.sort_by(|a, b| {
+                let key = |(column, row): (usize, usize)| {
+                    let dx = column as f32 - center_x;
+                    let dy = row as f32 - center_y;
+                    (dx.abs().max(dy.abs()).round(), dy.atan2(dx))
+                };
+                key(*a).partial_cmp(&key(*b)).unwrap()
+            });
+        }
+        TileOrder::Hilbert => {
+            let size = columns.max(rows).next_power_of_two();
+            grid.sort_by_key(|(column, row)| hilbert_index(size, *column, *row));
+        }
+    }
+
+    grid.into_iter()
+        .map(|(column, row)| Tile {
+            x0: column * tile_size,
+            y0: row * tile_size,
+            x1: ((column + 1) * tile_size).min(width),
+            y1: ((row + 1) * tile_size).min(height),
+        })
+        .collect()
+}
+
+// distance along the Hilbert curve filling a size x size grid, size a power of two
+fn hilbert_index(size: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut s = size / 2;
+    while s > 0 {
+        let rx = usize::from(x & s > 0);
+        let ry = usize::from(y & s > 0);
+        index += s * s * ((3 * rx) ^ ry);
+        if ry == 0 {
+            if rx == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        s /= 2;
+    }
+    index
+}
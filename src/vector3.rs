@@ -1,7 +1,7 @@
 extern crate overload;
 use overload::overload;
-use rand::prelude::*;
 use std::f32::consts::PI;
+use std::hash::{Hash, Hasher};
 use std::ops;
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -43,14 +43,12 @@ impl Vector3 {
     pub fn refract(&self, normal: Self, ior: f32) -> Self {
         let mut eta_t = ior;
         let mut eta_i = 1.0;
-        let mut cosi = -self.inner_product(normal).min(1.0).max(-1.0);
+        let mut cosi = -self.inner_product(normal).clamp(-1.0, 1.0);
         let n = if cosi < 0.0 {
             cosi *= -1.0;
             normal
         } else {
-            let tmp = eta_t;
-            eta_t = eta_i;
-            eta_i = tmp;
+            std::mem::swap(&mut eta_t, &mut eta_i);
             normal * -1.0
         };
         let eta = eta_i / eta_t;
@@ -132,10 +130,19 @@ impl Vector3 {
         [self.x, self.y, self.z]
     }
 
-    pub fn random_in_unit_disk() -> Self {
-        let mut rng = rand::thread_rng();
-        let theta: f32 = (rng.gen::<f32>()) * 2.0 * PI;
-        let r: f32 = rng.gen();
+    // maps a uniform sample on the unit square to a uniform point on the unit disk,
+    // using the concentric mapping so strata of the square stay compact on the disk
+    pub fn in_unit_disk((u, v): (f32, f32)) -> Self {
+        let a = 2.0 * u - 1.0;
+        let b = 2.0 * v - 1.0;
+        if a == 0.0 && b == 0.0 {
+            return Self::default();
+        }
+        let (r, theta) = if a.abs() > b.abs() {
+            (a, PI / 4.0 * (b / a))
+        } else {
+            (b, PI / 2.0 - PI / 4.0 * (a / b))
+        };
         Self {
             x: r * theta.cos(),
             y: r * theta.sin(),
@@ -143,27 +150,32 @@ impl Vector3 {
         }
     }
 
-    pub fn random_in_hemisphere() -> Self {
-        let mut rng = rand::thread_rng();
-        let y = rng.gen::<f32>();
+    // uniform direction in the hemisphere around the y axis
+    pub fn in_hemisphere((u, v): (f32, f32)) -> Self {
+        let y = u;
         let sin_theta = (1.0 - y * y).sqrt();
-        let phi: f32 = (rng.gen::<f32>()) * 2.0 * PI;
+        let phi = v * 2.0 * PI;
         let x = sin_theta * phi.cos();
         let z = sin_theta * phi.sin();
         Self { x, y, z }
     }
 
-    pub fn random_in_sphere() -> Self {
-        let mut rng = rand::thread_rng();
-        let y = -1.0 + 2.0 * rng.gen::<f32>();
+    pub fn in_sphere((u, v): (f32, f32)) -> Self {
+        let y = -1.0 + 2.0 * u;
         let sin_theta = (1.0 - y * y).sqrt();
-        let phi: f32 = (rng.gen::<f32>()) * 2.0 * PI;
+        let phi = v * 2.0 * PI;
         let x = sin_theta * phi.cos();
         let z = sin_theta * phi.sin();
         Self { x, y, z }
     }
 }
 
+impl Hash for Vector3 {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.to_array().map(f32::to_bits).hash(state);
+    }
+}
+
 overload!((a: ?Vector3) + (b: ?Vector3) -> Vector3 { Vector3 { x: a.x + b.x, y: a.y + b.y, z: a.z + b.z } });
 overload!((a: ?Vector3) - (b: ?Vector3) -> Vector3 { Vector3 { x: a.x - b.x, y: a.y - b.y, z: a.z - b.z } });
 overload!((a: ?Vector3) * (b: ?Vector3) -> Vector3 { Vector3 { x: a.x * b.x, y: a.y * b.y, z: a.z * b.z } });
@@ -174,3 +186,4 @@ overload!((a: &mut Vector3) += (b: ?Vector3) { a.x += b.x; a.y += b.y; a.z += b.
 overload!((a: &mut Vector3) -= (b: ?Vector3) { a.x -= b.x; a.y -= b.y; a.z -= b.z; });
 overload!((a: &mut Vector3) *= (b: f32) { a.x *= b; a.y *= b; a.z *= b; });
 overload!((a: &mut Vector3) /= (b: f32) { a.x /= b; a.y /= b; a.z /= b; });
+overload!(- (a: ?Vector3) -> Vector3 { Vector3 { x: -a.x, y: -a.y, z: -a.z } });